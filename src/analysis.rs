@@ -0,0 +1,271 @@
+use disasm::disassemble;
+use std::collections::{BTreeMap, BTreeSet};
+
+const ORIGIN: u16 = 0x200;
+
+/// How control gets from one instruction to another.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    /// The next instruction, also after a subroutine returns.
+    Next,
+    Jump,
+    /// The instruction after the next one, when a skip is taken.
+    Skip,
+    Call,
+    /// An entry of the jump table of `JP V0, addr`.
+    Table,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Edge {
+    pub target: u16,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that are always executed in sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    /// Address and opcode of each instruction.
+    pub instructions: Vec<(u16, u16)>,
+    pub edges: Vec<Edge>,
+}
+
+/// The control-flow graph of a ROM, found by following every path from
+/// 0x200. Bytes that are never reached are data. Code that is only reached
+/// through self-modification or computed jumps other than jump tables is
+/// taken for data as well.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControlFlowGraph {
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    /// Targets of `CALL`.
+    pub subroutines: BTreeSet<u16>,
+    /// Targets of `JP` and `JP V0`.
+    pub jump_targets: BTreeSet<u16>,
+    /// Targets of `LD I`, usually sprites.
+    pub data_references: BTreeSet<u16>,
+    instructions: BTreeMap<u16, u16>,
+}
+
+impl ControlFlowGraph {
+    /// Returns the opcode if an instruction starts at the address.
+    pub fn instruction(&self, address: u16) -> Option<u16> {
+        self.instructions.get(&address).cloned()
+    }
+
+    /// Returns the name of a referenced address, like `sub_2A4` for a
+    /// subroutine, `loc_2A0` for a jump target or `data_300` for data.
+    pub fn label(&self, address: u16) -> Option<String> {
+        let prefix = if self.subroutines.contains(&address) {
+            "sub"
+        } else if self.jump_targets.contains(&address) {
+            "loc"
+        } else if self.data_references.contains(&address) {
+            "data"
+        } else {
+            return None;
+        };
+        Some(format!("{}_{:03X}", prefix, address))
+    }
+
+    /// Exports the graph in the DOT format of Graphviz, with one node per
+    /// basic block. Calls are dashed, taken skips are labeled.
+    pub fn to_dot(&self) -> String {
+        let mut dot = "digraph chip8 {\n    node [shape=box, fontname=\"monospace\"];\n".to_string();
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if self.subroutines.contains(&block.start) {
+                label += &format!("{}:\\l", self.label(block.start).unwrap());
+            }
+            for &(address, opcode) in &block.instructions {
+                label += &format!("{:03X}  {}\\l", address, disassemble(opcode));
+            }
+            dot += &format!("    b{:03X} [label=\"{}\"];\n", block.start, label);
+        }
+        for block in self.blocks.values() {
+            for edge in &block.edges {
+                if !self.blocks.contains_key(&edge.target) {
+                    continue;
+                }
+                let style = match edge.kind {
+                    EdgeKind::Next | EdgeKind::Jump => "",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                    EdgeKind::Table => " [label=\"table\"]",
+                };
+                dot += &format!("    b{:03X} -> b{:03X}{};\n", block.start, edge.target, style);
+            }
+        }
+        dot + "}\n"
+    }
+}
+
+/// Builds the control-flow graph of a ROM loaded at 0x200.
+pub fn analyze(rom: &[u8]) -> ControlFlowGraph {
+    let fetch = |address: u16| {
+        let index = (address as usize).checked_sub(ORIGIN as usize)?;
+        Some((*rom.get(index)? as u16) << 8 | *rom.get(index + 1)? as u16)
+    };
+
+    let mut instructions = BTreeMap::new();
+    let mut edges = BTreeMap::new();
+    let mut subroutines = BTreeSet::new();
+    let mut jump_targets = BTreeSet::new();
+    let mut data_references = BTreeSet::new();
+    let mut pending = vec![ORIGIN];
+    while let Some(address) = pending.pop() {
+        if instructions.contains_key(&address) {
+            continue;
+        }
+        let opcode = match fetch(address) {
+            Some(opcode) if is_instruction(opcode) => opcode,
+            _ => continue,
+        };
+        let nnn = opcode & 0xFFF;
+        let next = |offset: u16, kind| Edge {
+            target: address.wrapping_add(offset),
+            kind,
+        };
+        let successors = match opcode {
+            0x00EE => vec![],
+            0x1000..=0x1FFF => {
+                jump_targets.insert(nnn);
+                vec![Edge {
+                    target: nnn,
+                    kind: EdgeKind::Jump,
+                }]
+            }
+            0x2000..=0x2FFF => {
+                subroutines.insert(nnn);
+                vec![
+                    Edge {
+                        target: nnn,
+                        kind: EdgeKind::Call,
+                    },
+                    next(2, EdgeKind::Next),
+                ]
+            }
+            // Jump tables are a series of jumps, V0 selects one of them
+            0xB000..=0xBFFF => {
+                jump_targets.insert(nnn);
+                let mut table = vec![Edge {
+                    target: nnn,
+                    kind: EdgeKind::Table,
+                }];
+                let mut entry = nnn + 2;
+                while fetch(entry).is_some_and(|opcode| opcode & 0xF000 == 0x1000) {
+                    table.push(Edge {
+                        target: entry,
+                        kind: EdgeKind::Table,
+                    });
+                    entry += 2;
+                }
+                table
+            }
+            0x3000..=0x5FFF | 0x9000..=0x9FFF | 0xE000..=0xEFFF => {
+                vec![next(2, EdgeKind::Next), next(4, EdgeKind::Skip)]
+            }
+            _ => {
+                if opcode & 0xF000 == 0xA000 {
+                    data_references.insert(nnn);
+                }
+                vec![next(2, EdgeKind::Next)]
+            }
+        };
+        pending.extend(successors.iter().rev().map(|edge| edge.target));
+        instructions.insert(address, opcode);
+        edges.insert(address, successors);
+    }
+
+    // A block starts where control does not simply come from the previous
+    // instruction
+    let falls_through = |successors: &Vec<Edge>| {
+        successors.len() == 1 && successors[0].kind == EdgeKind::Next
+    };
+    let mut leaders = BTreeSet::new();
+    leaders.insert(ORIGIN);
+    for successors in edges.values() {
+        if !falls_through(successors) {
+            leaders.extend(successors.iter().map(|edge| edge.target));
+        }
+    }
+
+    let mut blocks = BTreeMap::new();
+    for &start in leaders.iter().filter(|start| instructions.contains_key(start)) {
+        let mut block = BasicBlock {
+            start,
+            instructions: Vec::new(),
+            edges: Vec::new(),
+        };
+        let mut address = start;
+        loop {
+            block.instructions.push((address, instructions[&address]));
+            let successors = &edges[&address];
+            if !falls_through(successors) || leaders.contains(&successors[0].target) {
+                block.edges = successors.clone();
+                break;
+            }
+            address = successors[0].target;
+            if !instructions.contains_key(&address) {
+                block.edges = successors.clone();
+                break;
+            }
+        }
+        blocks.insert(start, block);
+    }
+
+    ControlFlowGraph {
+        blocks,
+        subroutines,
+        jump_targets,
+        data_references,
+        instructions,
+    }
+}
+
+// The disassembler shows everything else as data
+fn is_instruction(opcode: u16) -> bool {
+    !disassemble(opcode).starts_with(":byte")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD I, 0x20E; CALL 0x20A; SE V0, 0x00; JP 0x202; JP 0x208;
+    // DRW V0, V0, 5; RET; followed by the sprite of 0
+    const ROM: [u8; 19] = [
+        0xA2, 0x0E, 0x22, 0x0A, 0x30, 0x00, 0x12, 0x02, 0x12, 0x08, 0xD0, 0x05, 0x00, 0xEE,
+        0xF0, 0x90, 0x90, 0x90, 0xF0,
+    ];
+
+    #[test]
+    fn separates_code_from_data() {
+        let graph = analyze(&ROM);
+        let starts: Vec<u16> = graph.blocks.keys().cloned().collect();
+        assert_eq!(starts, vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A]);
+        assert_eq!(graph.blocks[&0x20A].instructions, vec![(0x20A, 0xD005), (0x20C, 0x00EE)]);
+        assert_eq!(graph.instruction(0x20C), Some(0x00EE));
+        assert_eq!(graph.instruction(0x20E), None);
+        assert_eq!(graph.label(0x20A), Some("sub_20A".to_string()));
+        assert_eq!(graph.label(0x20E), Some("data_20E".to_string()));
+
+        let dot = graph.to_dot();
+        assert!(dot.contains("    b204 -> b208 [label=\"skip\"];\n"));
+        assert!(dot.contains("    b202 -> b20A [style=dashed, label=\"call\"];\n"));
+        assert!(dot.contains("    b20A [label=\"sub_20A:\\l20A  DRW V0, V0, 5\\l20C  RET\\l\"];\n"));
+    }
+
+    #[test]
+    fn follows_jump_tables() {
+        // LD V0, 2; JP V0, 0x206; garbage; JP 0x20A; JP 0x20C; CLS; JP 0x20C
+        let rom = [
+            0x60, 0x02, 0xB2, 0x06, 0xFF, 0xFF, 0x12, 0x0A, 0x12, 0x0C, 0x00, 0xE0, 0x12, 0x0C,
+        ];
+        let graph = analyze(&rom);
+        let targets: Vec<u16> = graph.blocks[&0x200].edges.iter().map(|e| e.target).collect();
+        assert_eq!(targets, vec![0x206, 0x208]);
+        assert_eq!(graph.instruction(0x204), None);
+        assert_eq!(graph.blocks[&0x20A].instructions, vec![(0x20A, 0x00E0)]);
+    }
+}
@@ -0,0 +1,55 @@
+use std::io::{Cursor, Read};
+use zip::ZipArchive;
+
+/// Returns the names of the files in a zip archive in the order they are
+/// stored, leaving out directories.
+pub fn zip_entries(archive: &[u8]) -> Result<Vec<String>, String> {
+    let mut archive = open(archive)?;
+    let mut names = Vec::new();
+    for i in 0..archive.len() {
+        let file = archive.by_index(i).map_err(|e| format!("Invalid zip archive: {}", e))?;
+        if !file.is_dir() {
+            names.push(file.name().to_string());
+        }
+    }
+    Ok(names)
+}
+
+/// Extracts a file from a zip archive.
+pub fn read_zip_entry(archive: &[u8], name: &str) -> Result<Vec<u8>, String> {
+    let mut archive = open(archive)?;
+    let mut file = archive
+        .by_name(name)
+        .map_err(|e| format!("Error reading {} from the zip archive: {}", name, e))?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .map_err(|e| format!("Error reading {} from the zip archive: {}", name, e))?;
+    Ok(bytes)
+}
+
+fn open(archive: &[u8]) -> Result<ZipArchive<Cursor<&[u8]>>, String> {
+    ZipArchive::new(Cursor::new(archive)).map_err(|e| format!("Invalid zip archive: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::{FileOptions, ZipWriter};
+
+    #[test]
+    fn lists_and_extracts_files() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.add_directory("games/", FileOptions::default()).unwrap();
+        writer.start_file("games/pong.ch8", FileOptions::default()).unwrap();
+        writer.write_all(&[0x12, 0x00]).unwrap();
+        writer.start_file("readme.txt", FileOptions::default()).unwrap();
+        writer.write_all(b"Have fun").unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        assert_eq!(zip_entries(&archive).unwrap(), vec!["games/pong.ch8", "readme.txt"]);
+        assert_eq!(read_zip_entry(&archive, "games/pong.ch8").unwrap(), vec![0x12, 0x00]);
+        assert!(read_zip_entry(&archive, "tetris.ch8").is_err());
+        assert!(zip_entries(&[0x12, 0x00]).is_err());
+    }
+}
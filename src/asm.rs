@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+const ORIGIN: u16 = 0x200;
+
+/// Error in the assembler source, `line` and `column` start at 1.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl error::Error for AsmError {}
+
+/// Assembles source in the syntax of the disassembler into a ROM to be
+/// loaded at 0x200.
+///
+/// Every line holds an instruction like `LD VA, 0x02` or a directive, and
+/// may start with labels like `loop:` or, as in Octo, `: loop`. Besides the
+/// instructions, the following directives are supported:
+///
+/// * `:byte 0x12 34 0b0101`: literal bytes
+/// * `:sprite ..XXXX.. .X....X.`: one byte per row of pixels, `X` or `1` for
+///   set pixels, `.` or `0` for unset ones
+/// * `:const NAME VALUE`: defines a constant
+///
+/// Numbers are decimal, hex with `0x` or binary with `0b` prefix. Wherever
+/// a number is expected, a label or constant can be used, optionally with
+/// an offset like `table+2`. Comments start with `;` or a `#` token.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address = ORIGIN as usize;
+
+    for (number, line) in source.lines().enumerate() {
+        let mut tokens = tokenize(line, number + 1);
+        // Labels
+        loop {
+            let label = match tokens.first() {
+                Some(token) if token.text == ":" && tokens.len() > 1 => {
+                    tokens.remove(0);
+                    tokens.remove(0)
+                }
+                Some(token) if token.text.len() > 1 && token.text.ends_with(':') => {
+                    let mut token = tokens.remove(0);
+                    token.text.pop();
+                    token
+                }
+                _ => break,
+            };
+            if !is_symbol(&label.text) {
+                return Err(label.error(format!("Invalid label: {}", label.text)));
+            }
+            if symbols.insert(label.text.clone(), address as u16).is_some() {
+                return Err(label.error(format!("Duplicate label: {}", label.text)));
+            }
+        }
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let size = match &tokens[0].text.to_lowercase()[..] {
+            ":const" => {
+                if tokens.len() != 3 {
+                    return Err(tokens[0].error("Expected `:const NAME VALUE`".to_string()));
+                }
+                if !is_symbol(&tokens[1].text) {
+                    return Err(tokens[1].error(format!("Invalid name: {}", tokens[1].text)));
+                }
+                let value = evaluate(&tokens[2], &symbols)?;
+                if symbols.insert(tokens[1].text.clone(), value).is_some() {
+                    return Err(tokens[1].error(format!("Duplicate name: {}", tokens[1].text)));
+                }
+                continue;
+            }
+            ":byte" | ":sprite" => tokens.len() - 1,
+            _ => 2,
+        };
+        address += size;
+        if address > 0x1000 {
+            return Err(tokens[0].error("Program does not fit into memory".to_string()));
+        }
+        statements.push(tokens);
+    }
+
+    let mut rom = Vec::with_capacity(address - ORIGIN as usize);
+    for tokens in &statements {
+        let (directive, operands) = tokens.split_first().unwrap();
+        match &directive.text.to_lowercase()[..] {
+            ":byte" => {
+                for operand in operands {
+                    rom.push(byte(operand, &symbols)?);
+                }
+            }
+            ":sprite" => {
+                for operand in operands {
+                    rom.push(sprite_row(operand)?);
+                }
+            }
+            _ => {
+                let opcode = encode(directive, operands, &symbols)?;
+                rom.push((opcode >> 8) as u8);
+                rom.push(opcode as u8);
+            }
+        }
+    }
+    Ok(rom)
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+// Splits a line at whitespace and commas and drops comments
+fn tokenize(line: &str, number: usize) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (index, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+        let separator = c.is_whitespace() || c == ',' || c == ';';
+        match start {
+            Some(begin) if separator => {
+                tokens.push(Token {
+                    text: line[begin..index].to_string(),
+                    line: number,
+                    column: line[..begin].chars().count() + 1,
+                });
+                start = None;
+            }
+            None if c == '#' || c == ';' => break,
+            None if !separator => start = Some(index),
+            _ => {}
+        }
+        if c == ';' {
+            break;
+        }
+    }
+    tokens
+}
+
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && register(name).is_none()
+        && !["I", "DT", "ST", "K", "F", "HF", "B"].contains(&&name.to_uppercase()[..])
+}
+
+fn register(name: &str) -> Option<u16> {
+    let name = name.to_uppercase();
+    match name.strip_prefix('V') {
+        Some(digit) if digit.len() == 1 => u16::from_str_radix(digit, 16).ok(),
+        _ => None,
+    }
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    let lower = text.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// Evaluates a number, a symbol or a symbol with offset like `table+2`
+fn evaluate(token: &Token, symbols: &HashMap<String, u16>) -> Result<u16, AsmError> {
+    let text = &token.text;
+    if let Some(value) = parse_number(text) {
+        return Ok(value);
+    }
+    let (name, offset) = match text.find(['+', '-']).filter(|&i| i > 0) {
+        Some(index) if parse_number(&text[index + 1..]).is_some() => {
+            let offset = parse_number(&text[index + 1..]).unwrap() as i32;
+            let sign = if text[index..].starts_with('-') { -1 } else { 1 };
+            (&text[..index], sign * offset)
+        }
+        _ => (&text[..], 0),
+    };
+    match symbols.get(name) {
+        Some(&value) => {
+            let value = value as i32 + offset;
+            if (0..=0xFFFF).contains(&value) {
+                Ok(value as u16)
+            } else {
+                Err(token.error(format!("Value out of range: {}", text)))
+            }
+        }
+        None if is_symbol(name) => Err(token.error(format!("Unknown label or constant: {}", name))),
+        None => Err(token.error(format!("Expected a number: {}", text))),
+    }
+}
+
+fn byte(token: &Token, symbols: &HashMap<String, u16>) -> Result<u8, AsmError> {
+    match evaluate(token, symbols)? {
+        value if value <= 0xFF => Ok(value as u8),
+        _ => Err(token.error(format!("Byte out of range: {}", token.text))),
+    }
+}
+
+fn sprite_row(token: &Token) -> Result<u8, AsmError> {
+    if token.text.len() > 8 {
+        return Err(token.error(format!("Sprite row wider than 8 pixels: {}", token.text)));
+    }
+    let mut row = 0;
+    for (x, c) in token.text.chars().enumerate() {
+        match c {
+            'X' | 'x' | '1' => row |= 0x80 >> x,
+            '.' | '0' => {}
+            _ => return Err(token.error(format!("Invalid sprite row: {}", token.text))),
+        }
+    }
+    Ok(row)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operand {
+    V(u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    Value(u16),
+}
+
+fn operand(token: &Token, symbols: &HashMap<String, u16>) -> Result<Operand, AsmError> {
+    if let Some(x) = register(&token.text) {
+        return Ok(Operand::V(x));
+    }
+    let operand = match &token.text.to_uppercase()[..] {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::Dt,
+        "ST" => Operand::St,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::Hf,
+        "B" => Operand::B,
+        _ => Operand::Value(evaluate(token, symbols)?),
+    };
+    Ok(operand)
+}
+
+fn encode(
+    mnemonic: &Token,
+    tokens: &[Token],
+    symbols: &HashMap<String, u16>,
+) -> Result<u16, AsmError> {
+    use self::Operand::*;
+
+    let operands = tokens
+        .iter()
+        .map(|token| operand(token, symbols))
+        .collect::<Result<Vec<_>, _>>()?;
+    let invalid = || {
+        let position = tokens.first().unwrap_or(mnemonic);
+        position.error(format!("Invalid operands for {}", mnemonic.text.to_uppercase()))
+    };
+    let address = |index: usize, value: u16| {
+        if value <= 0xFFF {
+            Ok(value)
+        } else {
+            Err(tokens[index].error(format!("Address out of range: {}", tokens[index].text)))
+        }
+    };
+    let byte = |index: usize, value: u16| {
+        if value <= 0xFF {
+            Ok(value)
+        } else {
+            Err(tokens[index].error(format!("Byte out of range: {}", tokens[index].text)))
+        }
+    };
+    let xy = |x: u16, y: u16| x << 8 | y << 4;
+
+    let opcode = match (&mnemonic.text.to_uppercase()[..], &operands[..]) {
+        ("CLS", &[]) => 0x00E0,
+        ("RET", &[]) => 0x00EE,
+        ("JP", &[Value(nnn)]) => 0x1000 | address(0, nnn)?,
+        ("JP", &[V(0), Value(nnn)]) => 0xB000 | address(1, nnn)?,
+        ("CALL", &[Value(nnn)]) => 0x2000 | address(0, nnn)?,
+        ("SE", &[V(x), Value(kk)]) => 0x3000 | x << 8 | byte(1, kk)?,
+        ("SNE", &[V(x), Value(kk)]) => 0x4000 | x << 8 | byte(1, kk)?,
+        ("SE", &[V(x), V(y)]) => 0x5000 | xy(x, y),
+        ("SNE", &[V(x), V(y)]) => 0x9000 | xy(x, y),
+        ("LD", &[V(x), Value(kk)]) => 0x6000 | x << 8 | byte(1, kk)?,
+        ("ADD", &[V(x), Value(kk)]) => 0x7000 | x << 8 | byte(1, kk)?,
+        ("LD", &[V(x), V(y)]) => 0x8000 | xy(x, y),
+        ("OR", &[V(x), V(y)]) => 0x8001 | xy(x, y),
+        ("AND", &[V(x), V(y)]) => 0x8002 | xy(x, y),
+        ("XOR", &[V(x), V(y)]) => 0x8003 | xy(x, y),
+        ("ADD", &[V(x), V(y)]) => 0x8004 | xy(x, y),
+        ("SUB", &[V(x), V(y)]) => 0x8005 | xy(x, y),
+        ("SHR", &[V(x)]) => 0x8006 | xy(x, 0),
+        ("SHR", &[V(x), V(y)]) => 0x8006 | xy(x, y),
+        ("SUBN", &[V(x), V(y)]) => 0x8007 | xy(x, y),
+        ("SHL", &[V(x)]) => 0x800E | xy(x, 0),
+        ("SHL", &[V(x), V(y)]) => 0x800E | xy(x, y),
+        ("LD", &[I, Value(nnn)]) => 0xA000 | address(1, nnn)?,
+        ("RND", &[V(x), Value(kk)]) => 0xC000 | x << 8 | byte(1, kk)?,
+        ("DRW", &[V(x), V(y), Value(n)]) if (1..=15).contains(&n) => 0xD000 | xy(x, y) | n,
+        ("DRW", &[V(_), V(_), Value(_)]) => {
+            return Err(tokens[2].error("Sprite height must be 1 to 15".to_string()))
+        }
+        ("SKP", &[V(x)]) => 0xE09E | x << 8,
+        ("SKNP", &[V(x)]) => 0xE0A1 | x << 8,
+        ("LD", &[V(x), Dt]) => 0xF007 | x << 8,
+        ("LD", &[V(x), K]) => 0xF00A | x << 8,
+        ("LD", &[Dt, V(x)]) => 0xF015 | x << 8,
+        ("LD", &[St, V(x)]) => 0xF018 | x << 8,
+        ("ADD", &[I, V(x)]) => 0xF01E | x << 8,
+        ("LD", &[F, V(x)]) => 0xF029 | x << 8,
+        ("LD", &[Hf, V(x)]) => 0xF030 | x << 8,
+        ("LD", &[B, V(x)]) => 0xF033 | x << 8,
+        ("LD", &[IndirectI, V(x)]) => 0xF055 | x << 8,
+        ("LD", &[V(x), IndirectI]) => 0xF065 | x << 8,
+        (
+            "CLS" | "RET" | "JP" | "CALL" | "SE" | "SNE" | "LD" | "ADD" | "OR" | "AND" | "XOR"
+            | "SUB" | "SHR" | "SUBN" | "SHL" | "RND" | "DRW" | "SKP" | "SKNP",
+            _,
+        ) => return Err(invalid()),
+        _ => return Err(mnemonic.error(format!("Unknown instruction: {}", mnemonic.text))),
+    };
+    Ok(opcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disasm::disassemble;
+
+    #[test]
+    fn disassembly_assembles_to_same_opcode() {
+        for opcode in 0..=0xFFFF {
+            let source = disassemble(opcode);
+            let rom = assemble(&source).unwrap_or_else(|e| panic!("{}: {}", source, e));
+            assert_eq!(rom, vec![(opcode >> 8) as u8, opcode as u8], "{}", source);
+        }
+    }
+
+    #[test]
+    fn labels_constants_and_data() {
+        let source = "
+            :const speed 3      ; pixels per frame
+            start:  LD V0, speed
+                    LD I, ball
+            : loop
+                    DRW V0, V1, 1
+                    JP loop
+            ball:   :sprite ..XX....
+            data:   :byte 0x12, 0b101 ball-0x200
+                    JP data+1
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            vec![0x60, 0x03, 0xA2, 0x08, 0xD0, 0x11, 0x12, 0x04, 0x30, 0x12, 0x05, 0x08, 0x12, 0x0A]
+        );
+    }
+
+    #[test]
+    fn errors_have_line_and_column() {
+        let error = assemble("CLS\n  LD V0, V1, V2").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 6));
+        let error = assemble("JP nowhere").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 4));
+        assert_eq!(error.message, "Unknown label or constant: nowhere");
+        let error = assemble("x: CLS\nx: CLS").unwrap_err();
+        assert_eq!(error.to_string(), "2:1: Duplicate label: x");
+        let error = assemble("  LD V0, 256").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 10));
+        let error = assemble("  MOV V0, 1").unwrap_err();
+        assert_eq!(error.to_string(), "1:3: Unknown instruction: MOV");
+    }
+}
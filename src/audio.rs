@@ -1,45 +1,298 @@
+use hound;
 use sdl2::audio;
 use sdl2::Sdl;
 use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub type AudioDevice = audio::AudioDevice<SquareWave>;
+pub type AudioDevice = audio::AudioDevice<Tone>;
+pub type WavWriter = hound::WavWriter<BufWriter<File>>;
 
-pub fn create_audio_device(sdl_context: &Sdl) -> AudioDevice {
-    let audio_subsystem = sdl_context.audio().unwrap();
+pub const SAMPLE_RATE: u32 = 44100;
+
+// Durations of the linear fade in and fade out around each beep in seconds
+const ATTACK_TIME: f32 = 0.002;
+const RELEASE_TIME: f32 = 0.005;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Sine,
+    Triangle,
+    Sawtooth,
+    Noise,
+}
+
+impl FromStr for Waveform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Waveform, String> {
+        match &s.to_lowercase()[..] {
+            "square" => Ok(Waveform::Square),
+            "sine" => Ok(Waveform::Sine),
+            "triangle" => Ok(Waveform::Triangle),
+            "sawtooth" => Ok(Waveform::Sawtooth),
+            "noise" => Ok(Waveform::Noise),
+            _ => Err(format!("Unknown waveform: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BeeperConfig {
+    pub frequency: f32,
+    pub waveform: Waveform,
+    pub volume: f32,
+    pub duty_cycle: f32,
+}
+
+impl Default for BeeperConfig {
+    fn default() -> BeeperConfig {
+        BeeperConfig {
+            frequency: 440.0,
+            waveform: Waveform::Square,
+            volume: 0.25,
+            duty_cycle: 0.5,
+        }
+    }
+}
+
+/// Opens the audio device. The callback keeps running for the whole session
+/// and only produces sound while `active` is set and `muted` is not. If
+/// `capture` is given, every generated buffer is also sent through it.
+pub fn create_audio_device(
+    sdl_context: &Sdl,
+    config: &BeeperConfig,
+    active: Arc<AtomicBool>,
+    muted: Arc<AtomicBool>,
+    capture: Option<Sender<Vec<f32>>>,
+) -> Result<AudioDevice, String> {
+    let audio_subsystem = sdl_context.audio()?;
     let desired_spec = AudioSpecDesired {
-        freq: Some(44100),
+        freq: Some(SAMPLE_RATE as i32),
         channels: Some(1), // mono
         samples: None, // default sample size
     };
     audio_subsystem
         .open_playback(None, &desired_spec, |spec| {
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
-            }
+            let mut tone = Tone::new(config, spec.freq as u32, active, muted);
+            tone.capture = capture;
+            tone
         })
-        .unwrap()
 }
 
-pub struct SquareWave {
+/// Renders the output of the beeper offline. `timeline` holds for every
+/// frame whether the sound timer was running.
+pub fn render_timeline(
+    config: &BeeperConfig,
+    sample_rate: u32,
+    frame_rate: u32,
+    timeline: &[bool],
+) -> Vec<f32> {
+    let active = Arc::new(AtomicBool::new(false));
+    let muted = Arc::new(AtomicBool::new(false));
+    let mut tone = Tone::new(config, sample_rate, active.clone(), muted);
+    let mut samples = Vec::new();
+    for (frame, &sound) in timeline.iter().enumerate() {
+        let start = frame * sample_rate as usize / frame_rate as usize;
+        let end = (frame + 1) * sample_rate as usize / frame_rate as usize;
+        let mut buffer = vec![0.0; end - start];
+        active.store(sound, Ordering::Relaxed);
+        tone.callback(&mut buffer);
+        samples.extend(buffer);
+    }
+    samples
+}
+
+pub fn create_wav_writer(path: &Path, sample_rate: u32) -> Result<WavWriter, String> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    hound::WavWriter::create(path, spec)
+        .map_err(|e| format!("Error creating {}: {}", path.display(), e))
+}
+
+/// Appends the samples and updates the header so that the file stays
+/// readable even if the emulator is not shut down cleanly.
+pub fn write_samples(writer: &mut WavWriter, samples: &[f32]) -> Result<(), String> {
+    for &sample in samples {
+        let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        writer.write_sample(sample).map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+pub struct Tone {
     phase_inc: f32,
     phase: f32,
     volume: f32,
+    duty_cycle: f32,
+    waveform: Waveform,
+    // State of the 15 bit LFSR that drives the noise waveform
+    lfsr: u16,
+    noise: f32,
+    gain: f32,
+    attack_step: f32,
+    release_step: f32,
+    active: Arc<AtomicBool>,
+    muted: Arc<AtomicBool>,
+    capture: Option<Sender<Vec<f32>>>,
 }
 
-impl AudioCallback for SquareWave {
+impl Tone {
+    fn new(
+        config: &BeeperConfig,
+        sample_rate: u32,
+        active: Arc<AtomicBool>,
+        muted: Arc<AtomicBool>,
+    ) -> Tone {
+        let sample_rate = sample_rate as f32;
+        Tone {
+            phase_inc: config.frequency / sample_rate,
+            phase: 0.0,
+            volume: config.volume,
+            duty_cycle: config.duty_cycle,
+            waveform: config.waveform,
+            lfsr: 0x7FFF,
+            noise: 1.0,
+            gain: 0.0,
+            attack_step: 1.0 / (ATTACK_TIME * sample_rate),
+            release_step: 1.0 / (RELEASE_TIME * sample_rate),
+            active,
+            muted,
+            capture: None,
+        }
+    }
+
+    // The discontinuities of the square and sawtooth waves are smoothed with
+    // polynomial band-limited steps to avoid aliasing at high frequencies.
+    fn sample(&self) -> f32 {
+        let phase = self.phase;
+        let dt = self.phase_inc;
+        match self.waveform {
+            Waveform::Square => {
+                let naive = if phase < self.duty_cycle { 1.0 } else { -1.0 };
+                let falling_edge = (phase - self.duty_cycle + 1.0) % 1.0;
+                naive + poly_blep(phase, dt) - poly_blep(falling_edge, dt)
+            }
+            Waveform::Sine => (2.0 * PI * phase).sin(),
+            Waveform::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            Waveform::Sawtooth => 2.0 * phase - 1.0 - poly_blep(phase, dt),
+            Waveform::Noise => self.noise,
+        }
+    }
+
+    fn update_gain(&mut self, sounding: bool) {
+        if sounding {
+            self.gain = (self.gain + self.attack_step).min(1.0);
+        } else {
+            self.gain = (self.gain - self.release_step).max(0.0);
+        }
+    }
+
+    fn advance(&mut self) {
+        self.phase += self.phase_inc;
+        if self.phase >= 1.0 {
+            self.phase %= 1.0;
+            // Draw a new noise value once per period so that the frequency
+            // setting also controls the pitch of the noise
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            self.noise = if self.lfsr & 1 == 1 { 1.0 } else { -1.0 };
+        }
+    }
+}
+
+impl AudioCallback for Tone {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
+        let sounding = self.active.load(Ordering::Relaxed) && !self.muted.load(Ordering::Relaxed);
         for x in out.iter_mut() {
-            *x = if self.phase >= 0.0 && self.phase <= 0.5 {
-                self.volume
+            self.update_gain(sounding);
+            *x = if self.gain > 0.0 {
+                self.sample() * self.gain * self.volume
             } else {
-                -self.volume
+                0.0
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+            // The oscillator keeps running while silent so that every beep
+            // continues the waveform where the previous one stopped
+            self.advance();
         }
+        // The receiving end is dropped if the recording failed. Capturing
+        // stops then, while the sound keeps playing.
+        let stopped = match self.capture {
+            Some(ref capture) => capture.send(out.to_vec()).is_err(),
+            None => false,
+        };
+        if stopped {
+            self.capture = None;
+        }
+    }
+}
+
+// Correction for a rising step of height 2 at phase 0, see
+// Välimäki and Huovilainen, "Antialiasing Oscillators in Subtractive Synthesis"
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        2.0 * t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn render_file(config: &BeeperConfig, timeline: &[bool], name: &str) -> Vec<u8> {
+        let path = env::temp_dir().join(format!("chip8-{}-{}.wav", name, ::std::process::id()));
+        let mut writer = create_wav_writer(&path, SAMPLE_RATE).unwrap();
+        write_samples(&mut writer, &render_timeline(config, SAMPLE_RATE, 60, timeline)).unwrap();
+        writer.finalize().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn renders_the_sound_timer() {
+        let config = BeeperConfig::default();
+        let timeline = [false, false, true, true, true];
+        let samples = render_timeline(&config, SAMPLE_RATE, 60, &timeline);
+        let frame = SAMPLE_RATE as usize / 60;
+        assert_eq!(samples.len(), 5 * frame);
+        assert!(samples[..2 * frame].iter().all(|&x| x == 0.0));
+        let beep = &samples[2 * frame..];
+        assert!(beep.iter().any(|&x| x != 0.0));
+        assert!(beep.iter().all(|&x| x.abs() <= config.volume));
+    }
+
+    #[test]
+    fn rendering_is_deterministic() {
+        let config = BeeperConfig {
+            waveform: Waveform::Noise,
+            ..BeeperConfig::default()
+        };
+        let timeline = [true, false, true, true, false, false];
+        let first = render_file(&config, &timeline, "first");
+        let second = render_file(&config, &timeline, "second");
+        assert!(first.len() > 44);
+        assert_eq!(first, second);
     }
 }
@@ -0,0 +1,65 @@
+extern crate chip8;
+
+use chip8::{assemble, compile_octo, Target};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [-o ROM_FILE] [--target TARGET] SOURCE_FILE", program);
+    println!();
+    println!("Assembles CHIP-8 mnemonics as printed by the disassembler into a ROM. The ROM is");
+    println!("written next to the source with the extension .ch8 unless -o is given.");
+    println!();
+    println!("Sources ending in .8o are compiled as Octo for TARGET, which is chip8 (default),");
+    println!("schip or xochip.");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut output = None;
+    let mut target = Target::Chip8;
+    let mut source_path = None;
+    let mut i = 1;
+    while i < args.len() {
+        match (&args[i][..], args.get(i + 1)) {
+            ("-o", Some(path)) => output = Some(PathBuf::from(path)),
+            ("--target", Some(name)) => {
+                target = name.parse().unwrap_or_else(|e| {
+                    println!("{}", e);
+                    process::exit(2);
+                })
+            }
+            (path, _) if source_path.is_none() && !path.starts_with('-') => {
+                source_path = Some(path.to_string());
+                i += 1;
+                continue;
+            }
+            _ => usage(&args[0]),
+        }
+        i += 2;
+    }
+    let source_path = source_path.unwrap_or_else(|| usage(&args[0]));
+    let output = output.unwrap_or_else(|| PathBuf::from(&source_path).with_extension("ch8"));
+
+    let source = fs::read_to_string(&source_path).unwrap_or_else(|e| {
+        println!("Error reading {}: {}", source_path, e);
+        process::exit(2);
+    });
+    let rom = if source_path.ends_with(".8o") {
+        compile_octo(&source, target).map(|program| program.rom)
+    } else {
+        assemble(&source)
+    };
+    let rom = rom.unwrap_or_else(|e| {
+        println!("{}:{}", source_path, e);
+        process::exit(1);
+    });
+    if let Err(e) = fs::write(&output, &rom) {
+        println!("Error writing {}: {}", output.display(), e);
+        process::exit(2);
+    }
+    println!("{} bytes written to {}", rom.len(), output.display());
+}
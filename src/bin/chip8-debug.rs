@@ -0,0 +1,366 @@
+extern crate chip8;
+
+use chip8::{
+    compile_runnable_octo, disassemble, parse_number, serve_gdb, Breakpoint, Debugger, Machine,
+    Memory, OctoProgram, Stop, Target, WatchKind, Watchpoint,
+};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
+use std::net::TcpListener;
+use std::process;
+
+const COLS: usize = 64;
+
+// Octo source the ROM was compiled from
+struct Listing {
+    lines: Vec<String>,
+    program: OctoProgram,
+}
+
+const HELP: &str = "\
+Commands (numbers are decimal or hex with 0x prefix, an empty line repeats the last command):
+    step [N]                 execute N instructions (default: 1)
+    continue [N]             run until a breakpoint or watchpoint is hit, at most N instructions
+    break ADDR [if COND]     stop before the instruction at ADDR, optionally only if COND holds
+    break if COND            stop when COND becomes true, e.g. `break if V3 == 0x10 && I > 0x300`
+    watch START[-END] [KIND] stop after memory in the range is accessed, KIND is r, w (default) or rw
+    delete N                 remove breakpoint N
+    unwatch N                remove watchpoint N
+    info                     list breakpoints and watchpoints
+    regs                     show the registers
+    x ADDR [LEN]             show LEN bytes of memory (default: 16)
+    disasm [ADDR] [N]        disassemble N instructions (default: 8 from PC)
+    screen                   show the display
+    key K down|up            press or release key K
+    reset [soft]             restart the program with the ROM loaded again, with soft keep the memory
+    quit";
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [--gdb PORT] [--target TARGET] ROM_FILE", program);
+    println!();
+    println!("Runs the ROM headless under an interactive debugger. Type `help` for commands.");
+    println!("With --gdb, a debugger speaking the GDB remote protocol is expected to connect");
+    println!("to PORT on localhost instead, e.g. with `target remote localhost:PORT`.");
+    println!("ROM files ending in .8o are compiled from Octo source, which is then shown");
+    println!("next to the instructions, and its :breakpoint directives become breakpoints.");
+    println!("TARGET is the platform Octo source is compiled for: chip8 (default) or schip.");
+    println!("Of the SCHIP instructions, only `i := bighex vx` can be run.");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut gdb_port = None;
+    let mut target = Target::Chip8;
+    let mut rom = None;
+    let mut i = 1;
+    while i < args.len() {
+        match (&args[i][..], args.get(i + 1)) {
+            ("--gdb", Some(port)) => {
+                gdb_port = Some(port.parse::<u16>().unwrap_or_else(|_| usage(&args[0])))
+            }
+            ("--target", Some(name)) => {
+                target = match name.parse() {
+                    Ok(Target::XoChip) => {
+                        println!("XO-CHIP programs cannot be run, only CHIP-8 and SCHIP programs");
+                        process::exit(2);
+                    }
+                    Ok(target) => target,
+                    Err(e) => {
+                        println!("{}", e);
+                        process::exit(2);
+                    }
+                }
+            }
+            (path, _) if rom.is_none() && !path.starts_with('-') => {
+                rom = Some(path);
+                i += 1;
+                continue;
+            }
+            _ => usage(&args[0]),
+        }
+        i += 2;
+    }
+    let rom = rom.unwrap_or_else(|| usage(&args[0]));
+    let listing = if rom.ends_with(".8o") {
+        Some(compile(rom, target))
+    } else {
+        None
+    };
+    let machine = match listing {
+        Some(ref listing) => Machine::new(&mut &listing.program.rom[..]),
+        None => {
+            let mut file = File::open(rom).unwrap_or_else(|e| {
+                println!("Error opening {}: {}", rom, e);
+                process::exit(2);
+            });
+            Machine::new(&mut file)
+        }
+    };
+    let mut machine = machine.unwrap_or_else(|e| {
+        println!("{}: {}", rom, e);
+        process::exit(2);
+    });
+    machine.seed_rng(0);
+
+    if let Some(port) = gdb_port {
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap_or_else(|e| {
+            println!("Error listening on port {}: {}", port, e);
+            process::exit(2);
+        });
+        println!("Waiting for debugger on port {}", port);
+        if let Err(e) = serve_gdb(&mut machine, &listener) {
+            println!("Connection to debugger failed: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let mut debugger = Debugger::new();
+    let listing = listing.as_ref();
+    for &(ref name, address) in listing.iter().flat_map(|listing| &listing.program.breakpoints) {
+        let breakpoint = Breakpoint {
+            address: Some(address),
+            condition: None,
+        };
+        println!("Breakpoint {}: {} ({})", debugger.breakpoints().len(), breakpoint, name);
+        debugger.add_breakpoint(breakpoint, machine.registers());
+    }
+
+    show_position(&machine, listing);
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(chip8) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        let line = if line.trim().is_empty() {
+            last.clone()
+        } else {
+            line.trim().to_string()
+        };
+        if line == "quit" || line == "q" {
+            break;
+        }
+        if let Err(e) = execute(&line, &mut machine, &mut debugger, listing) {
+            println!("{}", e);
+        }
+        last = line;
+    }
+}
+
+// Compiles Octo source and shows the line with the error if that fails
+fn compile(path: &str, target: Target) -> Listing {
+    let source = fs::read_to_string(path).unwrap_or_else(|e| {
+        println!("Error opening {}: {}", path, e);
+        process::exit(2);
+    });
+    let lines: Vec<String> = source.lines().map(|line| line.to_string()).collect();
+    match compile_runnable_octo(&source, target) {
+        Ok(program) => Listing { lines, program },
+        Err(e) => {
+            println!("{}:{}", path, e);
+            if let Some(line) = lines.get(e.line - 1) {
+                println!("{:>5} | {}", e.line, line);
+                println!("      | {:>1$}", "^", e.column);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+fn execute(
+    line: &str,
+    machine: &mut Machine,
+    debugger: &mut Debugger,
+    listing: Option<&Listing>,
+) -> Result<(), String> {
+    let mut parts = line.splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    let rest = parts.next().unwrap_or("").trim();
+    let args: Vec<&str> = rest.split_whitespace().collect();
+    let number = |index: usize, default: u16| match args.get(index) {
+        Some(arg) => parse_number(arg),
+        None => Ok(default),
+    };
+    match command {
+        "" => {}
+        "help" | "h" => println!("{}", HELP),
+        "step" | "s" => {
+            for _ in 0..number(0, 1)? {
+                if let Some(stop) = debugger.step(machine) {
+                    report(stop, machine, debugger, listing);
+                    return Ok(());
+                }
+            }
+            show_position(machine, listing);
+        }
+        "continue" | "c" => {
+            let limit = match args.first() {
+                Some(arg) => parse_number(arg)? as u64,
+                None => u64::MAX,
+            };
+            match debugger.run(machine, limit) {
+                Some(stop) => report(stop, machine, debugger, listing),
+                None => show_position(machine, listing),
+            }
+        }
+        "break" | "b" => {
+            let breakpoint: Breakpoint = rest.parse()?;
+            println!("Breakpoint {}: {}", debugger.breakpoints().len(), breakpoint);
+            debugger.add_breakpoint(breakpoint, machine.registers());
+        }
+        "watch" | "w" => {
+            let range = args.first().ok_or("Missing address")?;
+            let mut bounds = range.splitn(2, '-');
+            let start = parse_number(bounds.next().unwrap())?;
+            let end = match bounds.next() {
+                Some(end) => parse_number(end)?,
+                None => start,
+            };
+            let kind = match args.get(1) {
+                Some(kind) => kind.parse()?,
+                None => WatchKind::Write,
+            };
+            let watchpoint = Watchpoint { start, end, kind };
+            let memory = machine.memory_mut();
+            println!("Watchpoint {}: {}", memory.watchpoints().len(), watchpoint);
+            memory.add_watchpoint(watchpoint);
+        }
+        "delete" | "d" => {
+            if debugger.remove_breakpoint(number(0, 0)? as usize).is_none() {
+                return Err("No such breakpoint".to_string());
+            }
+        }
+        "unwatch" => {
+            if machine.memory_mut().remove_watchpoint(number(0, 0)? as usize).is_none() {
+                return Err("No such watchpoint".to_string());
+            }
+        }
+        "info" | "i" => {
+            for (index, breakpoint) in debugger.breakpoints().iter().enumerate() {
+                println!("Breakpoint {}: {}", index, breakpoint);
+            }
+            for (index, watchpoint) in machine.memory().watchpoints().iter().enumerate() {
+                println!("Watchpoint {}: {}", index, watchpoint);
+            }
+        }
+        "regs" | "r" => show_registers(machine),
+        "x" => {
+            let address = args.first().ok_or("Missing address")?;
+            let address = parse_number(address)?;
+            let length = number(1, 16)?;
+            for row in (0..length).step_by(16) {
+                print!("{:03X}:", address.wrapping_add(row));
+                for offset in row..length.min(row + 16) {
+                    print!(" {:02X}", read(machine, address.wrapping_add(offset))?);
+                }
+                println!();
+            }
+        }
+        "disasm" => {
+            let address = number(0, machine.pc())?;
+            for index in 0..number(1, 8)? {
+                let address = address.wrapping_add(2 * index);
+                let marker = if address == machine.pc() { ">" } else { " " };
+                println!("{} {}", marker, describe(machine, listing, address));
+            }
+        }
+        "screen" => show_screen(machine),
+        "reset" => {
+            match args.first() {
+                Some(&"soft") => machine.soft_reset(),
+                Some(_) => return Err("Expected `reset` or `reset soft`".to_string()),
+                None => machine.reset(),
+            }
+            .map_err(|e| format!("Error restarting the program: {}", e))?;
+            show_position(machine, listing);
+        }
+        "key" => {
+            let key = args.first().and_then(|key| u8::from_str_radix(key, 16).ok());
+            let down = match args.get(1) {
+                Some(&"down") => Some(true),
+                Some(&"up") => Some(false),
+                _ => None,
+            };
+            match (key, down) {
+                (Some(key), Some(down)) if key < 16 => machine.set_key(key, down),
+                _ => return Err("Expected `key K down|up` with K from 0 to F".to_string()),
+            }
+        }
+        _ => return Err(format!("Unknown command: {} (type `help` for a list)", command)),
+    }
+    Ok(())
+}
+
+fn read(machine: &Machine, address: u16) -> Result<u8, String> {
+    machine
+        .memory()
+        .inner()
+        .read_byte(address)
+        .map_err(|e| e.to_string())
+}
+
+fn report(stop: Stop, machine: &Machine, debugger: &Debugger, listing: Option<&Listing>) {
+    match stop {
+        Stop::Breakpoint(index) => {
+            println!("Breakpoint {}: {}", index, debugger.breakpoints()[index]);
+        }
+        Stop::Watchpoint { pc, hit } => {
+            println!("Watchpoint: {}", hit);
+            println!("by {}", describe(machine, listing, pc));
+        }
+        Stop::Error(e) => println!("Error at {:03X}: {}", machine.pc(), e),
+    }
+    show_position(machine, listing);
+}
+
+fn show_position(machine: &Machine, listing: Option<&Listing>) {
+    println!("{}", describe(machine, listing, machine.pc()));
+}
+
+fn describe(machine: &Machine, listing: Option<&Listing>, address: u16) -> String {
+    let instruction = match (read(machine, address), read(machine, address.wrapping_add(1))) {
+        (Ok(high), Ok(low)) => {
+            let opcode = (high as u16) << 8 | low as u16;
+            format!("{:03X}  {:04X}  {}", address, opcode, disassemble(opcode))
+        }
+        _ => return format!("{:03X}  outside of memory", address),
+    };
+    let source = listing.and_then(|listing| {
+        let line = listing.program.line(address)?;
+        Some(format!("{:>5} | {}", line, listing.lines[line - 1].trim()))
+    });
+    match source {
+        Some(source) => format!("{:<30}{}", instruction, source),
+        None => instruction,
+    }
+}
+
+fn show_registers(machine: &Machine) {
+    let r = machine.registers();
+    for (x, v) in r.v.iter().enumerate() {
+        print!("V{:X}={:02X}{}", x, v, if x % 8 == 7 { "\n" } else { " " });
+    }
+    println!(
+        "I={:03X} PC={:03X} SP={:X} DT={:02X} ST={:02X}",
+        r.i, r.pc, r.sp, r.delay_timer, r.sound_timer
+    );
+    let stack: Vec<String> = r.stack[..r.sp as usize]
+        .iter()
+        .map(|address| format!("{:03X}", address))
+        .collect();
+    println!("stack: [{}]", stack.join(" "));
+}
+
+fn show_screen(machine: &Machine) {
+    let framebuffer = machine.framebuffer();
+    for row in framebuffer.pixels().chunks(COLS) {
+        let row: String = row.iter().map(|&pixel| if pixel { '#' } else { '.' }).collect();
+        println!("{}", row);
+    }
+}
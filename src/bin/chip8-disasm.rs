@@ -0,0 +1,33 @@
+extern crate chip8;
+
+use chip8::{analyze, disassemble_rom};
+use std::env;
+use std::fs;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [--dot] ROM_FILE", program);
+    println!();
+    println!("Disassembles the code reachable from 0x200 and shows the rest as data, in a");
+    println!("listing that chip8-asm assembles back into the ROM. With --dot, the control-flow");
+    println!("graph is written in the DOT format of Graphviz instead.");
+    process::exit(2);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let (dot, rom_path) = match args.len() {
+        2 => (false, &args[1]),
+        3 if args[1] == "--dot" => (true, &args[2]),
+        _ => usage(&args[0]),
+    };
+    let rom = fs::read(rom_path).unwrap_or_else(|e| {
+        println!("Error reading {}: {}", rom_path, e);
+        process::exit(2);
+    });
+    if dot {
+        print!("{}", analyze(&rom).to_dot());
+    } else {
+        print!("{}", disassemble_rom(&rom));
+    }
+}
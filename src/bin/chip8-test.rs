@@ -0,0 +1,113 @@
+extern crate chip8;
+
+use chip8::{Expectation, Framebuffer, InputScript, Quirks, RomTest};
+use std::env;
+use std::fs;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [OPTIONS] ROM_FILE", program);
+    println!();
+    println!("Runs the ROM headless and compares the final screen against an expected result.");
+    println!("Exits with 1 if the screen does not match and with 2 on other errors.");
+    println!();
+    println!("Options:");
+    println!("    --frames N            number of frames to run (default: 600)");
+    println!("    --seed SEED           seed for random numbers (default: 0)");
+    println!("    --input FILE          key presses, one `FRAME KEY down|up` per line");
+    println!("    --quirks PRESET       vip, schip, xochip or modern (default: modern)");
+    println!("    --tick-rate N         instructions per frame (default: 10)");
+    println!("    --expect-hash HASH    expected hash of the screen as printed by this tool");
+    println!("    --expect-image FILE   expected screen as plain PBM image");
+    println!("    --write-image FILE    write the final screen as plain PBM image");
+    process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    println!("{}", message);
+    process::exit(2);
+}
+
+fn read_file(path: &str) -> String {
+    fs::read_to_string(path).unwrap_or_else(|e| fail(format!("Error reading {}: {}", path, e)))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut test = RomTest {
+        frames: 600,
+        ..RomTest::default()
+    };
+    let mut expectation = None;
+    let mut write_image = None;
+    let mut rom = None;
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg.starts_with("--") {
+            let value = match args.get(i + 1) {
+                Some(value) => value,
+                None => usage(&args[0]),
+            };
+            match &arg[..] {
+                "--frames" => {
+                    test.frames = value.parse().unwrap_or_else(|_| usage(&args[0]))
+                }
+                "--seed" => test.seed = value.parse().unwrap_or_else(|_| usage(&args[0])),
+                "--quirks" => {
+                    test.quirks = value.parse::<Quirks>().unwrap_or_else(|e| fail(e))
+                }
+                "--tick-rate" => {
+                    test.tick_rate = Some(value.parse().unwrap_or_else(|_| usage(&args[0])))
+                }
+                "--input" => {
+                    test.input = read_file(value)
+                        .parse::<InputScript>()
+                        .unwrap_or_else(|e| fail(format!("{}: {}", value, e)))
+                }
+                "--expect-hash" => {
+                    let hash = u64::from_str_radix(value, 16).unwrap_or_else(|_| usage(&args[0]));
+                    expectation = Some(Expectation::Hash(hash));
+                }
+                "--expect-image" => {
+                    let image = Framebuffer::from_pbm(&read_file(value))
+                        .unwrap_or_else(|e| fail(format!("{}: {}", value, e)));
+                    expectation = Some(Expectation::Image(Box::new(image)));
+                }
+                "--write-image" => write_image = Some(value),
+                _ => usage(&args[0]),
+            }
+            i += 2;
+        } else if rom.is_none() {
+            rom = Some(arg);
+            i += 1;
+        } else {
+            usage(&args[0]);
+        }
+    }
+    let rom = match rom {
+        Some(rom) => rom,
+        None => usage(&args[0]),
+    };
+
+    let rom_bytes = fs::read(rom).unwrap_or_else(|e| fail(format!("Error opening {}: {}", rom, e)));
+    let framebuffer = match test.run(&rom_bytes) {
+        Ok(framebuffer) => framebuffer,
+        Err(e) => {
+            println!("FAILED: {}", e);
+            process::exit(1);
+        }
+    };
+    println!("{:016x}  {}", framebuffer.hash(), rom);
+    if let Some(path) = write_image {
+        fs::write(path, framebuffer.to_pbm())
+            .unwrap_or_else(|e| fail(format!("Error writing {}: {}", path, e)));
+    }
+    if let Some(expectation) = expectation {
+        if let Err(e) = expectation.check(&framebuffer) {
+            println!("FAILED: {}", e);
+            process::exit(1);
+        }
+        println!("ok");
+    }
+}
@@ -0,0 +1,102 @@
+extern crate chip8;
+
+use chip8::{disassemble, first_divergence, parse_trace, TraceRecord};
+use std::cmp;
+use std::env;
+use std::fs;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [--context N] TRACE_A TRACE_B", program);
+    println!();
+    println!("Compares two instruction traces as written by `chip8 --trace FILE` and shows");
+    println!("the first instruction at which they differ together with N instructions of");
+    println!("context before and after it (default: 5). Exits with 1 if the traces differ.");
+    process::exit(2);
+}
+
+fn read_trace(path: &str) -> Vec<TraceRecord> {
+    let text = fs::read_to_string(path).unwrap_or_else(|e| {
+        println!("Error reading {}: {}", path, e);
+        process::exit(2);
+    });
+    parse_trace(&text).unwrap_or_else(|e| {
+        println!("{}: {}", path, e);
+        process::exit(2);
+    })
+}
+
+fn print_context(name: &str, trace: &[TraceRecord], divergence: usize, context: usize) {
+    println!("{}:", name);
+    let start = divergence.saturating_sub(context);
+    let end = cmp::min(trace.len(), divergence + context + 1);
+    for (index, record) in trace.iter().enumerate().take(end).skip(start) {
+        let marker = if index == divergence { ">" } else { " " };
+        println!(
+            "{} {:8}  {:03X}  {:04X}  {:20} {}",
+            marker,
+            index,
+            record.pc,
+            record.opcode,
+            disassemble(record.opcode),
+            record
+        );
+    }
+    if divergence >= trace.len() {
+        println!("> {:8}  end of trace", trace.len());
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut context = 5;
+    let mut paths = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        if args[i] == "--context" {
+            context = match args.get(i + 1).and_then(|value| value.parse().ok()) {
+                Some(context) => context,
+                None => usage(&args[0]),
+            };
+            i += 2;
+        } else {
+            paths.push(&args[i]);
+            i += 1;
+        }
+    }
+    if paths.len() != 2 {
+        usage(&args[0]);
+    }
+
+    let a = read_trace(paths[0]);
+    let b = read_trace(paths[1]);
+    let divergence = match first_divergence(&a, &b) {
+        Some(divergence) => divergence,
+        None => {
+            println!("Traces are identical ({} instructions)", a.len());
+            return;
+        }
+    };
+
+    println!("Traces diverge at instruction {}", divergence);
+    if let (Some(record_a), Some(record_b)) = (a.get(divergence), b.get(divergence)) {
+        // Records hold the state before their instruction, so the differences
+        // were caused by the previous instruction
+        if divergence > 0 {
+            let previous = &a[divergence - 1];
+            println!(
+                "after {:03X}  {}",
+                previous.pc,
+                disassemble(previous.opcode)
+            );
+        }
+        for difference in record_a.differences(record_b) {
+            println!("    {}", difference);
+        }
+    }
+    println!();
+    print_context(paths[0], &a, divergence, context);
+    println!();
+    print_context(paths[1], &b, divergence, context);
+    process::exit(1);
+}
@@ -1,16 +1,157 @@
 extern crate chip8;
 
+use chip8::{load_rom_file, zip_entries, Config};
 use std::env;
-use std::fs::File;
+use std::fs;
+use std::io::{self, BufRead, Write};
 use std::path::Path;
+use std::process;
+
+fn usage(program: &str) -> ! {
+    println!("Usage: {} [OPTIONS] ROM_FILE", program);
+    println!("       {} [OPTIONS] --browse DIR", program);
+    println!();
+    println!("ROM files ending in .8o are compiled from Octo source before running. Octo");
+    println!("cartridges (.gif) are compiled with their options. From zip archives, the ROM");
+    println!("given with --entry is run, or the only file, or the one picked from a list.");
+    println!();
+    println!("Options (later options override earlier ones):");
+    println!("    --config FILE        read options from FILE (`option = value` per line)");
+    println!("    --entry NAME         file to run from a zip archive");
+    println!("    --browse DIR         choose the ROM from a menu of the ROMs in DIR");
+    println!("    --frequency HZ       pitch of the beeper (default: 440)");
+    println!("    --waveform WAVE      square, sine, triangle, sawtooth or noise (default: square)");
+    println!("    --volume VOLUME      volume from 0 to 1 (default: 0.25)");
+    println!("    --duty-cycle RATIO   duty cycle of the square wave between 0 and 1 (default: 0.5)");
+    println!("    --record-audio FILE  write the sound output to a WAV file");
+    println!("    --headless FRAMES    run for FRAMES frames without window, sound or input");
+    println!("    --seed SEED          seed for random numbers to make runs reproducible");
+    println!("    --trace FILE         write the machine state before every instruction to FILE");
+    println!("    --trace-log FILE     log the executed instructions readably to FILE, - for stderr");
+    println!("    --trace-pc START-END only log instructions in this hex address range");
+    println!("    --trace-class LIST   only log these classes: flow, skip, arithmetic, memory,");
+    println!("                         display, input, timer (comma separated)");
+    println!("    --trace-last N       only keep the last N instructions and log them on errors");
+    println!("    --quirks PRESET      vip, schip, xochip or modern (default: modern)");
+    println!("    --quirk-shift BOOL   SHR and SHL shift Vx instead of Vy");
+    println!("    --quirk-increment-i BOOL  LD [I], Vx and LD Vx, [I] advance I");
+    println!("    --quirk-jump BOOL    JP V0, nnn uses Vx for the highest digit x of nnn");
+    println!("    --quirk-vf-reset BOOL  OR, AND and XOR reset VF");
+    println!("    --tick-rate N        instructions per frame (default: 10)");
+    println!("    --keymap KEYS        host keys for the CHIP-8 keys 0 to F, e.g. x123qweasdzc4rfv");
+    println!("    --quit-key KEY       key that quits, a character or e.g. Backspace (default: Escape)");
+    println!("    --foreground RRGGBB  color of set pixels (default: FFFFFF)");
+    println!("    --background RRGGBB  color of unset pixels (default: 000000)");
+    println!("    --font NAME          vip, eti-660, dream-6800, schip or octo (default)");
+    println!("    --font-file FILE     font of 80 bytes, optionally followed by big digits");
+    println!("    --font-address ADDR  hex address of the font (default: 000)");
+    println!("    --stack-depth N      return addresses on the stack (default: 16, 12 with the");
+    println!("                         VIP stack, which has room for at most 24)");
+    println!("    --vip-stack BOOL     keep the stack in memory at EA0 like the COSMAC VIP");
+    println!("    --memory-map MAP     plain or vip, with registers and display RAM in memory");
+    println!("    --load-address ADDR  hex address of the ROM, 600 for ETI-660 (default: 200)");
+    println!("    --target TARGET      chip8 or schip, the platform to compile Octo source for;");
+    println!("                         of the SCHIP instructions, only bighex can be run");
+    println!("    --rom-db FILE        ROM database (default: ~/.config/chip8/roms.db)");
+    println!();
+    println!("ROMs found in the database by their SHA-1 hash get its settings, unless the");
+    println!("options are given explicitly.");
+    println!();
+    println!("Press F8 while running to toggle the sound, F9 to show the debug panel,");
+    println!("F5 to pause or resume, and F6 or F7 to execute a single instruction or frame");
+    println!("while paused. F10 toggles fast-forward, F11 slow motion, and + (or =) and -");
+    println!("change the instructions per frame.");
+    println!("F2 restarts the game with the ROM loaded again, F3 with the memory as it is.");
+    println!("F1 returns to the menu when browsing.");
+    process::exit(1);
+}
+
+fn fail(message: String) -> ! {
+    println!("{}", message);
+    process::exit(1);
+}
+
+// Archives with several files let the user choose on the terminal
+fn pick_entry(archive: &str, mut names: Vec<String>) -> String {
+    match names.len() {
+        0 => fail(format!("{}: The archive is empty", archive)),
+        1 => return names.remove(0),
+        _ => {}
+    }
+    for (i, name) in names.iter().enumerate() {
+        println!("{:3}  {}", i + 1, name);
+    }
+    let stdin = io::stdin();
+    loop {
+        print!("ROM to run (1-{}): ", names.len());
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            process::exit(1);
+        }
+        match line.trim().parse::<usize>() {
+            Ok(i) if i >= 1 && i <= names.len() => return names.remove(i - 1),
+            _ => println!("Please enter a number from the list"),
+        }
+    }
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} ROM_FILE", args[0]);
-        ::std::process::exit(1);
+    let mut config = Config::default();
+    let mut entry = None;
+    let mut browse = None;
+    let mut rom = None;
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if let Some(option) = arg.strip_prefix("--") {
+            let value = match args.get(i + 1) {
+                Some(value) => value,
+                None => usage(&args[0]),
+            };
+            let result = match option {
+                "config" => config.load_file(Path::new(value)),
+                "entry" => {
+                    entry = Some(value.clone());
+                    Ok(())
+                }
+                "browse" => {
+                    browse = Some(value.clone());
+                    Ok(())
+                }
+                _ => config.set(option, value),
+            };
+            if let Err(e) = result {
+                fail(e);
+            }
+            i += 2;
+        } else if rom.is_none() {
+            rom = Some(arg);
+            i += 1;
+        } else {
+            usage(&args[0]);
+        }
+    }
+    if let Some(dir) = browse {
+        if rom.is_some() {
+            usage(&args[0]);
+        }
+        chip8::browse(Path::new(&dir), &config).unwrap_or_else(|e| fail(e));
+        return;
+    }
+    let rom = match rom {
+        Some(rom) => rom,
+        None => usage(&args[0]),
+    };
+    let path = Path::new(rom);
+    if entry.is_none() && rom.to_lowercase().ends_with(".zip") {
+        let bytes =
+            fs::read(path).unwrap_or_else(|e| fail(format!("Error opening {}: {}", rom, e)));
+        let names = zip_entries(&bytes).unwrap_or_else(|e| fail(format!("{}: {}", rom, e)));
+        entry = Some(pick_entry(rom, names));
     }
-    let path = Path::new(&args[1]);
-    let mut file = File::open(path).expect(&format!("Error opening file: {}", args[1]));
-    chip8::run(&mut file);
+    let entry = entry.as_ref().map(|entry| &entry[..]);
+    let rom = load_rom_file(path, entry, &mut config).unwrap_or_else(|e| fail(e));
+    chip8::run(&mut &rom[..], &config).unwrap_or_else(|e| fail(e));
 }
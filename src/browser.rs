@@ -0,0 +1,219 @@
+use archive::zip_entries;
+use config::Config;
+use loader::{extension, load_rom_data, ROM_EXTENSIONS};
+use overlay::{PANEL_COLS, PANEL_ROWS};
+use romdb::RomDatabase;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+// The heading and the help take two lines each
+const VISIBLE_ENTRIES: usize = PANEL_ROWS - 4;
+
+/// A ROM that can be started from the menu.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomEntry {
+    pub path: PathBuf,
+    /// The file in a zip archive
+    pub entry: Option<String>,
+    /// The title from the ROM database, otherwise the file name
+    pub title: String,
+}
+
+/// Lists the ROMs in a directory sorted by title. Every ROM in a zip
+/// archive gets an entry of its own.
+pub fn list_roms(dir: &Path, database: &RomDatabase) -> Result<Vec<RomEntry>, String> {
+    let error = |e: ::std::io::Error| format!("Error reading {}: {}", dir.display(), e);
+    let mut roms = Vec::new();
+    for file in fs::read_dir(dir).map_err(error)? {
+        let path = file.map_err(error)?.path();
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => continue,
+        };
+        if !path.is_file() || !ROM_EXTENSIONS.contains(&&extension(&name)[..]) {
+            continue;
+        }
+        let bytes = match fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+        if extension(&name) != "zip" {
+            let title = title(&name, bytes, None, database);
+            roms.push(RomEntry { path, entry: None, title });
+            continue;
+        }
+        // Broken archives are left out
+        for entry in zip_entries(&bytes).unwrap_or_default() {
+            let is_rom = ROM_EXTENSIONS.contains(&&extension(&entry)[..]);
+            if !is_rom || extension(&entry) == "zip" {
+                continue;
+            }
+            let title = title(&name, bytes.clone(), Some(&entry), database);
+            roms.push(RomEntry {
+                path: path.clone(),
+                entry: Some(entry),
+                title,
+            });
+        }
+    }
+    roms.sort_by_key(|rom| rom.title.to_lowercase());
+    Ok(roms)
+}
+
+// ROMs that cannot be loaded are still listed, so that the error is shown
+// when they are started
+fn title(name: &str, bytes: Vec<u8>, entry: Option<&str>, database: &RomDatabase) -> String {
+    let rom = load_rom_data(name, bytes, entry, &mut Config::default());
+    let title = rom.ok().and_then(|rom| database.lookup(&rom).and_then(|info| info.title.clone()));
+    title.unwrap_or_else(|| {
+        let path = Path::new(entry.unwrap_or(name));
+        path.file_name().unwrap_or_default().to_string_lossy().into_owned()
+    })
+}
+
+/// The selection in the list of ROMs.
+pub struct Menu {
+    roms: Vec<RomEntry>,
+    selected: usize,
+    // Index of the first visible entry
+    scroll: usize,
+}
+
+impl Menu {
+    pub fn new(roms: Vec<RomEntry>) -> Menu {
+        Menu {
+            roms,
+            selected: 0,
+            scroll: 0,
+        }
+    }
+
+    pub fn selected(&self) -> Option<&RomEntry> {
+        self.roms.get(self.selected)
+    }
+
+    /// Moves the selection by `delta` entries, stopping at the first and
+    /// the last one.
+    pub fn move_by(&mut self, delta: isize) {
+        let last = self.roms.len().saturating_sub(1) as isize;
+        self.selected = (self.selected as isize + delta).clamp(0, last) as usize;
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if self.selected >= self.scroll + VISIBLE_ENTRIES {
+            self.scroll = self.selected + 1 - VISIBLE_ENTRIES;
+        }
+    }
+
+    pub fn page_size() -> isize {
+        VISIBLE_ENTRIES as isize
+    }
+
+    /// The text of the menu, with the selected ROM marked by `>`.
+    pub fn lines(&self, heading: &str, quit_key: &str) -> Vec<String> {
+        let mut lines = vec![heading.chars().take(PANEL_COLS).collect(), String::new()];
+        if self.roms.is_empty() {
+            lines.push("No ROMs found".to_string());
+        }
+        let visible = self.roms.iter().enumerate().skip(self.scroll).take(VISIBLE_ENTRIES);
+        for (i, rom) in visible {
+            let marker = if i == self.selected { '>' } else { ' ' };
+            let title: String = rom.title.chars().take(PANEL_COLS - 2).collect();
+            lines.push(format!("{} {}", marker, title));
+        }
+        lines.resize(PANEL_ROWS - 2, String::new());
+        lines.push("Enter: play  F1: menu".to_string());
+        lines.push(format!("{}: quit", quit_key).chars().take(PANEL_COLS).collect());
+        lines
+    }
+}
+
+/// The text shown instead of the menu when a ROM cannot be started or stops
+/// with an error, with the error wrapped to the width of the panel.
+pub fn error_lines(title: &str, error: &str) -> Vec<String> {
+    let heading = format!("Error running {}", title);
+    let mut lines = vec![heading.chars().take(PANEL_COLS).collect(), String::new()];
+    let mut line = String::new();
+    for word in error.split_whitespace() {
+        // Words longer than a line, like paths, are split
+        let chars: Vec<char> = word.chars().collect();
+        for piece in chars.chunks(PANEL_COLS) {
+            if !line.is_empty() && line.chars().count() + 1 + piece.len() > PANEL_COLS {
+                lines.push(mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.extend(piece);
+        }
+    }
+    lines.push(line);
+    lines.truncate(PANEL_ROWS - 2);
+    lines.resize(PANEL_ROWS - 1, String::new());
+    lines.push("Press a key to go back".to_string());
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn rom(title: &str) -> RomEntry {
+        RomEntry {
+            path: PathBuf::from(format!("{}.ch8", title)),
+            entry: None,
+            title: title.to_string(),
+        }
+    }
+
+    #[test]
+    fn scrolls_with_the_selection() {
+        let mut menu = Menu::new((0..40).map(|i| rom(&format!("ROM {}", i))).collect());
+        menu.move_by(-1);
+        assert_eq!(menu.selected(), Some(&rom("ROM 0")));
+        let lines = menu.lines("ROMS", "Escape");
+        assert_eq!(lines.len(), PANEL_ROWS);
+        assert_eq!(lines[2], "> ROM 0");
+        assert_eq!(lines[3], "  ROM 1");
+        assert_eq!(lines[PANEL_ROWS - 1], "Escape: quit");
+
+        menu.move_by(Menu::page_size() + 2);
+        let lines = menu.lines("ROMS", "Escape");
+        assert_eq!(lines[2], "  ROM 3");
+        assert_eq!(lines[PANEL_ROWS - 3], "> ROM 18");
+        menu.move_by(100);
+        assert_eq!(menu.selected(), Some(&rom("ROM 39")));
+        assert!(Menu::new(Vec::new()).selected().is_none());
+    }
+
+    #[test]
+    fn errors_are_wrapped() {
+        let error = format!("Error opening /roms/{}.ch8: No such file", "x".repeat(40));
+        let lines = error_lines("Pong", &error);
+        assert_eq!(lines.len(), PANEL_ROWS);
+        assert_eq!(lines[0], "Error running Pong");
+        assert_eq!(lines[2], "Error opening");
+        assert_eq!(lines[3], format!("/roms/{}", "x".repeat(PANEL_COLS - 6)));
+        assert_eq!(lines[4], format!("{}.ch8: No such file", "x".repeat(46 - PANEL_COLS)));
+        assert!(lines.iter().all(|line| line.chars().count() <= PANEL_COLS));
+        assert_eq!(lines[PANEL_ROWS - 1], "Press a key to go back");
+    }
+
+    #[test]
+    fn lists_roms_with_titles() {
+        let dir = env::temp_dir().join(format!("chip8-browser-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.ch8"), [0x12, 0x00]).unwrap();
+        fs::write(dir.join("a.8o"), ": main\n  v0 := 1\n  loop again\n").unwrap();
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        let mut database = RomDatabase::new();
+        database.parse(&format!("[{}]\ntitle = Zzz", ::romdb::hash(&[0x12, 0x00]))).unwrap();
+
+        let roms = list_roms(&dir, &database).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let titles: Vec<&str> = roms.iter().map(|rom| &rom.title[..]).collect();
+        assert_eq!(titles, vec!["a.8o", "Zzz"]);
+        assert_eq!(roms[1].path, dir.join("b.ch8"));
+    }
+}
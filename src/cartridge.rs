@@ -0,0 +1,177 @@
+use config::Config;
+use gif;
+use octo::Target;
+use romdb::RomInfo;
+use serde_json::{self, Value};
+
+/// A program shared as Octo cartridge: a GIF image that carries the source
+/// and the options of the program in the low two bits of its pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cartridge {
+    /// Octo source of the program
+    pub source: String,
+    /// Platform and settings from the options of the cartridge
+    pub info: RomInfo,
+}
+
+/// Extracts the program from a cartridge image. The pixels of all frames
+/// form a stream of two bit pieces, most significant first, of a 32 bit big
+/// endian length and a JSON object of that many bytes, which holds the
+/// `program` and its `options`.
+pub fn read_cartridge(image: &[u8]) -> Result<Cartridge, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(image)
+        .map_err(|e| format!("Invalid GIF image: {}", e))?;
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|e| format!("Invalid GIF image: {}", e))?
+    {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+    parse_payload(&decode_payload(&pixels)?)
+}
+
+fn decode_payload(pixels: &[u8]) -> Result<Vec<u8>, String> {
+    let mut bytes = pixels
+        .chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|chunk| chunk.iter().fold(0, |byte, &pixel| byte << 2 | (pixel & 3)));
+    let mut length = 0usize;
+    for _ in 0..4 {
+        let byte = bytes.next().ok_or_else(|| "Not an Octo cartridge".to_string())?;
+        length = length << 8 | byte as usize;
+    }
+    let payload: Vec<u8> = bytes.take(length).collect();
+    if payload.len() < length {
+        return Err("Not an Octo cartridge: the image is too small for its data".to_string());
+    }
+    Ok(payload)
+}
+
+fn parse_payload(payload: &[u8]) -> Result<Cartridge, String> {
+    let json: Value = serde_json::from_slice(payload)
+        .map_err(|e| format!("Not an Octo cartridge: {}", e))?;
+    let source = match json["program"].as_str() {
+        Some(source) => source.to_string(),
+        None => return Err("Octo cartridge without program".to_string()),
+    };
+    Ok(Cartridge {
+        source,
+        info: rom_info(&json["options"]),
+    })
+}
+
+// Translates the options of Octo into those of the emulator. Options that
+// have no counterpart, like the colors of the sound indicator, are ignored.
+fn rom_info(options: &Value) -> RomInfo {
+    // The presets of Octo's options differ in the program size, which Octo
+    // limits to the memory of the platform
+    let (platform, quirks) = match options["maxSize"].as_u64() {
+        Some(3215) => (Some(Target::Chip8), None),
+        Some(3583) => (Some(Target::Schip), None),
+        // Octo's own preset has the instructions of SCHIP and the quirks of
+        // XO-CHIP
+        Some(3584) => (Some(Target::Schip), Some("xochip")),
+        Some(65024) => (Some(Target::XoChip), None),
+        _ => (None, None),
+    };
+    let mut info = RomInfo {
+        platform,
+        ..RomInfo::default()
+    };
+    let mut add = |option: &str, value: String| info.options.push((option.to_string(), value));
+    if let Some(quirks) = quirks {
+        add("quirks", quirks.to_string());
+    }
+    if let Some(rate) = options["tickrate"].as_u64().filter(|&rate| rate > 0) {
+        add("tick-rate", rate.to_string());
+    }
+    let colors = [("fillColor", "foreground"), ("backgroundColor", "background")];
+    for &(name, option) in &colors {
+        if let Some(color) = options[name].as_str() {
+            add(option, color.to_string());
+        }
+    }
+    // Octo's load/store quirk leaves I unchanged
+    let quirks = [
+        ("shiftQuirks", "quirk-shift", false),
+        ("loadStoreQuirks", "quirk-increment-i", true),
+        ("jumpQuirks", "quirk-jump", false),
+        ("logicQuirks", "quirk-vf-reset", false),
+    ];
+    for &(name, option, inverted) in &quirks {
+        if let Some(enabled) = options[name].as_bool() {
+            add(option, (enabled != inverted).to_string());
+        }
+    }
+    // Values the emulator does not understand, like colors in other
+    // notations, are dropped
+    info.options.retain(|(option, value)| Config::default().set(option, value).is_ok());
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    // Spreads the bytes over the low bits of pixels with varying high bits,
+    // the way Octo draws the label
+    fn encode(payload: &str) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload.as_bytes());
+        let mut pixels = Vec::new();
+        for byte in bytes {
+            for shift in [6, 4, 2, 0].iter() {
+                pixels.push(0b1100 | (byte >> shift) & 3);
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn reads_program_and_options() {
+        let json = r##"{"program": ": main\n  loop again", "options": {
+            "tickrate": 20, "maxSize": 3584, "fillColor": "#FF8000",
+            "backgroundColor": "nonsense", "shiftQuirks": true, "loadStoreQuirks": true,
+            "buzzColor": "#FFAA00"}}"##;
+        let cartridge = parse_payload(&decode_payload(&encode(json)).unwrap()).unwrap();
+        assert_eq!(cartridge.source, ": main\n  loop again");
+        assert_eq!(cartridge.info.platform, Some(Target::Schip));
+
+        let mut config = Config::default();
+        cartridge.info.apply(&mut config);
+        assert_eq!(config.tick_rate, Some(20));
+        assert_eq!(config.palette.foreground, [0xFF, 0x80, 0x00]);
+        assert_eq!(config.palette.background, [0x00; 3]);
+        // Octo's own quirks with shifting and without incrementing I
+        assert_eq!(config.quirks, Quirks::MODERN);
+    }
+
+    fn preset(max_size: u64) -> (Option<Target>, Quirks) {
+        let info = rom_info(&serde_json::json!({ "maxSize": max_size }));
+        let mut config = Config::default();
+        info.apply(&mut config);
+        (info.platform, config.quirks)
+    }
+
+    #[test]
+    fn presets_are_recognized_by_their_size() {
+        assert_eq!(preset(3215), (Some(Target::Chip8), Quirks::VIP));
+        assert_eq!(preset(3583), (Some(Target::Schip), Quirks::SCHIP));
+        assert_eq!(preset(3584), (Some(Target::Schip), Quirks::XOCHIP));
+        assert_eq!(preset(65024), (Some(Target::XoChip), Quirks::XOCHIP));
+        assert_eq!(preset(4000), (None, Quirks::MODERN));
+    }
+
+    #[test]
+    fn rejects_other_images() {
+        assert!(decode_payload(&[0; 15]).is_err());
+        assert!(decode_payload(&encode("{}")[..20]).is_err());
+        let payload = decode_payload(&encode("{\"options\": {}}")).unwrap();
+        assert_eq!(parse_payload(&payload), Err("Octo cartridge without program".to_string()));
+    }
+}
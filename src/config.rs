@@ -0,0 +1,270 @@
+use audio::BeeperConfig;
+use display::Palette;
+use font::Font;
+use keyboard::{Keymap, QuitKey};
+use memory::MemoryMap;
+use octo::Target;
+use quirks::Quirks;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use trace::TracerConfig;
+
+/// Settings of the emulator. Every setting can be changed by name, which is
+/// used both for the command line options and for config files.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub beeper: BeeperConfig,
+    /// Run without window and sound for the given number of frames
+    pub headless: Option<u32>,
+    pub record_audio: Option<PathBuf>,
+    /// Seed for the random number instruction, random if not set
+    pub seed: Option<u32>,
+    pub trace: Option<PathBuf>,
+    pub tracer: TracerConfig,
+    pub quirks: Quirks,
+    /// Instructions executed per frame, 10 if not set
+    pub tick_rate: Option<u32>,
+    pub keymap: Keymap,
+    pub quit_key: QuitKey,
+    pub palette: Palette,
+    pub font: Font,
+    /// Where the font is stored in memory
+    pub font_address: u16,
+    /// Return addresses that fit on the stack, 16 if not set
+    pub stack_depth: Option<usize>,
+    /// Keep the stack in memory at 0xEA0 like the COSMAC VIP
+    pub vip_stack: bool,
+    /// The VIP memory map also keeps the stack in memory
+    pub memory_map: MemoryMap,
+    /// Where the ROM is loaded and execution starts, 0x200 if not set
+    pub load_address: Option<u16>,
+    /// Platform Octo source is compiled for, CHIP-8 or the one of the
+    /// cartridge if not set
+    pub target: Option<Target>,
+    /// ROM database read in addition to the bundled one
+    pub rom_db: Option<PathBuf>,
+    // Options set by name with their values, which take precedence over
+    // `set_default`
+    explicit: HashMap<String, String>,
+}
+
+impl Config {
+    /// Sets an option. Fails if the value is invalid or if the quit key
+    /// would be one of the keys of the keymap.
+    pub fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let (keymap, quit_key) = (self.keymap, self.quit_key);
+        self.apply(option, value)?;
+        if self.keymap.contains(self.quit_key.keycode()) {
+            let error = format!("The quit key {} is also in the keymap", self.quit_key.name());
+            self.keymap = keymap;
+            self.quit_key = quit_key;
+            return Err(error);
+        }
+        self.explicit.insert(option.to_string(), value.to_string());
+        Ok(())
+    }
+
+    /// Sets an option unless it has been set with `set` before. Used for
+    /// the settings of a ROM, which the user can still override.
+    pub fn set_default(&mut self, option: &str, value: &str) -> Result<(), String> {
+        // A preset also counts for the single quirks it consists of
+        let preset = option.starts_with("quirk-") && self.explicit.contains_key("quirks");
+        if self.explicit.contains_key(option) || preset {
+            return Ok(());
+        }
+        let keymap = self.keymap;
+        self.apply(option, value)?;
+        // The keymap of a ROM must not take the user's quit key
+        if self.keymap.contains(self.quit_key.keycode()) {
+            self.keymap = keymap;
+        }
+        // and must not replace the single quirks the user set
+        if option == "quirks" {
+            for (option, value) in &self.explicit.clone() {
+                if option.starts_with("quirk-") {
+                    self.apply(option, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, option: &str, value: &str) -> Result<(), String> {
+        match option {
+            "frequency" => self.beeper.frequency = parse_range(option, value, 20.0, 20000.0)?,
+            "waveform" => self.beeper.waveform = value.parse()?,
+            "volume" => self.beeper.volume = parse_range(option, value, 0.0, 1.0)?,
+            "duty-cycle" => {
+                // 0 and 1 would give a constant level without any tone
+                self.beeper.duty_cycle = match value.parse() {
+                    Ok(ratio) if ratio > 0.0 && ratio < 1.0 => ratio,
+                    _ => return Err(format!("Invalid duty cycle: {} (expected 0 < x < 1)", value)),
+                }
+            }
+            "headless" => {
+                self.headless = Some(value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of frames: {}", value))?)
+            }
+            "record-audio" => self.record_audio = Some(PathBuf::from(value)),
+            "trace" => self.trace = Some(PathBuf::from(value)),
+            "trace-log" => self.tracer.output = Some(PathBuf::from(value)),
+            "trace-pc" => self.tracer.pc_range = Some(parse_address_range(value)?),
+            "trace-class" => {
+                self.tracer.classes = value
+                    .split(',')
+                    .map(|class| class.trim().parse())
+                    .collect::<Result<_, _>>()?
+            }
+            "trace-last" => {
+                self.tracer.last = Some(value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of instructions: {}", value))?)
+            }
+            "seed" => {
+                self.seed = Some(value.parse().map_err(|_| format!("Invalid seed: {}", value))?)
+            }
+            "quirks" => self.quirks = value.parse()?,
+            "quirk-shift" => self.quirks.shift_vx = parse_bool(option, value)?,
+            "quirk-increment-i" => self.quirks.increment_i = parse_bool(option, value)?,
+            "quirk-jump" => self.quirks.jump_vx = parse_bool(option, value)?,
+            "quirk-vf-reset" => self.quirks.reset_vf = parse_bool(option, value)?,
+            "tick-rate" => {
+                self.tick_rate = match value.parse() {
+                    Ok(rate) if rate > 0 => Some(rate),
+                    _ => return Err(format!("Invalid tick rate: {}", value)),
+                }
+            }
+            "keymap" => self.keymap = value.parse()?,
+            "quit-key" => self.quit_key = value.parse()?,
+            "foreground" => self.palette.foreground = parse_color(value)?,
+            "background" => self.palette.background = parse_color(value)?,
+            "font" => self.font = value.parse()?,
+            "font-file" => self.font = Font::load_file(Path::new(value))?,
+            "font-address" => {
+                self.font_address = match u16::from_str_radix(value.trim_start_matches("0x"), 16) {
+                    Ok(address) if address < 0x1000 => address,
+                    _ => return Err(format!("Invalid font address: {}", value)),
+                }
+            }
+            "stack-depth" => {
+                self.stack_depth = match value.parse() {
+                    Ok(depth) if depth > 0 && depth <= 255 => Some(depth),
+                    _ => return Err(format!("Invalid stack depth: {} (expected 1 to 255)", value)),
+                }
+            }
+            "vip-stack" => self.vip_stack = parse_bool(option, value)?,
+            "memory-map" => self.memory_map = value.parse()?,
+            "load-address" => self.load_address = Some(parse_load_address(value)?),
+            "target" => self.target = Some(value.parse()?),
+            "rom-db" => self.rom_db = Some(PathBuf::from(value)),
+            _ => return Err(format!("Unknown option: {}", option)),
+        }
+        Ok(())
+    }
+
+    /// Reads a config file consisting of `option = value` lines. Empty lines
+    /// and lines starting with `#` are ignored.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let option = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => {
+                    let line = number + 1;
+                    return Err(format!("{}:{}: expected `option = value`", path.display(), line));
+                }
+            };
+            self.set(option, value)
+                .map_err(|e| format!("{}:{}: {}", path.display(), number + 1, e))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_range(option: &str, value: &str, min: f32, max: f32) -> Result<f32, String> {
+    match value.parse::<f32>() {
+        Ok(x) if x >= min && x <= max => Ok(x),
+        _ => Err(format!("Invalid value for {}: {} (expected {} to {})", option, value, min, max)),
+    }
+}
+
+fn parse_bool(option: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("Invalid value for {}: {} (expected true or false)", option, value)),
+    }
+}
+
+// Parses a hex address within the memory, at which there is room for at
+// least one instruction
+fn parse_load_address(value: &str) -> Result<u16, String> {
+    match u16::from_str_radix(value.trim_start_matches("0x"), 16) {
+        Ok(address) if address <= 0xFFE => Ok(address),
+        _ => Err(format!("Invalid load address: {} (expected e.g. 200 or 600)", value)),
+    }
+}
+
+// Parses a hex color like `FF8000`, optionally prefixed with `#`
+fn parse_color(value: &str) -> Result<[u8; 3], String> {
+    let invalid = || format!("Invalid color: {} (expected RRGGBB)", value);
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut color = [0; 3];
+    for (i, component) in color.iter_mut().enumerate() {
+        *component = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(color)
+}
+
+// Parses an inclusive range of hex addresses like `200-2FF`
+fn parse_address_range(value: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("Invalid address range: {} (expected e.g. 200-2FF)", value);
+    let mut parts = value.splitn(2, '-');
+    let mut parse = || {
+        let part = parts.next().ok_or_else(invalid)?.trim().trim_start_matches("0x");
+        u16::from_str_radix(part, 16).map_err(|_| invalid())
+    };
+    let start = parse()?;
+    let end = parse()?;
+    if start > end {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quit_key_must_not_be_in_the_keymap() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.set("quit-key", "q"),
+            Err("The quit key Q is also in the keymap".to_string())
+        );
+        assert_eq!(config.quit_key, QuitKey::default());
+        config.set("quit-key", "x").unwrap();
+        assert!(config.set("keymap", "x123qweasdzc4rfv").is_err());
+        assert_eq!(config.keymap, Keymap::default());
+        config.set("keymap", "0123456789abcdef").unwrap();
+
+        // The keymap of a ROM gives way to the user's quit key
+        config.set_default("keymap", "x123qweasdzc4rfv").unwrap();
+        assert_eq!(config.keymap, "0123456789abcdef".parse().unwrap());
+    }
+}
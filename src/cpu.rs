@@ -1,39 +1,154 @@
-use audio::AudioDevice;
 use display::Display;
-use memory::{BlockMemory, Memory};
+use error::Error;
+use font::SMALL_FONT_SIZE;
+use memory::{Memory, RegionKind};
 use keyboard::Keyboard;
+use quirks::Quirks;
 use rand;
-use rand::Rng;
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::io::{self, Write};
 use std::num::Wrapping;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use trace::{TraceRecord, Tracer};
 
-pub struct Cpu<'a> {
+pub struct Cpu<M, D, K> {
     registers: Registers,
-    memory: BlockMemory,
-    display: Display<'a>,
-    keyboard: Keyboard<'a>,
-    audio_device: AudioDevice,
+    memory: M,
+    display: D,
+    keyboard: K,
+    sound_active: Arc<AtomicBool>,
+    rng: XorShiftRng,
+    quirks: Quirks,
+    // Where execution starts after a reset
+    entry_point: u16,
+    font_address: u16,
+    // Where the stack is kept in memory in addition to the registers
+    stack_address: Option<u16>,
+    trace_output: Option<Box<dyn Write>>,
+    tracer: Option<Tracer>,
 }
 
-impl<'a> Cpu<'a> {
+impl<M: Memory, D: Display, K: Keyboard> Cpu<M, D, K> {
+    /// `sound_active` is kept in sync with the sound timer, i.e. it is set
+    /// as long as the timer is running.
     pub fn new(
-        memory: BlockMemory,
-        display: Display<'a>,
-        keyboard: Keyboard<'a>,
-        audio_device: AudioDevice,
-    ) -> Cpu<'a> {
+        memory: M,
+        display: D,
+        keyboard: K,
+        sound_active: Arc<AtomicBool>,
+    ) -> Cpu<M, D, K> {
         Cpu {
             registers: Registers::new(),
             memory,
             display,
             keyboard,
-            audio_device,
+            sound_active,
+            rng: rand::weak_rng(),
+            quirks: Quirks::default(),
+            entry_point: 0x200,
+            font_address: 0,
+            stack_address: None,
+            trace_output: None,
+            tracer: None,
         }
     }
 
-    pub fn cycle(&mut self) {
-        self.keyboard.check_events();
-        let opcode = self.fetch_opcode();
-        self.execute_opcode(opcode);
+    /// Writes a `TraceRecord` for every executed instruction to `output`.
+    pub fn set_trace_output(&mut self, output: Box<dyn Write>) {
+        self.trace_output = Some(output);
+    }
+
+    /// Logs the executed instructions in human readable form.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Sets where the font is in memory, for `LD F, Vx` and `LD HF, Vx`.
+    /// 0x000 by default.
+    pub fn set_font_address(&mut self, address: u16) {
+        self.font_address = address;
+    }
+
+    /// Sets how many return addresses fit on the stack, 16 by default.
+    /// Clears the stack.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.registers.stack = vec![0; depth];
+        self.registers.sp = 0;
+    }
+
+    /// Keeps the stack in memory from `address` on, two bytes per entry
+    /// with the high byte first, like the COSMAC VIP does at 0xEA0. Return
+    /// addresses are then read from memory, so that programs can change
+    /// them.
+    pub fn set_stack_address(&mut self, address: Option<u16>) {
+        self.stack_address = address;
+    }
+
+    /// Sets where execution starts, now and after a reset. 0x200 by default.
+    pub fn set_entry_point(&mut self, address: u16) {
+        self.entry_point = address;
+        self.registers.pc = address;
+    }
+
+    /// Restarts the program as after power-on: the registers, the screen
+    /// and the keys are cleared and the memory is replaced, typically by a
+    /// new `BlockMemory` with the ROM and the font loaded again.
+    pub fn reset(&mut self, memory: M) -> Result<(), Error> {
+        self.memory = memory;
+        self.soft_reset()
+    }
+
+    /// Restarts the program like `reset`, but keeps the memory as it is,
+    /// including anything the program wrote to it.
+    pub fn soft_reset(&mut self) -> Result<(), Error> {
+        let depth = self.registers.stack.len();
+        self.registers = Registers::new();
+        self.registers.stack = vec![0; depth];
+        self.registers.pc = self.entry_point;
+        self.display.clear();
+        self.keyboard.release_all();
+        self.sound_active.store(false, Ordering::Relaxed);
+        self.memory.take_written_regions();
+        self.sync_regions(true)
+    }
+
+    /// Makes the results of the random number instruction reproducible.
+    pub fn seed_rng(&mut self, seed: u32) {
+        // The xorshift generator must not be seeded with all zeros
+        self.rng = XorShiftRng::from_seed([0x193a_6754, 0xa8a7_d469, 0x9783_0e05, seed]);
+    }
+
+    /// Executes one instruction. If an error occurs, the registers are left
+    /// as they were before the instruction.
+    pub fn cycle(&mut self) -> Result<(), Error> {
+        let opcode = match self.fetch_opcode() {
+            Ok(opcode) => opcode,
+            Err(e) => {
+                let pc = self.registers.pc;
+                self.log_trace(|tracer| tracer.log_error(pc, None, &e));
+                return Err(e);
+            }
+        };
+        if self.trace_output.is_some() {
+            self.write_trace(opcode);
+        }
+        if self.tracer.is_none() {
+            return self.execute_opcode(opcode);
+        }
+
+        let before = self.trace_record(opcode);
+        let result = self.execute_opcode(opcode);
+        let after = self.trace_record(opcode);
+        self.log_trace(|tracer| match result {
+            Ok(()) => tracer.log(&before, &after),
+            Err(ref e) => tracer.log_error(before.pc, Some(before.opcode), e),
+        });
+        result
     }
 
     pub fn decrement_timers(&mut self) {
@@ -43,7 +158,7 @@ impl<'a> Cpu<'a> {
         if self.registers.sound_timer > 0 {
             self.registers.sound_timer -= 1;
             if self.registers.sound_timer == 0 {
-                self.audio_device.pause();
+                self.sound_active.store(false, Ordering::Relaxed);
             }
         }
     }
@@ -52,63 +167,179 @@ impl<'a> Cpu<'a> {
         self.display.redraw();
     }
 
-    fn fetch_opcode(&self) -> Opcode {
+    pub fn display(&self) -> &D {
+        &self.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut D {
+        &mut self.display
+    }
+
+    pub fn keyboard(&mut self) -> &mut K {
+        &mut self.keyboard
+    }
+
+    pub fn sound_active(&self) -> bool {
+        self.registers.sound_timer > 0
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.registers.pc
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    fn trace_record(&self, opcode: Opcode) -> TraceRecord {
+        let r = &self.registers;
+        TraceRecord {
+            pc: r.pc,
+            opcode: opcode.code,
+            v: r.v,
+            i: r.i,
+            sp: r.sp,
+            delay_timer: r.delay_timer,
+            sound_timer: r.sound_timer,
+        }
+    }
+
+    fn log_trace<F: FnOnce(&mut Tracer) -> io::Result<()>>(&mut self, log: F) {
+        let logged = match self.tracer {
+            Some(ref mut tracer) => log(tracer),
+            None => return,
+        };
+        if let Err(e) = logged {
+            println!("Error writing trace log, tracing stopped: {}", e);
+            self.tracer = None;
+        }
+    }
+
+    fn write_trace(&mut self, opcode: Opcode) {
+        let record = self.trace_record(opcode);
+        let written = match self.trace_output {
+            Some(ref mut output) => writeln!(output, "{}", record),
+            None => return,
+        };
+        if let Err(e) = written {
+            println!("Error writing trace, tracing stopped: {}", e);
+            self.trace_output = None;
+        }
+    }
+
+    fn fetch_opcode(&self) -> Result<Opcode, Error> {
         let pc = self.registers.pc;
-        let byte1 = self.memory.read_byte(pc);
-        let byte2 = self.memory.read_byte(pc + 1);
-        Opcode::new((byte1 as u16) << 8 | (byte2 as u16))
+        let byte1 = self.memory.read_byte(pc)?;
+        let byte2 = self.memory.read_byte(pc + 1)?;
+        Ok(Opcode::new((byte1 as u16) << 8 | (byte2 as u16)))
     }
 
-    fn execute_opcode(&mut self, opcode: Opcode) {
+    fn execute_opcode(&mut self, opcode: Opcode) -> Result<(), Error> {
+        // Memory may have been changed from outside, e.g. by a debugger
+        self.sync_regions(false)?;
+        self.dispatch_opcode(opcode)?;
+        let display_changed = opcode.code == 0x00E0 || opcode.code & 0xF000 == 0xD000;
+        self.sync_regions(display_changed)
+    }
+
+    // Keeps the registers and the screen in sync with their regions in the
+    // memory map. What the program wrote to memory takes precedence.
+    fn sync_regions(&mut self, display_changed: bool) -> Result<(), Error> {
+        if self.memory.regions().is_empty() {
+            return Ok(());
+        }
+        let written = self.memory.take_written_regions();
+        for region in self.memory.regions().to_vec() {
+            let from_memory = written.contains(&region.kind);
+            match region.kind {
+                RegionKind::Variables if from_memory => {
+                    let bytes = self.memory.read_mirror(region.start, 16)?;
+                    self.registers.v.copy_from_slice(bytes);
+                }
+                RegionKind::Variables => self.memory.mirror(region.start, &self.registers.v)?,
+                RegionKind::DisplayRam if from_memory => {
+                    let bytes = self.memory.read_mirror(region.start, region.size as usize)?;
+                    self.display.load_bytes(bytes);
+                }
+                RegionKind::DisplayRam if display_changed => {
+                    self.memory.mirror(region.start, &self.display.to_bytes())?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn dispatch_opcode(&mut self, opcode: Opcode) -> Result<(), Error> {
         match opcode.code {
             0x00e0 => self.create_and_execute::<Cls>(opcode),
             0x00ee => self.create_and_execute::<Ret>(opcode),
-            0x1000...0x1FFF => self.create_and_execute::<Jp>(opcode),
-            0x2000...0x2FFF => self.create_and_execute::<Call>(opcode),
-            0x3000...0x3FFF => self.create_and_execute::<SeXkk>(opcode),
-            0x4000...0x4FFF => self.create_and_execute::<SneXkk>(opcode),
-            0x5000...0x5FFF if opcode.code & 0xF == 0x0 => self.create_and_execute::<SeXy>(opcode),
-            0x6000...0x6FFF => self.create_and_execute::<LdXkk>(opcode),
-            0x7000...0x7FFF => self.create_and_execute::<AddXkk>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x0 => self.create_and_execute::<LdXy>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x1 => self.create_and_execute::<Or>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x2 => self.create_and_execute::<And>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x3 => self.create_and_execute::<Xor>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x4 => self.create_and_execute::<AddXy>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x5 => self.create_and_execute::<Sub>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xFF == 0x6 => self.create_and_execute::<Shr>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xF == 0x7 => self.create_and_execute::<Subn>(opcode),
-            0x8000...0x8FFF if opcode.code & 0xFF == 0xE => self.create_and_execute::<Shl>(opcode),
-            0x9000...0x9FFF if opcode.code & 0xF == 0x0 => self.create_and_execute::<SneXy>(opcode),
-            0xA000...0xAFFF => self.create_and_execute::<LdINnn>(opcode),
-            0xB000...0xBFFF => self.create_and_execute::<Jp2>(opcode),
-            0xC000...0xCFFF => self.create_and_execute::<Rnd>(opcode),
-            0xD000...0xDFFF if opcode.code & 0xF != 0x0 => self.create_and_execute::<Drw>(opcode),
-            0xE09E...0xEF9E if opcode.code & 0xFF == 0x9E => self.create_and_execute::<Skp>(opcode),
-            0xE0A1...0xEFA1 if opcode.code & 0xFF == 0xA1 => self.create_and_execute::<Sknp>(opcode),
-            0xF007...0xFF07 if opcode.code & 0xFF == 0x07 => self.create_and_execute::<LdXDt>(opcode),
-            0xF00A...0xFF0A if opcode.code & 0xFF == 0x0A => self.create_and_execute::<LdKey>(opcode),
-            0xF015...0xFF15 if opcode.code & 0xFF == 0x15 => self.create_and_execute::<LdDtX>(opcode),
-            0xF018...0xFF18 if opcode.code & 0xFF == 0x18 => self.create_and_execute::<LdStX>(opcode),
-            0xF01E...0xFF1E if opcode.code & 0xFF == 0x1E => self.create_and_execute::<AddIX>(opcode),
-            0xF029...0xFF29 if opcode.code & 0xFF == 0x29 => self.create_and_execute::<LdXSprite>(opcode),
-            0xF033...0xFF33 if opcode.code & 0xFF == 0x33 => self.create_and_execute::<LdBcd>(opcode),
-            0xF055...0xFF55 if opcode.code & 0xFF == 0x55 => self.create_and_execute::<LdIX>(opcode),
-            0xF065...0xFF65 if opcode.code & 0xFF == 0x65 => self.create_and_execute::<LdXI>(opcode),
-            x => panic!("Opcode unknown: {:X}", x),
-        }
-    }
-
-    fn create_and_execute<Op: OpConstruct + OpExecute>(&mut self, opcode: Opcode) {
+            0x1000..=0x1FFF => self.create_and_execute::<Jp>(opcode),
+            0x2000..=0x2FFF => self.create_and_execute::<Call>(opcode),
+            0x3000..=0x3FFF => self.create_and_execute::<SeXkk>(opcode),
+            0x4000..=0x4FFF => self.create_and_execute::<SneXkk>(opcode),
+            0x5000..=0x5FFF if opcode.code & 0xF == 0x0 => self.create_and_execute::<SeXy>(opcode),
+            0x6000..=0x6FFF => self.create_and_execute::<LdXkk>(opcode),
+            0x7000..=0x7FFF => self.create_and_execute::<AddXkk>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x0 => self.create_and_execute::<LdXy>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x1 => self.create_and_execute::<Or>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x2 => self.create_and_execute::<And>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x3 => self.create_and_execute::<Xor>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x4 => self.create_and_execute::<AddXy>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x5 => self.create_and_execute::<Sub>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x6 => self.create_and_execute::<Shr>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0x7 => self.create_and_execute::<Subn>(opcode),
+            0x8000..=0x8FFF if opcode.code & 0xF == 0xE => self.create_and_execute::<Shl>(opcode),
+            0x9000..=0x9FFF if opcode.code & 0xF == 0x0 => self.create_and_execute::<SneXy>(opcode),
+            0xA000..=0xAFFF => self.create_and_execute::<LdINnn>(opcode),
+            0xB000..=0xBFFF => self.create_and_execute::<Jp2>(opcode),
+            0xC000..=0xCFFF => self.create_and_execute::<Rnd>(opcode),
+            0xD000..=0xDFFF if opcode.code & 0xF != 0x0 => self.create_and_execute::<Drw>(opcode),
+            0xE09E..=0xEF9E if opcode.code & 0xFF == 0x9E => self.create_and_execute::<Skp>(opcode),
+            0xE0A1..=0xEFA1 if opcode.code & 0xFF == 0xA1 => self.create_and_execute::<Sknp>(opcode),
+            0xF007..=0xFF07 if opcode.code & 0xFF == 0x07 => self.create_and_execute::<LdXDt>(opcode),
+            0xF00A..=0xFF0A if opcode.code & 0xFF == 0x0A => self.create_and_execute::<LdKey>(opcode),
+            0xF015..=0xFF15 if opcode.code & 0xFF == 0x15 => self.create_and_execute::<LdDtX>(opcode),
+            0xF018..=0xFF18 if opcode.code & 0xFF == 0x18 => self.create_and_execute::<LdStX>(opcode),
+            0xF01E..=0xFF1E if opcode.code & 0xFF == 0x1E => self.create_and_execute::<AddIX>(opcode),
+            0xF029..=0xFF29 if opcode.code & 0xFF == 0x29 => self.create_and_execute::<LdXSprite>(opcode),
+            0xF030..=0xFF30 if opcode.code & 0xFF == 0x30 => self.create_and_execute::<LdXBigSprite>(opcode),
+            0xF033..=0xFF33 if opcode.code & 0xFF == 0x33 => self.create_and_execute::<LdBcd>(opcode),
+            0xF055..=0xFF55 if opcode.code & 0xFF == 0x55 => self.create_and_execute::<LdIX>(opcode),
+            0xF065..=0xFF65 if opcode.code & 0xFF == 0x65 => self.create_and_execute::<LdXI>(opcode),
+            x => Err(Error::UnknownOpcode(x)),
+        }
+    }
+
+    fn create_and_execute<Op: OpConstruct + OpExecute>(
+        &mut self,
+        opcode: Opcode,
+    ) -> Result<(), Error> {
         let op = Op::new(opcode);
         let components = Components {
             registers: &mut self.registers,
             memory: &mut self.memory,
             display: &mut self.display,
             keyboard: &mut self.keyboard,
-            audio_device: &mut self.audio_device,
+            sound_active: &self.sound_active,
+            rng: &mut self.rng,
+            quirks: self.quirks,
+            font_address: self.font_address,
+            stack_address: self.stack_address,
         };
-        op.execute(components);
+        op.execute(components)
     }
 }
 
@@ -136,21 +367,24 @@ impl Opcode {
     }
 }
 
-struct Registers {
-    v: [u8; 16],
-    stack: [u16; 16],
-    i: u16,
-    pc: u16,
-    sp: u8,
-    delay_timer: u8,
-    sound_timer: u8,
+/// The registers of the CHIP-8 machine, exposed for debuggers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Registers {
+    pub v: [u8; 16],
+    /// Return addresses, as many as the stack can hold
+    pub stack: Vec<u16>,
+    pub i: u16,
+    pub pc: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
 }
 
 impl Registers {
     fn new() -> Registers {
         Registers {
             v: [0; 16],
-            stack: [0; 16],
+            stack: vec![0; 16],
             i: 0,
             pc: 0x200,
             sp: 0,
@@ -160,12 +394,16 @@ impl Registers {
     }
 }
 
-struct Components<'a, 'b: 'a> {
+struct Components<'a> {
     registers: &'a mut Registers,
-    memory: &'a mut BlockMemory,
-    display: &'a mut Display<'b>,
-    keyboard: &'a mut Keyboard<'b>,
-    audio_device: &'a mut AudioDevice,
+    memory: &'a mut dyn Memory,
+    display: &'a mut dyn Display,
+    keyboard: &'a mut dyn Keyboard,
+    sound_active: &'a AtomicBool,
+    rng: &'a mut XorShiftRng,
+    quirks: Quirks,
+    font_address: u16,
+    stack_address: Option<u16>,
 }
 
 trait OpConstruct {
@@ -173,7 +411,7 @@ trait OpConstruct {
 }
 
 trait OpExecute {
-    fn execute(&self, c: Components);
+    fn execute(&self, c: Components) -> Result<(), Error>;
 }
 
 macro_rules! create_opcode_struct {
@@ -275,371 +513,1188 @@ macro_rules! create_opcode_struct_xkk {
 // Clear screen
 create_opcode_struct!(Cls);
 impl OpExecute for Cls {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.display.clear();
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Return from a subroutine
 create_opcode_struct!(Ret);
 impl OpExecute for Ret {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        if c.registers.sp == 0 {
+            return Err(Error::StackUnderflow);
+        }
+        let sp = c.registers.sp as usize - 1;
+        let return_address = match c.stack_address {
+            Some(stack_address) => {
+                let entry = c.memory.read_block(stack_entry(stack_address, sp)?, 2)?;
+                (entry[0] as u16) << 8 | entry[1] as u16
+            }
+            None => c.registers.stack[sp],
+        };
+        // Programs can change return addresses kept in memory to anything
+        if return_address > 0xFFF {
+            return Err(Error::AddressOutOfBounds(return_address));
+        }
+        c.registers.stack[sp] = return_address;
         c.registers.sp -= 1;
-        c.registers.pc = c.registers.stack[c.registers.sp as usize];
-        c.registers.pc += 2;
+        c.registers.pc = return_address + 2;
+        Ok(())
     }
 }
 
+// Address of the stack entry at `sp` when the stack is kept in memory
+fn stack_entry(stack_address: u16, sp: usize) -> Result<u16, Error> {
+    (sp as u16)
+        .checked_mul(2)
+        .and_then(|offset| stack_address.checked_add(offset))
+        .ok_or(Error::AddressOutOfBounds(stack_address))
+}
+
 // Jump to location at nnn
 create_opcode_struct_nnn!(Jp);
 impl OpExecute for Jp {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.pc = self.nnn;
+        Ok(())
     }
 }
 
-// Jump to location nnn + V0
+// Jump to location nnn + V0, or nnn + Vx with the highest digit of nnn as x
 create_opcode_struct_nnn!(Jp2);
 impl OpExecute for Jp2 {
-    fn execute(&self, c: Components) {
-        c.registers.pc = c.registers.v[0] as u16 + self.nnn;
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        let x = if c.quirks.jump_vx { self.nnn as usize >> 8 } else { 0 };
+        let address = c.registers.v[x] as u16 + self.nnn;
+        if address > 0xFFF {
+            return Err(Error::AddressOutOfBounds(address));
+        }
+        c.registers.pc = address;
+        Ok(())
     }
 }
 
 // Call subroutine at nnn
 create_opcode_struct_nnn!(Call);
 impl OpExecute for Call {
-    fn execute(&self, c: Components) {
-        c.registers.stack[c.registers.sp as usize] = c.registers.pc;
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        if c.registers.sp as usize == c.registers.stack.len() {
+            return Err(Error::StackOverflow);
+        }
+        let sp = c.registers.sp as usize;
+        if let Some(stack_address) = c.stack_address {
+            let address = stack_entry(stack_address, sp)?;
+            c.memory.write_byte(address, (c.registers.pc >> 8) as u8)?;
+            c.memory.write_byte(address + 1, c.registers.pc as u8)?;
+        }
+        c.registers.stack[sp] = c.registers.pc;
         c.registers.sp += 1;
         c.registers.pc = self.nnn;
+        Ok(())
     }
 }
 
 // Skip next instruction if Vx == kk
 create_opcode_struct_xkk!(SeXkk);
 impl OpExecute for SeXkk {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         if c.registers.v[self.x] == self.kk {
             c.registers.pc += 2;
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Skip next instruction if Vx == Vy
 create_opcode_struct_xy!(SeXy);
 impl OpExecute for SeXy {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         if c.registers.v[self.x] == c.registers.v[self.y] {
             c.registers.pc += 2;
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Skip next instruction if Vx != kk
 create_opcode_struct_xkk!(SneXkk);
 impl OpExecute for SneXkk {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         if c.registers.v[self.x] != self.kk {
             c.registers.pc += 2;
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Skip next instruction if Vx != Vy
 create_opcode_struct_xy!(SneXy);
 impl OpExecute for SneXy {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         if c.registers.v[self.x] != c.registers.v[self.y] {
             c.registers.pc += 2;
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx == kk
 create_opcode_struct_xkk!(LdXkk);
 impl OpExecute for LdXkk {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.v[self.x] = self.kk;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vy
 create_opcode_struct_xy!(LdXy);
 impl OpExecute for LdXy {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.v[self.x] = c.registers.v[self.y];
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set I = nnn
 create_opcode_struct_nnn!(LdINnn);
 impl OpExecute for LdINnn {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.i = self.nnn;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = delay timer value
 create_opcode_struct_x!(LdXDt);
 impl OpExecute for LdXDt {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.v[self.x] = c.registers.delay_timer;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Wait for a key press, store the value of the key in Vx.
 create_opcode_struct_x!(LdKey);
 impl OpExecute for LdKey {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         if let Some(key) = c.keyboard.any_key_pressed() {
             c.registers.v[self.x] = key;
             c.registers.pc += 2;
         }
+        Ok(())
     }
 }
 
 // Set delay timer = Vx
 create_opcode_struct_x!(LdDtX);
 impl OpExecute for LdDtX {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.delay_timer = c.registers.v[self.x];
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set sound timer = Vx
 create_opcode_struct_x!(LdStX);
 impl OpExecute for LdStX {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         c.registers.sound_timer = c.registers.v[self.x];
         c.registers.pc += 2;
-        if c.registers.sound_timer > 0 {
-            c.audio_device.resume();
-        } else {
-            c.audio_device.pause();
-        }
+        c.sound_active.store(c.registers.sound_timer > 0, Ordering::Relaxed);
+        Ok(())
     }
 }
 
 // Set I = location of sprite for digit Vx
 create_opcode_struct_x!(LdXSprite);
 impl OpExecute for LdXSprite {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        let val = c.registers.v[self.x] & 0xF;
+        // The digit sprites are 5 bytes long each
+        c.registers.i = c.font_address + val as u16 * 0x5;
+        c.registers.pc += 2;
+        Ok(())
+    }
+}
+
+// Set I = location of the big sprite for digit Vx (SUPER-CHIP)
+create_opcode_struct_x!(LdXBigSprite);
+impl OpExecute for LdXBigSprite {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let val = c.registers.v[self.x] & 0xF;
-        // The digit sprites are stored from memory location 0x0 onwards and are
-        // 5 bytes long each
-        c.registers.i = val as u16 * 0x5;
+        // The big font follows the small one, with 10 bytes per digit. Like
+        // on SUPER-CHIP, digits the font has no big sprites for point to
+        // whatever follows the font.
+        c.registers.i = c.font_address + SMALL_FONT_SIZE as u16 + val as u16 * 10;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Store BCD representation of Vx in memory locations I, I+1, and I+2
 create_opcode_struct_x!(LdBcd);
 impl OpExecute for LdBcd {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let val = c.registers.v[self.x];
-        c.memory.write_byte(c.registers.i, val / 100);
-        c.memory.write_byte(c.registers.i + 1, val % 100 / 10);
-        c.memory.write_byte(c.registers.i + 2, val % 10);
+        let i = c.registers.i;
+        c.memory.write_byte(i, val / 100)?;
+        c.memory.write_byte(i.wrapping_add(1), val % 100 / 10)?;
+        c.memory.write_byte(i.wrapping_add(2), val % 10)?;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Store registers V0 through Vx in memory starting at location I
 create_opcode_struct_x!(LdIX);
 impl OpExecute for LdIX {
-    fn execute(&self, c: Components) {
-        for (j, val) in (&c.registers.v[..(self.x + 1)]).iter().enumerate() {
-            c.memory.write_byte(c.registers.i + j as u16, *val);
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        for (j, val) in c.registers.v[..(self.x + 1)].iter().enumerate() {
+            c.memory.write_byte(c.registers.i.wrapping_add(j as u16), *val)?;
+        }
+        if c.quirks.increment_i {
+            c.registers.i = c.registers.i.wrapping_add(self.x as u16 + 1);
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Read registers V0 through Vx from memory starting at location I
 create_opcode_struct_x!(LdXI);
 impl OpExecute for LdXI {
-    fn execute(&self, c: Components) {
-        for (j, reg) in (&mut c.registers.v[..(self.x + 1)]).iter_mut().enumerate() {
-            *reg = c.memory.read_byte(c.registers.i + j as u16);
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        let values = c.memory.read_block(c.registers.i, self.x + 1)?;
+        c.registers.v[..(self.x + 1)].copy_from_slice(values);
+        if c.quirks.increment_i {
+            c.registers.i = c.registers.i.wrapping_add(self.x as u16 + 1);
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vx + kk
 create_opcode_struct_xkk!(AddXkk);
 impl OpExecute for AddXkk {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vx = &mut c.registers.v[self.x];
         let val1 = Wrapping(*vx);
         let val2 = Wrapping(self.kk);
         *vx = (val1 + val2).0;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vx + Vy, set VF = carry
 create_opcode_struct_xy!(AddXy);
 impl OpExecute for AddXy {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let val1 = Wrapping(c.registers.v[self.x]);
         let val2 = Wrapping(c.registers.v[self.y]);
         let sum = val1 + val2;
         let carry = sum < val1;
-        c.registers.v[0xF] = carry as u8;
         c.registers.v[self.x] = sum.0;
+        c.registers.v[0xF] = carry as u8;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set I = I + Vx
 create_opcode_struct_x!(AddIX);
 impl OpExecute for AddIX {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vx = Wrapping(c.registers.v[self.x] as u16);
         let i = Wrapping(c.registers.i);
         c.registers.i = (i + vx).0;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vx - Vy, set VF = NOT borrow
 create_opcode_struct_xy!(Sub);
 impl OpExecute for Sub {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vx = Wrapping(c.registers.v[self.x]);
         let vy = Wrapping(c.registers.v[self.y]);
         let difference = vx - vy;
         let borrow = vx < vy;
-        c.registers.v[0xF] = !borrow as u8;
         c.registers.v[self.x] = difference.0;
+        c.registers.v[0xF] = !borrow as u8;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vy - Vx, set VF = NOT borrow
 create_opcode_struct_xy!(Subn);
 impl OpExecute for Subn {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vx = Wrapping(c.registers.v[self.x]);
         let vy = Wrapping(c.registers.v[self.y]);
         let difference = vy - vx;
         let borrow = vy < vx;
-        c.registers.v[0xF] = !borrow as u8;
         c.registers.v[self.x] = difference.0;
+        c.registers.v[0xF] = !borrow as u8;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vx OR Vy
 create_opcode_struct_xy!(Or);
 impl OpExecute for Or {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vy = c.registers.v[self.y];
         let vx = &mut c.registers.v[self.x];
         *vx |= vy;
+        if c.quirks.reset_vf {
+            c.registers.v[0xF] = 0;
+        }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vx AND Vy
 create_opcode_struct_xy!(And);
 impl OpExecute for And {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vy = c.registers.v[self.y];
         let vx = &mut c.registers.v[self.x];
         *vx &= vy;
+        if c.quirks.reset_vf {
+            c.registers.v[0xF] = 0;
+        }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = Vx XOR Vy
 create_opcode_struct_xy!(Xor);
 impl OpExecute for Xor {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let vy = c.registers.v[self.y];
         let vx = &mut c.registers.v[self.x];
         *vx ^= vy;
+        if c.quirks.reset_vf {
+            c.registers.v[0xF] = 0;
+        }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
-// Set Vx = Vx SHR 1
-create_opcode_struct_x!(Shr);
+// Set Vx = Vx SHR 1, or Vx = Vy SHR 1
+create_opcode_struct_xy!(Shr);
 impl OpExecute for Shr {
-    fn execute(&self, c: Components) {
-        let val = c.registers.v[self.x];
-        c.registers.v[0xF] = val % 1;
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        let val = c.registers.v[if c.quirks.shift_vx { self.x } else { self.y }];
         c.registers.v[self.x] = val >> 1;
+        c.registers.v[0xF] = val & 0x1;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
-// Set Vx = Vx SHL 1
-create_opcode_struct_x!(Shl);
+// Set Vx = Vx SHL 1, or Vx = Vy SHL 1
+create_opcode_struct_xy!(Shl);
 impl OpExecute for Shl {
-    fn execute(&self, c: Components) {
-        let val = c.registers.v[self.x];
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        let val = c.registers.v[if c.quirks.shift_vx { self.x } else { self.y }];
         let msb = (val & 0b1000_0000) > 0;
-        c.registers.v[0xF] = msb as u8;
         c.registers.v[self.x] = val << 1;
+        c.registers.v[0xF] = msb as u8;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 // Set Vx = random byte AND kk
 create_opcode_struct_xkk!(Rnd);
 impl OpExecute for Rnd {
-    fn execute(&self, c: Components) {
-        let mut rng = rand::thread_rng();
-        let rand_byte = rng.gen::<u8>();
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        let rand_byte = c.rng.gen::<u8>();
         let result = rand_byte & self.kk;
         c.registers.v[self.x] = result;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 create_opcode_struct_xyn!(Drw);
 impl OpExecute for Drw {
-    fn execute(&self, c: Components) {
+    fn execute(&self, c: Components) -> Result<(), Error> {
         let x = c.registers.v[self.x];
         let y = c.registers.v[self.y];
-        let sprite = c.memory.read_block(c.registers.i, self.n);
+        let sprite = c.memory.read_block(c.registers.i, self.n)?;
         let erased_pixel = c.display.draw_sprite(x, y, sprite);
         c.registers.v[0xF] = erased_pixel as u8;
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 create_opcode_struct_x!(Skp);
 impl OpExecute for Skp {
-    fn execute(&self, c: Components) {
-        let key = c.registers.v[self.x];
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        // Only the low nibble selects the key, as on the COSMAC VIP
+        let key = c.registers.v[self.x] & 0xF;
         if c.keyboard.is_pressed(key) {
             c.registers.pc += 2;
         }
         c.registers.pc += 2;
+        Ok(())
     }
 }
 
 create_opcode_struct_x!(Sknp);
 impl OpExecute for Sknp {
-    fn execute(&self, c: Components) {
-        let key = c.registers.v[self.x];
+    fn execute(&self, c: Components) -> Result<(), Error> {
+        // Only the low nibble selects the key, as on the COSMAC VIP
+        let key = c.registers.v[self.x] & 0xF;
         if !c.keyboard.is_pressed(key) {
             c.registers.pc += 2;
         }
         c.registers.pc += 2;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use display::Framebuffer;
+    use keyboard::Keypad;
+    use memory::{BlockMemory, MemoryMap};
+    use proptest::prelude::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use trace::{OpcodeClass, TracerConfig};
+
+    struct MockMemory {
+        bytes: Vec<u8>,
+    }
+
+    impl Memory for MockMemory {
+        fn read_byte(&self, address: u16) -> Result<u8, Error> {
+            Ok(self.bytes[address as usize])
+        }
+
+        fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+            self.bytes[address as usize] = value;
+            Ok(())
+        }
+
+        fn read_block(&self, address: u16, size: usize) -> Result<&[u8], Error> {
+            let address = address as usize;
+            Ok(&self.bytes[address..(address + size)])
+        }
+    }
+
+    #[derive(Default)]
+    struct MockDisplay {
+        cleared: bool,
+        sprites: Vec<(u8, u8, Vec<u8>)>,
+        collision: bool,
+        // Display RAM in the format of `to_bytes`
+        ram: Vec<u8>,
+    }
+
+    impl Display for MockDisplay {
+        fn clear(&mut self) {
+            self.cleared = true;
+        }
+
+        fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
+            self.sprites.push((x, y, sprite.to_vec()));
+            self.collision
+        }
+
+        fn redraw(&mut self) {}
+
+        fn to_bytes(&self) -> Vec<u8> {
+            self.ram.clone()
+        }
+
+        fn load_bytes(&mut self, bytes: &[u8]) {
+            self.ram = bytes.to_vec();
+        }
+    }
+
+    #[derive(Default)]
+    struct MockKeyboard {
+        pressed: [bool; 16],
+        key_press: Option<u8>,
+    }
+
+    impl Keyboard for MockKeyboard {
+        fn is_pressed(&mut self, key: u8) -> bool {
+            self.pressed[key as usize]
+        }
+
+        fn any_key_pressed(&mut self) -> Option<u8> {
+            self.key_press.take()
+        }
+
+        fn release_all(&mut self) {
+            self.pressed = [false; 16];
+            self.key_press = None;
+        }
+    }
+
+    type TestCpu = Cpu<MockMemory, MockDisplay, MockKeyboard>;
+
+    fn cpu() -> TestCpu {
+        let memory = MockMemory { bytes: vec![0; 4096] };
+        let sound_active = Arc::new(AtomicBool::new(false));
+        Cpu::new(memory, MockDisplay::default(), MockKeyboard::default(), sound_active)
+    }
+
+    fn execute(cpu: &mut TestCpu, code: u16) {
+        cpu.execute_opcode(Opcode::new(code)).unwrap();
+    }
+
+    #[test]
+    fn cycle_fetches_opcode_at_pc() {
+        let mut cpu = cpu();
+        cpu.memory.bytes[0x200] = 0x6A;
+        cpu.memory.bytes[0x201] = 0x42;
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.registers.v[0xA], 0x42);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn shifts_decode_for_any_y() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0b0000_0011;
+        execute(&mut cpu, 0x8126);
+        assert_eq!(cpu.registers.v[1], 0b0000_0001);
+        execute(&mut cpu, 0x81FE);
+        assert_eq!(cpu.registers.v[1], 0b0000_0010);
+        assert_eq!(cpu.registers.pc, 0x204);
+    }
+
+    #[test]
+    fn reset_restarts_the_program() {
+        let mut cpu = cpu();
+        cpu.set_entry_point(0x600);
+        cpu.memory.bytes[0x600] = 0x6A;
+        cpu.memory.bytes[0x601] = 0x42;
+        cpu.cycle().unwrap();
+        cpu.registers.sound_timer = 10;
+        cpu.keyboard.pressed[5] = true;
+        cpu.soft_reset().unwrap();
+        assert_eq!(cpu.registers.v[0xA], 0);
+        assert_eq!(cpu.registers.sound_timer, 0);
+        assert_eq!(cpu.registers.pc, 0x600);
+        assert!(cpu.display.cleared);
+        assert!(!cpu.keyboard.pressed[5]);
+        assert_eq!(cpu.memory.bytes[0x600], 0x6A);
+
+        cpu.reset(MockMemory { bytes: vec![0; 4096] }).unwrap();
+        assert_eq!(cpu.memory.bytes[0x600], 0);
+        assert_eq!(cpu.registers.pc, 0x600);
+    }
+
+    #[test]
+    fn unknown_opcode_is_an_error() {
+        let mut cpu = cpu();
+        let result = cpu.execute_opcode(Opcode::new(0x8128));
+        assert_eq!(result, Err(Error::UnknownOpcode(0x8128)));
+        assert_eq!(cpu.registers.pc, 0x200);
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn tracer_dumps_last_instructions_on_error() {
+        let mut cpu = cpu();
+        let rom = [0x60, 0x01, 0x61, 0x02, 0xA3, 0x00, 0x00, 0x00];
+        cpu.memory.bytes[0x200..0x208].copy_from_slice(&rom);
+        let buffer = SharedBuffer::default();
+        let config = TracerConfig {
+            last: Some(2),
+            ..TracerConfig::default()
+        };
+        cpu.set_tracer(Tracer::new(Box::new(buffer.clone()), &config));
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+        assert!(buffer.0.borrow().is_empty());
+        assert_eq!(cpu.cycle(), Err(Error::UnknownOpcode(0x0000)));
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "Last 2 instructions:");
+        assert_eq!(lines[1], "0202  6102  LD V1, 0x02           V1=02");
+        assert_eq!(lines[2], "0204  A300  LD I, 0x300           I=0300");
+        assert!(lines[3].starts_with("0206  0000"));
+    }
+
+    #[test]
+    fn tracer_filters_by_pc_and_class() {
+        let mut cpu = cpu();
+        let rom = [0x60, 0x01, 0x12, 0x04, 0x12, 0x00];
+        cpu.memory.bytes[0x200..0x206].copy_from_slice(&rom);
+        let buffer = SharedBuffer::default();
+        let config = TracerConfig {
+            pc_range: Some((0x200, 0x203)),
+            classes: vec![OpcodeClass::Flow],
+            ..TracerConfig::default()
+        };
+        cpu.set_tracer(Tracer::new(Box::new(buffer.clone()), &config));
+        for _ in 0..6 {
+            cpu.cycle().unwrap();
+        }
+        let log = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        assert!(log.lines().all(|line| line.starts_with("0202  1204  JP 0x204")));
+    }
+
+    #[test]
+    fn cls() {
+        let mut cpu = cpu();
+        execute(&mut cpu, 0x00E0);
+        assert!(cpu.display.cleared);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn call_and_ret() {
+        let mut cpu = cpu();
+        execute(&mut cpu, 0x2345);
+        assert_eq!(cpu.registers.pc, 0x345);
+        assert_eq!(cpu.registers.sp, 1);
+        assert_eq!(cpu.registers.stack[0], 0x200);
+        execute(&mut cpu, 0x2456);
+        assert_eq!(cpu.registers.sp, 2);
+        assert_eq!(cpu.registers.stack[1], 0x345);
+        execute(&mut cpu, 0x00EE);
+        assert_eq!(cpu.registers.pc, 0x347);
+        assert_eq!(cpu.registers.sp, 1);
+        execute(&mut cpu, 0x00EE);
+        assert_eq!(cpu.registers.pc, 0x202);
+        assert_eq!(cpu.registers.sp, 0);
+    }
+
+    #[test]
+    fn stack_overflow_and_underflow_are_errors() {
+        let mut cpu = cpu();
+        let result = cpu.execute_opcode(Opcode::new(0x00EE));
+        assert_eq!(result, Err(Error::StackUnderflow));
+        for _ in 0..16 {
+            execute(&mut cpu, 0x2200);
+        }
+        let result = cpu.execute_opcode(Opcode::new(0x2200));
+        assert_eq!(result, Err(Error::StackOverflow));
+        assert_eq!(cpu.registers.sp, 16);
+
+        cpu.set_stack_depth(12);
+        for _ in 0..12 {
+            execute(&mut cpu, 0x2200);
+        }
+        let result = cpu.execute_opcode(Opcode::new(0x2200));
+        assert_eq!(result, Err(Error::StackOverflow));
+    }
+
+    #[test]
+    fn stack_in_memory() {
+        let mut cpu = cpu();
+        cpu.set_stack_address(Some(0xEA0));
+        execute(&mut cpu, 0x2345);
+        execute(&mut cpu, 0x2456);
+        assert_eq!(&cpu.memory.bytes[0xEA0..0xEA4], &[0x02, 0x00, 0x03, 0x45]);
+        // Programs can change the return address
+        cpu.memory.bytes[0xEA3] = 0x80;
+        execute(&mut cpu, 0x00EE);
+        assert_eq!(cpu.registers.pc, 0x382);
+        assert_eq!(cpu.registers.stack[1], 0x380);
+
+        cpu.memory.bytes[0xEA0] = 0x10;
+        let result = cpu.execute_opcode(Opcode::new(0x00EE));
+        assert_eq!(result, Err(Error::AddressOutOfBounds(0x1000)));
+        assert_eq!(cpu.registers.pc, 0x382);
+        assert_eq!(cpu.registers.sp, 1);
+
+        cpu.set_stack_address(Some(0xFFFE));
+        let result = cpu.execute_opcode(Opcode::new(0x2456));
+        assert_eq!(result, Err(Error::AddressOutOfBounds(0xFFFE)));
+    }
+
+    #[test]
+    fn jp() {
+        let mut cpu = cpu();
+        execute(&mut cpu, 0x1ABC);
+        assert_eq!(cpu.registers.pc, 0xABC);
+    }
+
+    #[test]
+    fn jp2() {
+        let mut cpu = cpu();
+        cpu.registers.v[0] = 0x10;
+        cpu.registers.v[1] = 0x20;
+        execute(&mut cpu, 0xB300);
+        assert_eq!(cpu.registers.pc, 0x310);
+    }
+
+    #[test]
+    fn se_xkk() {
+        let mut cpu = cpu();
+        cpu.registers.v[3] = 0x12;
+        execute(&mut cpu, 0x3312);
+        assert_eq!(cpu.registers.pc, 0x204);
+        execute(&mut cpu, 0x3313);
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn sne_xkk() {
+        let mut cpu = cpu();
+        cpu.registers.v[3] = 0x12;
+        execute(&mut cpu, 0x4312);
+        assert_eq!(cpu.registers.pc, 0x202);
+        execute(&mut cpu, 0x4313);
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn se_xy() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 7;
+        cpu.registers.v[2] = 7;
+        execute(&mut cpu, 0x5120);
+        assert_eq!(cpu.registers.pc, 0x204);
+        cpu.registers.v[2] = 8;
+        execute(&mut cpu, 0x5120);
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn sne_xy() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 7;
+        cpu.registers.v[2] = 7;
+        execute(&mut cpu, 0x9120);
+        assert_eq!(cpu.registers.pc, 0x202);
+        cpu.registers.v[2] = 8;
+        execute(&mut cpu, 0x9120);
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn ld_xkk() {
+        let mut cpu = cpu();
+        execute(&mut cpu, 0x6A42);
+        assert_eq!(cpu.registers.v[0xA], 0x42);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_xy() {
+        let mut cpu = cpu();
+        cpu.registers.v[2] = 0x42;
+        execute(&mut cpu, 0x8120);
+        assert_eq!(cpu.registers.v[1], 0x42);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_i_nnn() {
+        let mut cpu = cpu();
+        execute(&mut cpu, 0xA123);
+        assert_eq!(cpu.registers.i, 0x123);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_x_dt() {
+        let mut cpu = cpu();
+        cpu.registers.delay_timer = 0x33;
+        execute(&mut cpu, 0xF507);
+        assert_eq!(cpu.registers.v[5], 0x33);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_key_waits_for_key_press() {
+        let mut cpu = cpu();
+        execute(&mut cpu, 0xF50A);
+        assert_eq!(cpu.registers.pc, 0x200);
+        cpu.keyboard.key_press = Some(0xB);
+        execute(&mut cpu, 0xF50A);
+        assert_eq!(cpu.registers.v[5], 0xB);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_dt_x() {
+        let mut cpu = cpu();
+        cpu.registers.v[5] = 0x33;
+        execute(&mut cpu, 0xF515);
+        assert_eq!(cpu.registers.delay_timer, 0x33);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_st_x_controls_sound() {
+        let mut cpu = cpu();
+        cpu.registers.v[5] = 2;
+        execute(&mut cpu, 0xF518);
+        assert_eq!(cpu.registers.sound_timer, 2);
+        assert!(cpu.sound_active.load(Ordering::Relaxed));
+        cpu.decrement_timers();
+        assert!(cpu.sound_active.load(Ordering::Relaxed));
+        cpu.decrement_timers();
+        assert!(!cpu.sound_active.load(Ordering::Relaxed));
+        execute(&mut cpu, 0xF518);
+        cpu.registers.v[5] = 0;
+        execute(&mut cpu, 0xF518);
+        assert!(!cpu.sound_active.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn decrement_timers_stops_at_zero() {
+        let mut cpu = cpu();
+        cpu.registers.delay_timer = 1;
+        cpu.decrement_timers();
+        cpu.decrement_timers();
+        assert_eq!(cpu.registers.delay_timer, 0);
+        assert_eq!(cpu.registers.sound_timer, 0);
+    }
+
+    #[test]
+    fn ld_x_sprite() {
+        let mut cpu = cpu();
+        cpu.registers.v[5] = 0xA;
+        execute(&mut cpu, 0xF529);
+        assert_eq!(cpu.registers.i, 50);
+        cpu.registers.v[5] = 0x1F;
+        execute(&mut cpu, 0xF529);
+        assert_eq!(cpu.registers.i, 75);
+    }
+
+    #[test]
+    fn ld_bcd() {
+        let mut cpu = cpu();
+        cpu.registers.v[5] = 254;
+        cpu.registers.i = 0x300;
+        execute(&mut cpu, 0xF533);
+        assert_eq!(&cpu.memory.bytes[0x300..0x303], &[2, 5, 4]);
+        cpu.registers.v[5] = 7;
+        execute(&mut cpu, 0xF533);
+        assert_eq!(&cpu.memory.bytes[0x300..0x303], &[0, 0, 7]);
+        assert_eq!(cpu.registers.i, 0x300);
+    }
+
+    #[test]
+    fn ld_i_x() {
+        let mut cpu = cpu();
+        cpu.registers.v[..4].copy_from_slice(&[1, 2, 3, 4]);
+        cpu.registers.i = 0x300;
+        execute(&mut cpu, 0xF255);
+        assert_eq!(&cpu.memory.bytes[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!(cpu.registers.i, 0x300);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_x_i() {
+        let mut cpu = cpu();
+        cpu.memory.bytes[0x300..0x304].copy_from_slice(&[1, 2, 3, 4]);
+        cpu.registers.i = 0x300;
+        execute(&mut cpu, 0xF265);
+        assert_eq!(&cpu.registers.v[..4], &[1, 2, 3, 0]);
+        assert_eq!(cpu.registers.i, 0x300);
+        assert_eq!(cpu.registers.pc, 0x202);
+    }
+
+    #[test]
+    fn add_xkk_wraps_without_carry() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0xFF;
+        execute(&mut cpu, 0x7102);
+        assert_eq!(cpu.registers.v[1], 0x01);
+        assert_eq!(cpu.registers.v[0xF], 0);
+    }
+
+    #[test]
+    fn add_xy_sets_carry() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0xFF;
+        cpu.registers.v[2] = 0x02;
+        execute(&mut cpu, 0x8124);
+        assert_eq!(cpu.registers.v[1], 0x01);
+        assert_eq!(cpu.registers.v[0xF], 1);
+        execute(&mut cpu, 0x8124);
+        assert_eq!(cpu.registers.v[1], 0x03);
+        assert_eq!(cpu.registers.v[0xF], 0);
+    }
+
+    #[test]
+    fn add_i_x() {
+        let mut cpu = cpu();
+        cpu.registers.i = 0x300;
+        cpu.registers.v[1] = 0x20;
+        execute(&mut cpu, 0xF11E);
+        assert_eq!(cpu.registers.i, 0x320);
+    }
+
+    #[test]
+    fn sub_sets_not_borrow() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 5;
+        cpu.registers.v[2] = 3;
+        execute(&mut cpu, 0x8125);
+        assert_eq!(cpu.registers.v[1], 2);
+        assert_eq!(cpu.registers.v[0xF], 1);
+        execute(&mut cpu, 0x8125);
+        assert_eq!(cpu.registers.v[1], 0xFF);
+        assert_eq!(cpu.registers.v[0xF], 0);
+        cpu.registers.v[1] = 3;
+        execute(&mut cpu, 0x8125);
+        assert_eq!(cpu.registers.v[1], 0);
+        assert_eq!(cpu.registers.v[0xF], 1);
+    }
+
+    #[test]
+    fn subn_sets_not_borrow() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 3;
+        cpu.registers.v[2] = 5;
+        execute(&mut cpu, 0x8127);
+        assert_eq!(cpu.registers.v[1], 2);
+        assert_eq!(cpu.registers.v[0xF], 1);
+        cpu.registers.v[1] = 6;
+        execute(&mut cpu, 0x8127);
+        assert_eq!(cpu.registers.v[1], 0xFF);
+        assert_eq!(cpu.registers.v[0xF], 0);
+    }
+
+    #[test]
+    fn flag_overwrites_result_in_vf() {
+        let mut cpu = cpu();
+        cpu.registers.v[0xF] = 3;
+        cpu.registers.v[2] = 5;
+        execute(&mut cpu, 0x8F25);
+        assert_eq!(cpu.registers.v[0xF], 0);
+        cpu.registers.v[0xF] = 0xFF;
+        execute(&mut cpu, 0x8F24);
+        assert_eq!(cpu.registers.v[0xF], 1);
+    }
+
+    #[test]
+    fn or_and_xor() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0b1100;
+        cpu.registers.v[2] = 0b1010;
+        execute(&mut cpu, 0x8121);
+        assert_eq!(cpu.registers.v[1], 0b1110);
+        execute(&mut cpu, 0x8122);
+        assert_eq!(cpu.registers.v[1], 0b1010);
+        execute(&mut cpu, 0x8123);
+        assert_eq!(cpu.registers.v[1], 0);
+        assert_eq!(cpu.registers.pc, 0x206);
+    }
+
+    #[test]
+    fn vip_quirks() {
+        let mut cpu = cpu();
+        cpu.set_quirks(Quirks::VIP);
+        cpu.registers.v[1] = 0b0011;
+        cpu.registers.v[2] = 0b0110;
+        execute(&mut cpu, 0x8126);
+        assert_eq!(cpu.registers.v[1], 0b0011);
+        assert_eq!(cpu.registers.v[0xF], 0);
+        cpu.registers.v[0xF] = 1;
+        execute(&mut cpu, 0x8121);
+        assert_eq!(cpu.registers.v[0xF], 0);
+        cpu.registers.i = 0x300;
+        execute(&mut cpu, 0xF255);
+        assert_eq!(cpu.registers.i, 0x303);
+        execute(&mut cpu, 0xF165);
+        assert_eq!(cpu.registers.i, 0x305);
+    }
+
+    #[test]
+    fn schip_jump_quirk() {
+        let mut cpu = cpu();
+        cpu.registers.v[0] = 1;
+        cpu.registers.v[3] = 2;
+        execute(&mut cpu, 0xB300);
+        assert_eq!(cpu.registers.pc, 0x301);
+        cpu.set_quirks(Quirks::SCHIP);
+        execute(&mut cpu, 0xB300);
+        assert_eq!(cpu.registers.pc, 0x302);
+    }
+
+    #[test]
+    fn digit_sprites_start_at_font_address() {
+        let mut cpu = cpu();
+        cpu.registers.v[2] = 0xA;
+        execute(&mut cpu, 0xF229);
+        assert_eq!(cpu.registers.i, 50);
+        cpu.set_font_address(0x100);
+        execute(&mut cpu, 0xF229);
+        assert_eq!(cpu.registers.i, 0x132);
+        execute(&mut cpu, 0xF230);
+        assert_eq!(cpu.registers.i, 0x100 + 80 + 100);
+    }
+
+    #[test]
+    fn shr_sets_vf_to_lsb() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0b0000_0101;
+        execute(&mut cpu, 0x8106);
+        assert_eq!(cpu.registers.v[1], 0b0000_0010);
+        assert_eq!(cpu.registers.v[0xF], 1);
+        execute(&mut cpu, 0x8106);
+        assert_eq!(cpu.registers.v[1], 0b0000_0001);
+        assert_eq!(cpu.registers.v[0xF], 0);
+    }
+
+    #[test]
+    fn shl_sets_vf_to_msb() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0b1100_0000;
+        execute(&mut cpu, 0x810E);
+        assert_eq!(cpu.registers.v[1], 0b1000_0000);
+        assert_eq!(cpu.registers.v[0xF], 1);
+        cpu.registers.v[1] = 0b0100_0000;
+        execute(&mut cpu, 0x810E);
+        assert_eq!(cpu.registers.v[1], 0b1000_0000);
+        assert_eq!(cpu.registers.v[0xF], 0);
+    }
+
+    #[test]
+    fn rnd_is_masked_and_reproducible() {
+        let mut first = cpu();
+        let mut second = cpu();
+        first.seed_rng(42);
+        second.seed_rng(42);
+        for _ in 0..16 {
+            execute(&mut first, 0xC10F);
+            execute(&mut second, 0xC10F);
+            assert_eq!(first.registers.v[1] & 0xF0, 0);
+            assert_eq!(first.registers.v[1], second.registers.v[1]);
+        }
+        execute(&mut first, 0xC100);
+        assert_eq!(first.registers.v[1], 0);
+    }
+
+    #[test]
+    fn drw_reads_sprite_at_i() {
+        let mut cpu = cpu();
+        cpu.memory.bytes[0x300..0x303].copy_from_slice(&[0xF0, 0x90, 0xF0]);
+        cpu.registers.i = 0x300;
+        cpu.registers.v[1] = 10;
+        cpu.registers.v[2] = 20;
+        execute(&mut cpu, 0xD123);
+        assert_eq!(cpu.display.sprites, vec![(10, 20, vec![0xF0, 0x90, 0xF0])]);
+        assert_eq!(cpu.registers.v[0xF], 0);
+        cpu.display.collision = true;
+        execute(&mut cpu, 0xD123);
+        assert_eq!(cpu.registers.v[0xF], 1);
+        assert_eq!(cpu.registers.pc, 0x204);
+    }
+
+    #[test]
+    fn skp_and_sknp() {
+        let mut cpu = cpu();
+        cpu.registers.v[1] = 0xC;
+        execute(&mut cpu, 0xE19E);
+        assert_eq!(cpu.registers.pc, 0x202);
+        execute(&mut cpu, 0xE1A1);
+        assert_eq!(cpu.registers.pc, 0x206);
+        cpu.keyboard.pressed[0xC] = true;
+        execute(&mut cpu, 0xE19E);
+        assert_eq!(cpu.registers.pc, 0x20A);
+        execute(&mut cpu, 0xE1A1);
+        assert_eq!(cpu.registers.pc, 0x20C);
+    }
+
+    #[test]
+    fn vip_memory_map_mirrors_registers_and_screen() {
+        let mut memory = BlockMemory::new();
+        memory.set_memory_map(MemoryMap::Vip).unwrap();
+        let sound_active = Arc::new(AtomicBool::new(false));
+        let mut cpu = Cpu::new(memory, Framebuffer::new(), Keypad::new(), sound_active);
+        // LD [I], V0 draws the top left pixels
+        cpu.registers.v[0] = 0xC0;
+        cpu.registers.i = 0xF00;
+        cpu.execute_opcode(Opcode::new(0xF055)).unwrap();
+        assert_eq!(&cpu.display.pixels()[..3], &[true, true, false]);
+        assert_eq!(cpu.memory.read_byte(0xEF0), Ok(0xC0));
+
+        // DRW shows up in display RAM, LD V0, [I] reads the variables
+        cpu.registers.i = 0x000;
+        cpu.execute_opcode(Opcode::new(0xD005)).unwrap();
+        assert_eq!(cpu.memory.read_byte(0xF00), Ok(0xC0 ^ 0xF0));
+        cpu.memory.write_byte(0xEF3, 0x42).unwrap();
+        cpu.registers.i = 0xEF3;
+        cpu.execute_opcode(Opcode::new(0xF065)).unwrap();
+        assert_eq!(cpu.registers.v[3], 0x42);
+        assert_eq!(cpu.registers.v[0], 0x42);
+    }
+
+    proptest! {
+        #[test]
+        fn random_programs_fail_with_errors_only(
+            rom in proptest::collection::vec(any::<u8>(), 0..0xE00),
+            keys in proptest::collection::vec((0..1000usize, 0..16u8, any::<bool>()), 0..32),
+        ) {
+            let mut memory = BlockMemory::new();
+            for (i, &byte) in rom.iter().enumerate() {
+                memory.write_byte(0x200 + i as u16, byte).unwrap();
+            }
+            let sound_active = Arc::new(AtomicBool::new(false));
+            let mut cpu = Cpu::new(memory, Framebuffer::new(), Keypad::new(), sound_active);
+            cpu.seed_rng(0);
+            for cycle in 0..1000 {
+                for &(_, key, down) in keys.iter().filter(|k| k.0 == cycle) {
+                    cpu.keyboard.set_key(key, down);
+                }
+                let pc = cpu.registers.pc;
+                let sp = cpu.registers.sp;
+                match cpu.cycle() {
+                    Ok(()) => prop_assert!(pc <= 0xFFE, "fetched outside of memory at {:X}", pc),
+                    Err(_) => {
+                        prop_assert_eq!(cpu.registers.pc, pc);
+                        prop_assert_eq!(cpu.registers.sp, sp);
+                        break;
+                    }
+                }
+                prop_assert!(cpu.registers.sp as usize <= cpu.registers.stack.len());
+            }
+        }
     }
 }
@@ -0,0 +1,681 @@
+use cpu::Registers;
+use error::Error;
+use headless::Machine;
+use memory::{Memory, Region, RegionKind};
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl FromStr for WatchKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<WatchKind, String> {
+        match s {
+            "r" | "read" => Ok(WatchKind::Read),
+            "w" | "write" => Ok(WatchKind::Write),
+            "rw" | "access" => Ok(WatchKind::Access),
+            _ => Err(format!("Unknown watchpoint kind: {} (expected r, w or rw)", s)),
+        }
+    }
+}
+
+/// Watches the inclusive address range from `start` to `end`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn matches(&self, address: u16, write: bool) -> bool {
+        let kind_matches = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+        kind_matches && address >= self.start && address <= self.end
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "r",
+            WatchKind::Write => "w",
+            WatchKind::Access => "rw",
+        };
+        if self.start == self.end {
+            write!(f, "{:03X} {}", self.start, kind)
+        } else {
+            write!(f, "{:03X}-{:03X} {}", self.start, self.end, kind)
+        }
+    }
+}
+
+/// An access to a watched address. For reads, `old` and `new` are equal.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WatchHit {
+    pub address: u16,
+    pub write: bool,
+    pub old: u8,
+    pub new: u8,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.write {
+            write!(f, "write {:03X}: {:02X} -> {:02X}", self.address, self.old, self.new)
+        } else {
+            write!(f, "read {:03X}: {:02X}", self.address, self.new)
+        }
+    }
+}
+
+/// Memory that records the accesses to watched addresses. Instruction
+/// fetches are reads as well, `Debugger` filters them out.
+pub struct WatchedMemory<M> {
+    memory: M,
+    watchpoints: Vec<Watchpoint>,
+    // Reads only borrow the memory immutably
+    hits: RefCell<Vec<WatchHit>>,
+}
+
+impl<M: Memory> WatchedMemory<M> {
+    pub fn new(memory: M) -> WatchedMemory<M> {
+        WatchedMemory {
+            memory,
+            watchpoints: Vec::new(),
+            hits: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.watchpoints.len() {
+            Some(self.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Returns the accesses to watched addresses since the last call.
+    pub fn take_hits(&self) -> Vec<WatchHit> {
+        self.hits.borrow_mut().drain(..).collect()
+    }
+
+    /// Gives access to the memory without triggering watchpoints.
+    pub fn inner(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    fn is_watched(&self, address: u16, write: bool) -> bool {
+        self.watchpoints.iter().any(|w| w.matches(address, write))
+    }
+}
+
+impl<M: Memory> Memory for WatchedMemory<M> {
+    fn read_byte(&self, address: u16) -> Result<u8, Error> {
+        let value = self.memory.read_byte(address)?;
+        if self.is_watched(address, false) {
+            self.hits.borrow_mut().push(WatchHit {
+                address,
+                write: false,
+                old: value,
+                new: value,
+            });
+        }
+        Ok(value)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        if !self.is_watched(address, true) {
+            return self.memory.write_byte(address, value);
+        }
+        let old = self.memory.read_byte(address)?;
+        self.memory.write_byte(address, value)?;
+        self.hits.borrow_mut().push(WatchHit {
+            address,
+            write: true,
+            old,
+            new: value,
+        });
+        Ok(())
+    }
+
+    fn read_block(&self, address: u16, size: usize) -> Result<&[u8], Error> {
+        let block = self.memory.read_block(address, size)?;
+        for (offset, &value) in block.iter().enumerate() {
+            let address = address.wrapping_add(offset as u16);
+            if self.is_watched(address, false) {
+                self.hits.borrow_mut().push(WatchHit {
+                    address,
+                    write: false,
+                    old: value,
+                    new: value,
+                });
+            }
+        }
+        Ok(block)
+    }
+
+    fn regions(&self) -> &[Region] {
+        self.memory.regions()
+    }
+
+    fn take_written_regions(&mut self) -> Vec<RegionKind> {
+        self.memory.take_written_regions()
+    }
+
+    // The machine state is not the program accessing memory
+    fn mirror(&mut self, address: u16, bytes: &[u8]) -> Result<(), Error> {
+        self.memory.mirror(address, bytes)
+    }
+
+    fn read_mirror(&self, address: u16, size: usize) -> Result<&[u8], Error> {
+        self.memory.read_mirror(address, size)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Register {
+    V(usize),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    fn value(self, registers: &Registers) -> u16 {
+        match self {
+            Register::V(x) => registers.v[x] as u16,
+            Register::I => registers.i,
+            Register::Pc => registers.pc,
+            Register::Sp => registers.sp as u16,
+            Register::Dt => registers.delay_timer as u16,
+            Register::St => registers.sound_timer as u16,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Operand {
+    Register(Register),
+    Value(u16),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expression {
+    Compare(Operand, &'static str, Operand),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+}
+
+impl Expression {
+    fn evaluate(&self, registers: &Registers) -> bool {
+        let value = |operand: &Operand| match *operand {
+            Operand::Register(register) => register.value(registers),
+            Operand::Value(value) => value,
+        };
+        match *self {
+            Expression::Compare(ref a, op, ref b) => {
+                let (a, b) = (value(a), value(b));
+                match op {
+                    "==" => a == b,
+                    "!=" => a != b,
+                    "<" => a < b,
+                    "<=" => a <= b,
+                    ">" => a > b,
+                    _ => a >= b,
+                }
+            }
+            Expression::And(ref a, ref b) => a.evaluate(registers) && b.evaluate(registers),
+            Expression::Or(ref a, ref b) => a.evaluate(registers) || b.evaluate(registers),
+        }
+    }
+}
+
+/// A condition on the registers like `V3 == 0x10 && I > 0x300`.
+///
+/// Comparisons of the registers `V0` to `VF`, `I`, `PC`, `SP`, `DT` and `ST`
+/// with each other or with numbers can be combined with `&&`, `||` and
+/// parentheses. Numbers are decimal or hex with a `0x` prefix.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    expression: Expression,
+    text: String,
+}
+
+impl Condition {
+    pub fn evaluate(&self, registers: &Registers) -> bool {
+        self.expression.evaluate(registers)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        let mut parser = ConditionParser {
+            tokens: tokenize(s)?,
+            position: 0,
+        };
+        let expression = parser.parse_or()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("Unexpected `{}` in condition", token));
+        }
+        Ok(Condition {
+            expression,
+            text: s.trim().to_string(),
+        })
+    }
+}
+
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+const OPERATORS: [&str; 10] = ["==", "!=", "<=", ">=", "<", ">", "&&", "||", "(", ")"];
+
+fn tokenize(s: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        let length = match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            Some(op) => op.len(),
+            None => rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len()),
+        };
+        if length == 0 {
+            return Err(format!("Unexpected `{}` in condition", &rest[..1]));
+        }
+        tokens.push(rest[..length].to_string());
+        rest = rest[length..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct ConditionParser {
+    tokens: Vec<String>,
+    position: usize,
+}
+
+impl ConditionParser {
+    fn next(&mut self) -> Result<String, String> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token.ok_or_else(|| "Unexpected end of condition".to_string())
+    }
+
+    fn accept(&mut self, expected: &str) -> bool {
+        let found = self.tokens.get(self.position).is_some_and(|t| t == expected);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_and()?;
+        while self.accept("||") {
+            expression = Expression::Or(Box::new(expression), Box::new(self.parse_and()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.parse_comparison()?;
+        while self.accept("&&") {
+            expression = Expression::And(Box::new(expression), Box::new(self.parse_comparison()?));
+        }
+        Ok(expression)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expression, String> {
+        if self.accept("(") {
+            let expression = self.parse_or()?;
+            if !self.accept(")") {
+                return Err("Missing `)` in condition".to_string());
+            }
+            return Ok(expression);
+        }
+        let a = self.parse_operand()?;
+        let token = self.next()?;
+        let op = match COMPARISONS.iter().find(|&&op| op == token) {
+            Some(&op) => op,
+            None => return Err(format!("Expected comparison instead of `{}`", token)),
+        };
+        let b = self.parse_operand()?;
+        Ok(Expression::Compare(a, op, b))
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        let token = self.next()?;
+        let upper = token.to_uppercase();
+        let register = match &upper[..] {
+            "I" => Register::I,
+            "PC" => Register::Pc,
+            "SP" => Register::Sp,
+            "DT" => Register::Dt,
+            "ST" => Register::St,
+            _ if upper.len() == 2 && upper.starts_with('V') => {
+                match usize::from_str_radix(&upper[1..], 16) {
+                    Ok(x) => Register::V(x),
+                    Err(_) => return Err(format!("Unknown register: {}", token)),
+                }
+            }
+            _ => return parse_number(&token).map(Operand::Value),
+        };
+        Ok(Operand::Register(register))
+    }
+}
+
+/// Parses a decimal number or a hex number with `0x` prefix.
+pub fn parse_number(s: &str) -> Result<u16, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    parsed.map_err(|_| format!("Invalid number: {}", s))
+}
+
+/// Stops at an address, when a condition holds at an address, or, without
+/// address, when a condition becomes true.
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub address: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.address, &self.condition) {
+            (Some(address), Some(condition)) => write!(f, "{:03X} if {}", address, condition),
+            (Some(address), None) => write!(f, "{:03X}", address),
+            (None, Some(condition)) => write!(f, "if {}", condition),
+            (None, None) => write!(f, "never"),
+        }
+    }
+}
+
+/// Parses `ADDRESS`, `ADDRESS if CONDITION` or `if CONDITION`.
+impl FromStr for Breakpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Breakpoint, String> {
+        let s = s.trim();
+        let (address, condition) = match s.find("if ") {
+            Some(0) => (None, Some(&s[3..])),
+            Some(index) => (Some(s[..index].trim()), Some(&s[index + 3..])),
+            None => (Some(s), None),
+        };
+        Ok(Breakpoint {
+            address: match address {
+                Some(address) => Some(parse_number(address)?),
+                None => None,
+            },
+            condition: match condition {
+                Some(condition) => Some(condition.parse()?),
+                None => None,
+            },
+        })
+    }
+}
+
+/// Why `Debugger` stopped the execution.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Stop {
+    /// Index of the breakpoint that was hit
+    Breakpoint(usize),
+    /// The instruction at `pc` accessed a watched address
+    Watchpoint { pc: u16, hit: WatchHit },
+    Error(Error),
+}
+
+/// Runs a `Machine` until a breakpoint or watchpoint is hit. Breakpoints are
+/// checked before the instruction at PC is executed, watchpoints after the
+/// instruction that accessed the memory.
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    // Last result of every condition, so that breakpoints without address
+    // only stop when their condition becomes true
+    conditions_held: Vec<bool>,
+    // PC of the last stop at a breakpoint, which execution resumes from
+    stopped_at: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint, registers: &Registers) {
+        let held = breakpoint
+            .condition
+            .as_ref()
+            .is_some_and(|c| c.evaluate(registers));
+        self.breakpoints.push(breakpoint);
+        self.conditions_held.push(held);
+    }
+
+    pub fn remove_breakpoint(&mut self, index: usize) -> Option<Breakpoint> {
+        if index < self.breakpoints.len() {
+            self.conditions_held.remove(index);
+            Some(self.breakpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Executes a single instruction regardless of breakpoints at PC and
+    /// reports the breakpoints at the instruction it arrives at.
+    pub fn step(&mut self, machine: &mut Machine) -> Option<Stop> {
+        self.stopped_at = None;
+        if let Some(stop) = self.execute(machine) {
+            return Some(stop);
+        }
+        self.check_breakpoints(machine.registers())
+    }
+
+    /// Executes instructions until the debugger stops or `limit`
+    /// instructions were executed. The breakpoints at the current PC are
+    /// ignored if the debugger stopped there, so that execution can continue
+    /// after a breakpoint was hit.
+    pub fn run(&mut self, machine: &mut Machine, limit: u64) -> Option<Stop> {
+        let resumed_from = self.stopped_at.take();
+        for count in 0..limit {
+            if count > 0 || resumed_from != Some(machine.pc()) {
+                if let Some(stop) = self.check_breakpoints(machine.registers()) {
+                    return Some(stop);
+                }
+            }
+            if let Some(stop) = self.execute(machine) {
+                return Some(stop);
+            }
+        }
+        None
+    }
+
+    fn execute(&mut self, machine: &mut Machine) -> Option<Stop> {
+        let pc = machine.pc();
+        if let Err(e) = machine.step() {
+            return Some(Stop::Error(e));
+        }
+        let hits = machine.memory().take_hits();
+        let fetched = |hit: &WatchHit| !hit.write && (hit.address == pc || hit.address == pc.wrapping_add(1));
+        hits.iter()
+            .find(|hit| !fetched(hit))
+            .map(|&hit| Stop::Watchpoint { pc, hit })
+    }
+
+    fn check_breakpoints(&mut self, registers: &Registers) -> Option<Stop> {
+        let mut stop = None;
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            let held = breakpoint
+                .condition
+                .as_ref()
+                .is_none_or(|c| c.evaluate(registers));
+            let hit = match breakpoint.address {
+                Some(address) => address == registers.pc && held,
+                None => held && !self.conditions_held[index],
+            };
+            self.conditions_held[index] = held;
+            if hit && stop.is_none() {
+                stop = Some(Stop::Breakpoint(index));
+                self.stopped_at = Some(registers.pc);
+            }
+        }
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::{BlockMemory, MemoryMap};
+
+    fn registers() -> Registers {
+        Registers {
+            v: [0; 16],
+            stack: vec![0; 16],
+            i: 0x310,
+            pc: 0x200,
+            sp: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+        }
+    }
+
+    #[test]
+    fn conditions_compare_registers() {
+        let mut r = registers();
+        r.v[3] = 0x10;
+        let condition: Condition = "V3 == 0x10 && I > 0x300".parse().unwrap();
+        assert!(condition.evaluate(&r));
+        r.i = 0x300;
+        assert!(!condition.evaluate(&r));
+
+        let condition: Condition = "(v3 != 16 || pc >= 512) && VF < V3".parse().unwrap();
+        assert!(condition.evaluate(&r));
+        r.v[0xF] = 0x10;
+        assert!(!condition.evaluate(&r));
+    }
+
+    #[test]
+    fn invalid_conditions_are_rejected() {
+        for condition in &["", "V3", "V3 = 1", "VG == 1", "V3 == 1 &&", "(V3 == 1", "V3 == 1)"] {
+            assert!(condition.parse::<Condition>().is_err(), "{}", condition);
+        }
+    }
+
+    #[test]
+    fn breakpoints_parse_address_and_condition() {
+        let breakpoint: Breakpoint = "0x2A0 if V0 == 1".parse().unwrap();
+        assert_eq!(breakpoint.address, Some(0x2A0));
+        assert_eq!(breakpoint.to_string(), "2A0 if V0 == 1");
+        let breakpoint: Breakpoint = "if V0 == 1".parse().unwrap();
+        assert_eq!(breakpoint.address, None);
+        assert!(breakpoint.condition.is_some());
+    }
+
+    #[test]
+    fn watched_memory_records_accesses() {
+        let mut memory = WatchedMemory::new(BlockMemory::new());
+        memory.add_watchpoint(Watchpoint {
+            start: 0x300,
+            end: 0x301,
+            kind: WatchKind::Write,
+        });
+        memory.add_watchpoint(Watchpoint {
+            start: 0x310,
+            end: 0x310,
+            kind: WatchKind::Read,
+        });
+        memory.write_byte(0x2FF, 1).unwrap();
+        memory.write_byte(0x301, 2).unwrap();
+        memory.write_byte(0x301, 3).unwrap();
+        memory.read_byte(0x301).unwrap();
+        memory.read_block(0x30E, 4).unwrap();
+        let hits = memory.take_hits();
+        assert_eq!(hits.len(), 3);
+        assert_eq!((hits[0].address, hits[0].old, hits[0].new), (0x301, 0, 2));
+        assert_eq!((hits[1].old, hits[1].new), (2, 3));
+        assert_eq!((hits[2].address, hits[2].write), (0x310, false));
+        assert!(memory.take_hits().is_empty());
+    }
+
+    #[test]
+    fn breakpoints_stop_before_executing() {
+        // V0 = 5, then V0 += 1 in a loop
+        let rom = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+        let mut machine = Machine::from_bytes(&rom, 0x200).unwrap();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint("0x200".parse().unwrap(), machine.registers());
+        assert_eq!(debugger.run(&mut machine, 10), Some(Stop::Breakpoint(0)));
+        assert_eq!((machine.pc(), machine.registers().v[0]), (0x200, 0));
+        assert_eq!(debugger.run(&mut machine, 10), None);
+
+        debugger.add_breakpoint("0x202".parse().unwrap(), machine.registers());
+        assert_eq!(debugger.run(&mut machine, 10), Some(Stop::Breakpoint(1)));
+        let v0 = machine.registers().v[0];
+        assert_eq!(debugger.run(&mut machine, 10), Some(Stop::Breakpoint(1)));
+        assert_eq!(machine.registers().v[0], v0 + 1);
+
+        // Steps leave the breakpoint and report the one they arrive at
+        assert_eq!(debugger.step(&mut machine), None);
+        assert_eq!(debugger.step(&mut machine), Some(Stop::Breakpoint(1)));
+        assert_eq!(debugger.run(&mut machine, 1), None);
+    }
+
+    #[test]
+    fn mirrored_registers_do_not_hit_watchpoints() {
+        // Stores V0 into its own place in the VIP memory map and loads it
+        let rom = [0x60, 0x05, 0xAE, 0xF0, 0xF0, 0x55, 0xF0, 0x65, 0x12, 0x08];
+        let mut machine = Machine::from_bytes(&rom, 0x200).unwrap();
+        machine.set_memory_map(MemoryMap::Vip).unwrap();
+        machine.memory_mut().add_watchpoint(Watchpoint {
+            start: 0xEF0,
+            end: 0xEF0,
+            kind: WatchKind::Read,
+        });
+        let mut debugger = Debugger::new();
+        let hit = WatchHit {
+            address: 0xEF0,
+            write: false,
+            old: 5,
+            new: 5,
+        };
+        let stop = debugger.run(&mut machine, 10);
+        assert_eq!(stop, Some(Stop::Watchpoint { pc: 0x206, hit }));
+        assert_eq!(debugger.run(&mut machine, 10), None);
+    }
+}
@@ -0,0 +1,158 @@
+use analysis::analyze;
+
+const ORIGIN: usize = 0x200;
+
+/// Returns the mnemonic of the opcode in the notation of Cowgod's CHIP-8
+/// technical reference, e.g. `LD VA, 0x02`. Opcodes that are not
+/// instructions are shown as `:byte` data.
+pub fn disassemble(opcode: u16) -> String {
+    let nnn = opcode & 0xFFF;
+    let x = (opcode >> 8) & 0xF;
+    let y = (opcode >> 4) & 0xF;
+    let n = opcode & 0xF;
+    let kk = opcode & 0xFF;
+    match opcode {
+        0x00E0 => "CLS".to_string(),
+        0x00EE => "RET".to_string(),
+        0x1000..=0x1FFF => format!("JP 0x{:03X}", nnn),
+        0x2000..=0x2FFF => format!("CALL 0x{:03X}", nnn),
+        0x3000..=0x3FFF => format!("SE V{:X}, 0x{:02X}", x, kk),
+        0x4000..=0x4FFF => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        0x5000..=0x5FFF if n == 0x0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000..=0x6FFF => format!("LD V{:X}, 0x{:02X}", x, kk),
+        0x7000..=0x7FFF => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        0x8000..=0x8FFF if n == 0x0 => format!("LD V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0x1 => format!("OR V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0x2 => format!("AND V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0x3 => format!("XOR V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0x4 => format!("ADD V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0x5 => format!("SUB V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0x6 => shift("SHR", x, y),
+        0x8000..=0x8FFF if n == 0x7 => format!("SUBN V{:X}, V{:X}", x, y),
+        0x8000..=0x8FFF if n == 0xE => shift("SHL", x, y),
+        0x9000..=0x9FFF if n == 0x0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000..=0xAFFF => format!("LD I, 0x{:03X}", nnn),
+        0xB000..=0xBFFF => format!("JP V0, 0x{:03X}", nnn),
+        0xC000..=0xCFFF => format!("RND V{:X}, 0x{:02X}", x, kk),
+        0xD000..=0xDFFF if n != 0x0 => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        0xE000..=0xEFFF if kk == 0x9E => format!("SKP V{:X}", x),
+        0xE000..=0xEFFF if kk == 0xA1 => format!("SKNP V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x07 => format!("LD V{:X}, DT", x),
+        0xF000..=0xFFFF if kk == 0x0A => format!("LD V{:X}, K", x),
+        0xF000..=0xFFFF if kk == 0x15 => format!("LD DT, V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x18 => format!("LD ST, V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x1E => format!("ADD I, V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x29 => format!("LD F, V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x30 => format!("LD HF, V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x33 => format!("LD B, V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x55 => format!("LD [I], V{:X}", x),
+        0xF000..=0xFFFF if kk == 0x65 => format!("LD V{:X}, [I]", x),
+        _ => format!(":byte 0x{:02X} 0x{:02X}", opcode >> 8, kk),
+    }
+}
+
+// The shift instructions ignore Vy, it is only shown if it is set
+fn shift(mnemonic: &str, x: u16, y: u16) -> String {
+    if y == 0 {
+        format!("{} V{:X}", mnemonic, x)
+    } else {
+        format!("{} V{:X}, V{:X}", mnemonic, x, y)
+    }
+}
+
+/// Disassembles a ROM loaded at 0x200 into a listing that `assemble` turns
+/// back into the same ROM. The control flow is analyzed to name subroutines,
+/// jump targets and data, and to show the bytes that are never executed as
+/// data instead of instructions. Data loaded into I is shown as sprite rows.
+pub fn disassemble_rom(rom: &[u8]) -> String {
+    let graph = analyze(rom);
+    let end = ORIGIN + rom.len();
+
+    // Lines of the listing as address, size and whether they are sprite rows
+    let mut lines = Vec::new();
+    let mut address = ORIGIN;
+    let mut sprites = false;
+    while address < end {
+        if graph.instruction(address as u16).is_some() {
+            lines.push((address, 2, false));
+            sprites = false;
+            address += 2;
+            continue;
+        }
+        sprites |= graph.data_references.contains(&(address as u16));
+        let start = address;
+        address += 1;
+        while !sprites
+            && address < end
+            && address - start < 8
+            && graph.instruction(address as u16).is_none()
+            && graph.label(address as u16).is_none()
+        {
+            address += 1;
+        }
+        lines.push((start, address - start, sprites));
+    }
+    // Labels can only be placed at the start of a line, other targets stay
+    // numbers
+    let label = |address: usize| {
+        lines
+            .iter()
+            .find(|line| line.0 == address)
+            .and_then(|_| graph.label(address as u16))
+    };
+    let operand = |target: u16| label(target as usize).unwrap_or(format!("0x{:03X}", target));
+
+    let mut listing = String::new();
+    for &(address, size, sprite) in &lines {
+        if let Some(label) = label(address) {
+            listing += &format!("{}:\n", label);
+        }
+        let bytes = &rom[address - ORIGIN..address - ORIGIN + size];
+        let (text, comment) = match graph.instruction(address as u16) {
+            Some(opcode) => {
+                let text = match opcode >> 12 {
+                    0x1 => format!("JP {}", operand(opcode & 0xFFF)),
+                    0x2 => format!("CALL {}", operand(opcode & 0xFFF)),
+                    0xA => format!("LD I, {}", operand(opcode & 0xFFF)),
+                    0xB => format!("JP V0, {}", operand(opcode & 0xFFF)),
+                    _ => disassemble(opcode),
+                };
+                (text, format!("{:03X}  {:04X}", address, opcode))
+            }
+            None if sprite => {
+                let row: String = (0..8)
+                    .map(|x| if bytes[0] & 0x80 >> x != 0 { 'X' } else { '.' })
+                    .collect();
+                (format!(":sprite {}", row), format!("{:03X}  {:02X}", address, bytes[0]))
+            }
+            None => {
+                let hex: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+                (format!(":byte {}", hex.join(" ")), format!("{:03X}", address))
+            }
+        };
+        listing += &format!("    {:<24}; {}\n", text, comment);
+    }
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    #[test]
+    fn listing_names_code_and_data_and_assembles_back() {
+        // LD I, 0x20E; CALL 0x20A; SE V0, 0x00; JP 0x202; JP 0x208;
+        // DRW V0, V0, 5; RET; followed by the sprite of 0 and an odd byte
+        let rom = [
+            0xA2, 0x0E, 0x22, 0x0A, 0x30, 0x00, 0x12, 0x02, 0x12, 0x08, 0xD0, 0x05, 0x00, 0xEE,
+            0xF0, 0x90, 0x90, 0x90, 0xF0, 0x12,
+        ];
+        let listing = disassemble_rom(&rom);
+        assert!(listing.contains("    CALL sub_20A            ; 202  220A\n"));
+        assert!(listing.contains("sub_20A:\n    DRW V0, V0, 5"));
+        assert!(listing.contains("data_20E:\n    :sprite XXXX....        ; 20E  F0\n"));
+        assert!(!listing.contains("loc_20E"));
+        assert_eq!(assemble(&listing).unwrap(), rom.to_vec());
+    }
+}
@@ -1,11 +1,19 @@
+use overlay;
 use sdl2::Sdl;
-use sdl2::pixels::PixelFormatEnum;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
 use sdl2::render::{Canvas, Texture, TextureCreator};
 use sdl2::video::{Window, WindowContext};
 
 const COLS: usize = 64;
 const ROWS: usize = 32;
 const PIXELS: usize = (COLS * ROWS) as usize;
+const SCALE: u32 = 10;
+
+// The debug panel is scaled to the height of the screen
+const PANEL_WIDTH: u32 = (overlay::PANEL_COLS * overlay::CHAR_WIDTH) as u32;
+const PANEL_HEIGHT: u32 = (overlay::PANEL_ROWS * overlay::CHAR_HEIGHT) as u32;
+const PANEL_SCALE: u32 = SCALE * ROWS as u32 / PANEL_HEIGHT;
 
 pub struct DisplayContext {
     canvas: Canvas<Window>,
@@ -13,65 +21,139 @@ pub struct DisplayContext {
 }
 
 impl DisplayContext {
-    pub fn new(sdl_context: &Sdl) -> DisplayContext {
-        let video_subsystem = sdl_context.video().unwrap();
+    pub fn new(sdl_context: &Sdl) -> Result<DisplayContext, String> {
+        let video_subsystem = sdl_context.video()?;
         let window = video_subsystem
-            .window("chip8", 10 * COLS as u32, 10 * ROWS as u32)
+            .window("chip8", SCALE * COLS as u32, SCALE * ROWS as u32)
             .position_centered()
             .opengl()
             .build()
-            .unwrap();
-        let canvas = window.into_canvas().build().unwrap();
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
         let texture_creator = canvas.texture_creator();
-        DisplayContext {
+        Ok(DisplayContext {
             canvas,
             texture_creator,
+        })
+    }
+}
+
+/// RGB colors of the set and the unset pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub foreground: [u8; 3],
+    pub background: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            foreground: [0xFF; 3],
+            background: [0x00; 3],
         }
     }
 }
 
-pub struct Display<'a> {
+pub trait Display {
+    fn clear(&mut self);
+    fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool;
+    fn redraw(&mut self);
+    /// The pixels with 8 per byte, the leftmost in the most significant
+    /// bit, row by row like the display RAM of the COSMAC VIP.
+    fn to_bytes(&self) -> Vec<u8>;
+    /// Sets the pixels from bytes in the format of `to_bytes`.
+    fn load_bytes(&mut self, bytes: &[u8]);
+}
+
+/// Pixel state of the screen without any output attached.
+#[derive(Clone)]
+pub struct Framebuffer {
     pixels: [bool; PIXELS],
-    canvas: &'a mut Canvas<Window>,
-    texture: Texture<'a>,
 }
 
-impl<'a> Display<'a> {
-    pub fn new(display_context: &'a mut DisplayContext) -> Display<'a> {
-        let texture = display_context
-            .texture_creator
-            .create_texture_streaming(PixelFormatEnum::RGB24, COLS as u32, ROWS as u32)
-            .unwrap();
-        Display {
+impl Framebuffer {
+    pub fn new() -> Framebuffer {
+        Framebuffer {
             pixels: [false; PIXELS],
-            canvas: &mut display_context.canvas,
-            texture,
         }
     }
 
-    pub fn redraw(&mut self) {
-        let pixels = &self.pixels;
-        self.texture
-            .with_lock(None, |buffer: &mut [u8], _: usize| {
-                for (i, &p) in pixels.iter().enumerate() {
-                    let offset = i * 3;
-                    let val = p as u8 * 255;
-                    buffer[offset] = val;
-                    buffer[offset + 1] = val;
-                    buffer[offset + 2] = val;
-                }
-            })
-            .unwrap();
-        self.canvas.clear();
-        self.canvas.copy(&self.texture, None, None).unwrap();
-        self.canvas.present();
+    pub fn pixels(&self) -> &[bool] {
+        &self.pixels
+    }
+
+    /// 64 bit FNV-1a hash of the pixels, one byte per pixel in row-major order
+    pub fn hash(&self) -> u64 {
+        self.pixels.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &p| {
+            (hash ^ p as u64).wrapping_mul(0x0100_0000_01b3)
+        })
     }
 
-    pub fn clear(&mut self) {
+    /// Writes the pixels as plain PBM image, i.e. the `P1` variant of the
+    /// netpbm formats, where set pixels are black.
+    pub fn to_pbm(&self) -> String {
+        let mut pbm = format!("P1\n{} {}\n", COLS, ROWS);
+        for row in self.pixels.chunks(COLS) {
+            let line: Vec<&str> = row.iter().map(|&p| if p { "1" } else { "0" }).collect();
+            pbm.push_str(&line.join(" "));
+            pbm.push('\n');
+        }
+        pbm
+    }
+
+    pub fn from_pbm(pbm: &str) -> Result<Framebuffer, String> {
+        let mut tokens = pbm
+            .lines()
+            .map(|line| line.split('#').next().unwrap())
+            .flat_map(|line| line.split_whitespace());
+        if tokens.next() != Some("P1") {
+            return Err("Not a plain PBM image".to_string());
+        }
+        let size = (tokens.next(), tokens.next());
+        if size != (Some(&COLS.to_string()[..]), Some(&ROWS.to_string()[..])) {
+            return Err(format!("Image size must be {}x{}", COLS, ROWS));
+        }
+        // Pixels do not need to be separated by whitespace
+        let bits: String = tokens.collect();
+        if bits.len() != PIXELS {
+            return Err(format!("Expected {} pixels, found {}", PIXELS, bits.len()));
+        }
+        let mut framebuffer = Framebuffer::new();
+        for (pixel, bit) in framebuffer.pixels.iter_mut().zip(bits.chars()) {
+            *pixel = match bit {
+                '0' => false,
+                '1' => true,
+                _ => return Err(format!("Invalid pixel: {}", bit)),
+            };
+        }
+        Ok(framebuffer)
+    }
+
+    /// Returns the number of pixels that differ between the two framebuffers.
+    pub fn diff(&self, other: &Framebuffer) -> usize {
+        self.pixels.iter().zip(other.pixels.iter()).filter(|&(a, b)| a != b).count()
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize) -> bool {
+        let i = y * COLS + x;
+        let was_set = self.pixels[i];
+        self.pixels[i] ^= true;
+        was_set
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer::new()
+    }
+}
+
+impl Display for Framebuffer {
+    fn clear(&mut self) {
         self.pixels = [false; PIXELS];
     }
 
-    pub fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
+    fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
         let x = x as usize;
         let y = y as usize;
         let mut erased_pixel = false;
@@ -88,10 +170,138 @@ impl<'a> Display<'a> {
         erased_pixel
     }
 
-    fn set_pixel(&mut self, x: usize, y: usize) -> bool {
-        let i = y * COLS + x;
-        let was_set = self.pixels[i];
-        self.pixels[i] ^= true;
-        was_set
+    fn redraw(&mut self) {}
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.pixels
+            .chunks(8)
+            .map(|pixels| pixels.iter().fold(0, |byte, &p| byte << 1 | p as u8))
+            .collect()
+    }
+
+    fn load_bytes(&mut self, bytes: &[u8]) {
+        for (pixels, byte) in self.pixels.chunks_mut(8).zip(bytes.iter()) {
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = byte & (0x80 >> i) != 0;
+            }
+        }
+    }
+}
+
+pub struct SdlDisplay<'a> {
+    framebuffer: Framebuffer,
+    canvas: &'a mut Canvas<Window>,
+    texture: Texture<'a>,
+    panel: Texture<'a>,
+    overlay: Option<Vec<String>>,
+    palette: Palette,
+}
+
+impl<'a> SdlDisplay<'a> {
+    pub fn new(display_context: &'a mut DisplayContext) -> SdlDisplay<'a> {
+        let texture = display_context
+            .texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, COLS as u32, ROWS as u32)
+            .unwrap();
+        let panel = display_context
+            .texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, PANEL_WIDTH, PANEL_HEIGHT)
+            .unwrap();
+        SdlDisplay {
+            framebuffer: Framebuffer::new(),
+            canvas: &mut display_context.canvas,
+            texture,
+            panel,
+            overlay: None,
+            palette: Palette::default(),
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).unwrap();
+    }
+
+    /// Shows the lines in a debug panel to the right of the screen, or
+    /// hides the panel if `None`. The window grows to make room for it.
+    pub fn set_overlay(&mut self, lines: Option<Vec<String>>) {
+        if lines.is_some() != self.overlay.is_some() {
+            let mut width = SCALE * COLS as u32;
+            if lines.is_some() {
+                width += PANEL_SCALE * PANEL_WIDTH;
+            }
+            self.canvas
+                .window_mut()
+                .set_size(width, SCALE * ROWS as u32)
+                .unwrap();
+        }
+        self.overlay = lines;
+    }
+
+    /// Shows the lines instead of the screen, in the font of the debug
+    /// panel and centered in the window.
+    pub fn show_text(&mut self, lines: &[String]) {
+        self.panel
+            .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                overlay::render(lines, buffer, pitch)
+            })
+            .unwrap();
+        let width = PANEL_SCALE * PANEL_WIDTH;
+        let x = (SCALE * COLS as u32).saturating_sub(width) as i32 / 2;
+        self.canvas.set_draw_color(Color::RGB(0x20, 0x20, 0x20));
+        self.canvas.clear();
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        let area = Rect::new(x, 0, width, PANEL_SCALE * PANEL_HEIGHT);
+        self.canvas.copy(&self.panel, None, area).unwrap();
+        self.canvas.present();
+    }
+}
+
+impl<'a> Display for SdlDisplay<'a> {
+    fn clear(&mut self) {
+        self.framebuffer.clear();
+    }
+
+    fn draw_sprite(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
+        self.framebuffer.draw_sprite(x, y, sprite)
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.framebuffer.to_bytes()
+    }
+
+    fn load_bytes(&mut self, bytes: &[u8]) {
+        self.framebuffer.load_bytes(bytes);
+    }
+
+    fn redraw(&mut self) {
+        let pixels = self.framebuffer.pixels();
+        let palette = self.palette;
+        self.texture
+            .with_lock(None, |buffer: &mut [u8], _: usize| {
+                for (i, &p) in pixels.iter().enumerate() {
+                    let offset = i * 3;
+                    let color = if p { palette.foreground } else { palette.background };
+                    buffer[offset..offset + 3].copy_from_slice(&color);
+                }
+            })
+            .unwrap();
+        self.canvas.clear();
+        let screen = Rect::new(0, 0, SCALE * COLS as u32, SCALE * ROWS as u32);
+        self.canvas.copy(&self.texture, None, screen).unwrap();
+        if let Some(ref lines) = self.overlay {
+            self.panel
+                .with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                    overlay::render(lines, buffer, pitch)
+                })
+                .unwrap();
+            let x = screen.width() as i32;
+            let area = Rect::new(x, 0, PANEL_SCALE * PANEL_WIDTH, PANEL_SCALE * PANEL_HEIGHT);
+            self.canvas.copy(&self.panel, None, area).unwrap();
+        }
+        self.canvas.present();
     }
 }
@@ -0,0 +1,31 @@
+use std::error;
+use std::fmt;
+
+/// Errors raised by the CHIP-8 machine while executing a program
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    UnknownOpcode(u16),
+    AddressOutOfBounds(u16),
+    /// Write to memory that belongs to the interpreter
+    WriteProtected(u16),
+    StackOverflow,
+    StackUnderflow,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownOpcode(opcode) => write!(f, "Opcode unknown: {:04X}", opcode),
+            Error::AddressOutOfBounds(address) => {
+                write!(f, "Address out of bounds: {:04X}", address)
+            }
+            Error::WriteProtected(address) => {
+                write!(f, "Write to protected address: {:04X}", address)
+            }
+            Error::StackOverflow => write!(f, "Stack overflow"),
+            Error::StackUnderflow => write!(f, "Stack underflow"),
+        }
+    }
+}
+
+impl error::Error for Error {}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Size of the small font: 16 digits of 5 bytes
+pub const SMALL_FONT_SIZE: usize = 16 * 5;
+
+const VIP_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0,
+    0x60, 0x20, 0x20, 0x20, 0x70,
+    0xF0, 0x10, 0xF0, 0x80, 0xF0,
+    0xF0, 0x10, 0xF0, 0x10, 0xF0,
+    0xA0, 0xA0, 0xF0, 0x20, 0x20,
+    0xF0, 0x80, 0xF0, 0x10, 0xF0,
+    0xF0, 0x80, 0xF0, 0x90, 0xF0,
+    0xF0, 0x10, 0x10, 0x10, 0x10,
+    0xF0, 0x90, 0xF0, 0x90, 0xF0,
+    0xF0, 0x90, 0xF0, 0x10, 0xF0,
+    0xF0, 0x90, 0xF0, 0x90, 0x90,
+    0xF0, 0x50, 0x70, 0x50, 0xF0,
+    0xF0, 0x80, 0x80, 0x80, 0xF0,
+    0xF0, 0x50, 0x50, 0x50, 0xF0,
+    0xF0, 0x80, 0xF0, 0x80, 0xF0,
+    0xF0, 0x80, 0xF0, 0x80, 0x80,
+];
+
+const ETI_660_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0,
+    0x20, 0x20, 0x20, 0x20, 0x20,
+    0xE0, 0x20, 0xE0, 0x80, 0xE0,
+    0xE0, 0x20, 0xE0, 0x20, 0xE0,
+    0xA0, 0xA0, 0xE0, 0x20, 0x20,
+    0xE0, 0x80, 0xE0, 0x20, 0xE0,
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0,
+    0xE0, 0x20, 0x20, 0x20, 0x20,
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0,
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0,
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0,
+    0x80, 0x80, 0xE0, 0xA0, 0xE0,
+    0xE0, 0x80, 0x80, 0x80, 0xE0,
+    0x20, 0x20, 0xE0, 0xA0, 0xE0,
+    0xE0, 0x80, 0xE0, 0x80, 0xE0,
+    0xE0, 0x80, 0xC0, 0x80, 0x80,
+];
+
+const DREAM_6800_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xE0, 0xA0, 0xA0, 0xA0, 0xE0,
+    0x40, 0x40, 0x40, 0x40, 0x40,
+    0xE0, 0x20, 0xE0, 0x80, 0xE0,
+    0xE0, 0x20, 0xE0, 0x20, 0xE0,
+    0x80, 0xA0, 0xA0, 0xE0, 0x20,
+    0xE0, 0x80, 0xE0, 0x20, 0xE0,
+    0xE0, 0x80, 0xE0, 0xA0, 0xE0,
+    0xE0, 0x20, 0x20, 0x20, 0x20,
+    0xE0, 0xA0, 0xE0, 0xA0, 0xE0,
+    0xE0, 0xA0, 0xE0, 0x20, 0xE0,
+    0xE0, 0xA0, 0xE0, 0xA0, 0xA0,
+    0xC0, 0xA0, 0xE0, 0xA0, 0xC0,
+    0xE0, 0x80, 0x80, 0x80, 0xE0,
+    0xC0, 0xA0, 0xA0, 0xA0, 0xC0,
+    0xE0, 0x80, 0xE0, 0x80, 0xE0,
+    0xE0, 0x80, 0xC0, 0x80, 0x80,
+];
+
+// Used by SUPER-CHIP and Octo, and the font of this emulator from the start
+const MODERN_SMALL: [u8; SMALL_FONT_SIZE] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0,
+    0x20, 0x60, 0x20, 0x20, 0x70,
+    0xF0, 0x10, 0xF0, 0x80, 0xF0,
+    0xF0, 0x10, 0xF0, 0x10, 0xF0,
+    0x90, 0x90, 0xF0, 0x10, 0x10,
+    0xF0, 0x80, 0xF0, 0x10, 0xF0,
+    0xF0, 0x80, 0xF0, 0x90, 0xF0,
+    0xF0, 0x10, 0x20, 0x40, 0x40,
+    0xF0, 0x90, 0xF0, 0x90, 0xF0,
+    0xF0, 0x90, 0xF0, 0x10, 0xF0,
+    0xF0, 0x90, 0xF0, 0x90, 0x90,
+    0xE0, 0x90, 0xE0, 0x90, 0xE0,
+    0xF0, 0x80, 0x80, 0x80, 0xF0,
+    0xE0, 0x90, 0x90, 0x90, 0xE0,
+    0xF0, 0x80, 0xF0, 0x80, 0xF0,
+    0xF0, 0x80, 0xF0, 0x80, 0x80,
+];
+
+// SUPER-CHIP 1.1 only has big digits 0 to 9
+const SCHIP_BIG: [u8; 10 * 10] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C,
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C,
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF,
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C,
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C,
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C,
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60,
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C,
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C,
+];
+
+const OCTO_BIG: [u8; 16 * 10] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF,
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF,
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18,
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF,
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3,
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC,
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C,
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0,
+];
+
+/// The sprites of the hex digits: a small font of 4x5 pixels for `LD F, Vx`
+/// and, on interpreters that have one, a big font of 8x10 pixels for
+/// `LD HF, Vx`, which is stored right after the small one. For digits
+/// without a big sprite, like A to F of SUPER-CHIP or all digits of the
+/// fonts without big font, `LD HF, Vx` points past the end of the font.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Font {
+    small: Vec<u8>,
+    big: Vec<u8>,
+}
+
+impl Font {
+    pub fn vip() -> Font {
+        Font::new(&VIP_SMALL, &[])
+    }
+
+    pub fn eti_660() -> Font {
+        Font::new(&ETI_660_SMALL, &[])
+    }
+
+    pub fn dream_6800() -> Font {
+        Font::new(&DREAM_6800_SMALL, &[])
+    }
+
+    pub fn schip() -> Font {
+        Font::new(&MODERN_SMALL, &SCHIP_BIG)
+    }
+
+    pub fn octo() -> Font {
+        Font::new(&MODERN_SMALL, &OCTO_BIG)
+    }
+
+    fn new(small: &[u8], big: &[u8]) -> Font {
+        Font {
+            small: small.to_vec(),
+            big: big.to_vec(),
+        }
+    }
+
+    /// Parses a font file: the 80 bytes of the small font, optionally
+    /// followed by a big font of 10 bytes for each of the digits 0 to 9 or
+    /// 0 to F.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Font, String> {
+        match bytes.len().checked_sub(SMALL_FONT_SIZE) {
+            Some(0) | Some(100) | Some(160) => {
+                Ok(Font::new(&bytes[..SMALL_FONT_SIZE], &bytes[SMALL_FONT_SIZE..]))
+            }
+            _ => Err(format!(
+                "Invalid font size: {} bytes (expected 80, 180 or 240)",
+                bytes.len()
+            )),
+        }
+    }
+
+    pub fn load_file(path: &Path) -> Result<Font, String> {
+        let bytes = fs::read(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        Font::from_bytes(&bytes).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// The small font followed by the big one, as stored in memory.
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = self.small.clone();
+        bytes.extend_from_slice(&self.big);
+        bytes
+    }
+}
+
+/// The font of Octo, whose small digits are also those of SUPER-CHIP.
+impl Default for Font {
+    fn default() -> Font {
+        Font::octo()
+    }
+}
+
+impl FromStr for Font {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Font, String> {
+        match &s.to_lowercase()[..] {
+            "vip" | "chip8" | "chip-8" => Ok(Font::vip()),
+            "eti-660" | "eti660" => Ok(Font::eti_660()),
+            "dream-6800" | "dream6800" => Ok(Font::dream_6800()),
+            "schip" | "superchip" => Ok(Font::schip()),
+            "octo" => Ok(Font::octo()),
+            _ => Err(format!(
+                "Unknown font: {} (expected vip, eti-660, dream-6800, schip or octo)",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn font_files_need_whole_fonts() {
+        let font = Font::from_bytes(&[0xF0; 180]).unwrap();
+        assert_eq!(font.bytes().len(), 180);
+        assert_eq!(Font::from_bytes(&Font::vip().bytes()), Ok(Font::vip()));
+        assert!(Font::from_bytes(&[0xF0; 79]).is_err());
+        assert!(Font::from_bytes(&[0xF0; 81]).is_err());
+        assert_eq!("ETI660".parse(), Ok(Font::eti_660()));
+    }
+}
@@ -0,0 +1,550 @@
+use debugger::{Breakpoint, Debugger, Stop, WatchKind, Watchpoint};
+use error::Error;
+use headless::Machine;
+use memory::Memory;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Instructions executed between checks for an interrupt from the debugger
+const INSTRUCTIONS_PER_POLL: u64 = 10000;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+/// The registers in the order of the target description, with the size of
+/// each in bytes. Values are sent little endian.
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1), ("v1", 1), ("v2", 1), ("v3", 1), ("v4", 1), ("v5", 1), ("v6", 1), ("v7", 1),
+    ("v8", 1), ("v9", 1), ("va", 1), ("vb", 1), ("vc", 1), ("vd", 1), ("ve", 1), ("vf", 1),
+    ("i", 2), ("pc", 2), ("sp", 1), ("dt", 1), ("st", 1),
+];
+
+/// Serves the first connection of a debugger speaking the GDB remote serial
+/// protocol until it detaches or disconnects. The machine is halted until
+/// the debugger continues or steps it.
+pub fn serve(machine: &mut Machine, listener: &TcpListener) -> io::Result<()> {
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let mut stub = GdbStub {
+        machine,
+        debugger: Debugger::new(),
+        stream,
+        no_ack: false,
+    };
+    stub.run()
+}
+
+struct GdbStub<'a> {
+    machine: &'a mut Machine,
+    debugger: Debugger,
+    stream: TcpStream,
+    no_ack: bool,
+}
+
+impl<'a> GdbStub<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let reply = match self.handle(&packet) {
+                Some(reply) => reply,
+                None => {
+                    self.send(b"OK")?;
+                    return Ok(());
+                }
+            };
+            self.send(reply.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // Returns `None` when the debugger detaches
+    fn handle(&mut self, packet: &str) -> Option<String> {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP, ""),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "s" => self.resume(1),
+            "c" => self.resume(u64::MAX),
+            "D" | "k" => return None,
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+".to_string()
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            transfer(&target_description(), range)
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..REGISTERS.len()).map(|n| self.register(n)).collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let mut offset = 0;
+        for (n, &(_, size)) in REGISTERS.iter().enumerate() {
+            match data.get(offset..offset + 2 * size).and_then(decode_le) {
+                Some(value) => self.set_register(n, value),
+                None => return "E01".to_string(),
+            }
+            offset += 2 * size;
+        }
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16) {
+            Ok(n) if n < REGISTERS.len() => self.register(n),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let n = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+        let value = parts.next().and_then(decode_le);
+        match (n, value) {
+            (Some(n), Some(value)) if n < REGISTERS.len() => {
+                self.set_register(n, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn register(&self, n: usize) -> String {
+        let r = self.machine.registers();
+        let value = match n {
+            0..=15 => r.v[n] as u16,
+            16 => r.i,
+            17 => r.pc,
+            18 => r.sp as u16,
+            19 => r.delay_timer as u16,
+            _ => r.sound_timer as u16,
+        };
+        encode_le(value, REGISTERS[n].1)
+    }
+
+    fn set_register(&mut self, n: usize, value: u16) {
+        let r = self.machine.registers_mut();
+        match n {
+            0..=15 => r.v[n] = value as u8,
+            16 => r.i = value,
+            17 => r.pc = value,
+            18 => r.sp = (value as u8).min(r.stack.len() as u8),
+            19 => r.delay_timer = value as u8,
+            _ => r.sound_timer = value as u8,
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (address, length) = match parse_address_length(args) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let memory = self.machine.memory().inner();
+        let mut reply = String::new();
+        for offset in 0..length {
+            match memory.read_byte(address.wrapping_add(offset)) {
+                Ok(byte) => reply += &format!("{:02x}", byte),
+                // Partial reads are allowed
+                Err(_) if offset > 0 => break,
+                Err(_) => return "E02".to_string(),
+            }
+        }
+        reply
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(parse_address_length);
+        let (address, data) = match (range, parts.next()) {
+            (Some((address, length)), Some(data)) if data.len() == 2 * length as usize => {
+                (address, data)
+            }
+            _ => return "E01".to_string(),
+        };
+        let memory = self.machine.memory_mut().inner_mut();
+        for (offset, byte) in data.as_bytes().chunks(2).enumerate() {
+            let byte = match decode_byte(byte) {
+                Some(byte) => byte,
+                None => return "E01".to_string(),
+            };
+            if memory.write_byte(address.wrapping_add(offset as u16), byte).is_err() {
+                return "E02".to_string();
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Software and hardware breakpoints are the same for the emulator
+    fn insert_point(&mut self, args: &str) -> String {
+        let (kind, address, length) = match parse_point(args) {
+            Some(point) => point,
+            None => return "E01".to_string(),
+        };
+        match kind {
+            '0' | '1' => {
+                let breakpoint = Breakpoint {
+                    address: Some(address),
+                    condition: None,
+                };
+                self.debugger.add_breakpoint(breakpoint, self.machine.registers());
+            }
+            _ => {
+                let watchpoint = Watchpoint {
+                    start: address,
+                    end: address.wrapping_add(length.max(1) - 1),
+                    kind: watch_kind(kind),
+                };
+                self.machine.memory_mut().add_watchpoint(watchpoint);
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn remove_point(&mut self, args: &str) -> String {
+        let (kind, address, length) = match parse_point(args) {
+            Some(point) => point,
+            None => return "E01".to_string(),
+        };
+        match kind {
+            '0' | '1' => {
+                let index = self.debugger.breakpoints().iter().position(|b| {
+                    b.address == Some(address) && b.condition.is_none()
+                });
+                if let Some(index) = index {
+                    self.debugger.remove_breakpoint(index);
+                }
+            }
+            _ => {
+                let memory = self.machine.memory_mut();
+                let end = address.wrapping_add(length.max(1) - 1);
+                let index = memory.watchpoints().iter().position(|w| {
+                    w.start == address && w.end == end && w.kind == watch_kind(kind)
+                });
+                if let Some(index) = index {
+                    memory.remove_watchpoint(index);
+                }
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn resume(&mut self, instructions: u64) -> String {
+        let mut remaining = instructions;
+        while remaining > 0 {
+            let batch = remaining.min(INSTRUCTIONS_PER_POLL);
+            remaining -= batch;
+            match self.debugger.run(self.machine, batch) {
+                Some(Stop::Breakpoint(_)) => return stop_reply(SIGTRAP, "swbreak:;"),
+                Some(Stop::Watchpoint { hit, .. }) => {
+                    let kind = if hit.write { "watch" } else { "rwatch" };
+                    return stop_reply(SIGTRAP, &format!("{}:{:x};", kind, hit.address));
+                }
+                Some(Stop::Error(Error::UnknownOpcode(_))) => return stop_reply(SIGILL, ""),
+                Some(Stop::Error(_)) => return stop_reply(SIGSEGV, ""),
+                None => {}
+            }
+            if remaining > 0 && self.interrupted() {
+                return stop_reply(SIGINT, "");
+            }
+        }
+        stop_reply(SIGTRAP, "")
+    }
+
+    // Checks without blocking whether the debugger sent a break (Ctrl-C)
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let read = self.stream.read(&mut byte);
+        let _ = self.stream.set_nonblocking(false);
+        match read {
+            Ok(1) => byte[0] == 0x03,
+            _ => false,
+        }
+    }
+
+    // Returns `None` when the connection is closed
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = match checksum {
+                [Some(high), Some(low)] => decode_byte(&[high, low]) == Some(checksum_of(&data)),
+                _ => return Ok(None),
+            };
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&unescape(&data)).into_owned()));
+            }
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        for &byte in data {
+            if let b'#' | b'$' | b'}' | b'*' = byte {
+                packet.push(b'}');
+                packet.push(byte ^ 0x20);
+            } else {
+                packet.push(byte);
+            }
+        }
+        let checksum = checksum_of(&packet[1..]);
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        self.stream.write_all(&packet)?;
+        // The acknowledgement is not checked, TCP already guarantees delivery
+        if !self.no_ack {
+            self.read_byte()?;
+        }
+        Ok(())
+    }
+}
+
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n  <feature name=\"org.chip8.core\">\n",
+    );
+    for &(name, size) in REGISTERS.iter() {
+        let kind = match name {
+            "pc" => "code_ptr",
+            "i" => "data_ptr",
+            _ => "uint8",
+        };
+        xml += &format!(
+            "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>\n",
+            name,
+            size * 8,
+            kind
+        );
+    }
+    xml + "  </feature>\n</target>\n"
+}
+
+// Answers a qXfer read of `OFFSET,LENGTH`
+fn transfer(document: &str, range: &str) -> String {
+    let mut parts = range.splitn(2, ',');
+    let offset = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+    let length = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok());
+    match (offset, length) {
+        (Some(offset), Some(length)) if offset <= document.len() => {
+            let end = document.len().min(offset + length);
+            let prefix = if end == document.len() { "l" } else { "m" };
+            format!("{}{}", prefix, &document[offset..end])
+        }
+        _ => "E01".to_string(),
+    }
+}
+
+fn stop_reply(signal: u8, info: &str) -> String {
+    if info.is_empty() {
+        format!("S{:02x}", signal)
+    } else {
+        format!("T{:02x}{}", signal, info)
+    }
+}
+
+fn watch_kind(kind: char) -> WatchKind {
+    match kind {
+        '2' => WatchKind::Write,
+        '3' => WatchKind::Read,
+        _ => WatchKind::Access,
+    }
+}
+
+// Parses `TYPE,ADDRESS,KIND` of the Z and z packets
+fn parse_point(args: &str) -> Option<(char, u16, u16)> {
+    let mut parts = args.splitn(3, ',');
+    let kind = parts.next()?.chars().next().filter(|c| ('0'..='4').contains(c))?;
+    let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let length = u16::from_str_radix(parts.next()?.split(';').next()?, 16).ok()?;
+    Some((kind, address, length))
+}
+
+fn parse_address_length(args: &str) -> Option<(u16, u16)> {
+    let mut parts = args.splitn(2, ',');
+    let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let length = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, length))
+}
+
+fn encode_le(value: u16, size: usize) -> String {
+    (0..size).map(|n| format!("{:02x}", (value >> (8 * n)) as u8)).collect()
+}
+
+fn decode_le(hex: &str) -> Option<u16> {
+    let bytes = hex.as_bytes();
+    if bytes.is_empty() || !bytes.len().is_multiple_of(2) || bytes.len() > 4 {
+        return None;
+    }
+    let mut value = 0;
+    for (n, byte) in bytes.chunks(2).enumerate() {
+        value |= (decode_byte(byte)? as u16) << (8 * n);
+    }
+    Some(value)
+}
+
+fn decode_byte(hex: &[u8]) -> Option<u8> {
+    let text = ::std::str::from_utf8(hex).ok()?;
+    u8::from_str_radix(text, 16).ok()
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &byte in data {
+        if escaped {
+            result.push(byte ^ 0x20);
+            escaped = false;
+        } else if byte == b'}' {
+            escaped = true;
+        } else {
+            result.push(byte);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Sends the packets and returns the replies
+    fn client(address: ::std::net::SocketAddr, packets: &[&str]) -> Vec<String> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut replies = Vec::new();
+        for packet in packets {
+            let checksum = checksum_of(packet.as_bytes());
+            write!(stream, "${}#{:02x}", packet, checksum).unwrap();
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if reply.is_empty() => {}
+                    b'#' => break,
+                    b'$' => {}
+                    byte => reply.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum).unwrap();
+            stream.write_all(b"+").unwrap();
+            replies.push(String::from_utf8(reply).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn debugger_controls_machine_over_loopback() {
+        // LD V0, 0x05; LD I, 0x300; ADD V0, 0x01; LD [I], V0; JP 0x204
+        let rom = [0x60, 0x05, 0xA3, 0x00, 0x70, 0x01, 0xF0, 0x55, 0x12, 0x04];
+        let mut machine = Machine::new(&mut &rom[..]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            client(
+                address,
+                &[
+                    "qSupported:swbreak+",
+                    "qXfer:features:read:target.xml:0,20",
+                    "?",
+                    "s",
+                    "p11",
+                    "Z0,206,2",
+                    "c",
+                    "g",
+                    "z0,206,2",
+                    "Z2,300,1",
+                    "c",
+                    "m300,2",
+                    "M300,1:ff",
+                    "P0=42",
+                    "m300,1",
+                    "D",
+                ],
+            )
+        });
+        serve(&mut machine, &listener).unwrap();
+        let replies = client.join().unwrap();
+
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert!(replies[1].starts_with("m<?xml"));
+        assert_eq!(replies[1].len(), 1 + 0x20);
+        assert_eq!(replies[2], "S05");
+        assert_eq!(replies[3], "S05");
+        assert_eq!(replies[4], "0202");
+        assert_eq!(replies[5], "OK");
+        assert_eq!(replies[6], "T05swbreak:;");
+        assert_eq!(&replies[7][..2], "06");
+        assert_eq!(&replies[7][32..], "00030602000000");
+        assert_eq!(replies[9], "OK");
+        assert_eq!(replies[10], "T05watch:300;");
+        assert_eq!(replies[11], "0600");
+        assert_eq!(replies[12], "OK");
+        assert_eq!(replies[14], "ff");
+        assert_eq!(replies[15], "OK");
+        assert_eq!(machine.registers().v[0], 0x42);
+    }
+}
@@ -0,0 +1,183 @@
+use display::Framebuffer;
+use headless::Machine;
+use quirks::Quirks;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub frame: u32,
+    pub key: u8,
+    pub down: bool,
+}
+
+/// Scripted key presses. The text form has one event per line, consisting of
+/// the frame, the key as hex digit and `down` or `up`, e.g. `120 5 down`.
+/// Events take effect before the instructions of their frame are executed.
+/// Empty lines and lines starting with `#` are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct InputScript {
+    events: Vec<KeyEvent>,
+}
+
+impl InputScript {
+    pub fn new(mut events: Vec<KeyEvent>) -> InputScript {
+        events.sort_by_key(|e| e.frame);
+        InputScript { events }
+    }
+
+    pub fn events_at(&self, frame: u32) -> impl Iterator<Item = &KeyEvent> {
+        self.events.iter().filter(move |e| e.frame == frame)
+    }
+}
+
+impl FromStr for InputScript {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InputScript, String> {
+        let mut events = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("line {}: expected `FRAME KEY down|up`", number + 1);
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() != 3 {
+                return Err(error());
+            }
+            let frame = parts[0].parse().map_err(|_| error())?;
+            let key = match u8::from_str_radix(parts[1], 16) {
+                Ok(key) if key < 16 => key,
+                _ => return Err(error()),
+            };
+            let down = match parts[2] {
+                "down" => true,
+                "up" => false,
+                _ => return Err(error()),
+            };
+            events.push(KeyEvent { frame, key, down });
+        }
+        Ok(InputScript::new(events))
+    }
+}
+
+/// Runs a ROM headless for a fixed number of frames, so that the final
+/// screen can be compared against a known good result.
+#[derive(Clone, Debug, Default)]
+pub struct RomTest {
+    pub frames: u32,
+    pub seed: u32,
+    pub input: InputScript,
+    pub quirks: Quirks,
+    /// Instructions per frame, the default of the machine if not given
+    pub tick_rate: Option<u32>,
+}
+
+impl RomTest {
+    pub fn run(&self, rom: &[u8]) -> Result<Framebuffer, String> {
+        let mut machine = Machine::new(&mut &rom[..])?;
+        machine.seed_rng(self.seed);
+        machine.set_quirks(self.quirks);
+        if let Some(tick_rate) = self.tick_rate {
+            machine.set_tick_rate(tick_rate);
+        }
+        for frame in 0..self.frames {
+            for event in self.input.events_at(frame) {
+                machine.set_key(event.key, event.down);
+            }
+            machine.run_frame().map_err(|e| e.to_string())?;
+        }
+        Ok(machine.framebuffer().clone())
+    }
+}
+
+pub enum Expectation {
+    Hash(u64),
+    Image(Box<Framebuffer>),
+}
+
+impl Expectation {
+    pub fn check(&self, framebuffer: &Framebuffer) -> Result<(), String> {
+        match *self {
+            Expectation::Hash(hash) if framebuffer.hash() != hash => Err(format!(
+                "Framebuffer hash {:016x} does not match expected {:016x}",
+                framebuffer.hash(),
+                hash
+            )),
+            Expectation::Image(ref golden) if framebuffer.diff(golden) > 0 => Err(format!(
+                "{} pixels differ from the golden image",
+                framebuffer.diff(golden)
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use display::Display;
+
+    #[test]
+    fn input_scripts_are_sorted_by_frame() {
+        let script: InputScript = "# start the game\n\n30 f up\n 10 F down \n30 1 down"
+            .parse()
+            .unwrap();
+        let events: Vec<KeyEvent> = script.events_at(30).cloned().collect();
+        assert_eq!(
+            events,
+            vec![
+                KeyEvent { frame: 30, key: 0xF, down: false },
+                KeyEvent { frame: 30, key: 0x1, down: true },
+            ]
+        );
+        assert_eq!(script.events[0], KeyEvent { frame: 10, key: 0xF, down: true });
+        assert_eq!(script.events_at(20).count(), 0);
+    }
+
+    #[test]
+    fn invalid_input_lines_are_reported() {
+        for script in &["10 5", "10 5 down up", "x 5 down", "10 10 down", "10 5 pressed"] {
+            let error = format!("\n{}", script).parse::<InputScript>().unwrap_err();
+            assert!(error.starts_with("line 2:"), "{}: {}", script, error);
+        }
+    }
+
+    #[test]
+    fn expectations_compare_the_screen() {
+        let mut framebuffer = Framebuffer::new();
+        framebuffer.draw_sprite(0, 0, &[0xF0, 0x90]);
+        let golden = Framebuffer::from_pbm(&framebuffer.to_pbm()).unwrap();
+        assert_eq!(golden.diff(&framebuffer), 0);
+        assert!(Expectation::Hash(framebuffer.hash()).check(&framebuffer).is_ok());
+        assert!(Expectation::Image(Box::new(golden.clone())).check(&framebuffer).is_ok());
+
+        framebuffer.draw_sprite(0, 0, &[0x80]);
+        assert!(Expectation::Hash(golden.hash()).check(&framebuffer).is_err());
+        let error = Expectation::Image(Box::new(golden)).check(&framebuffer).unwrap_err();
+        assert_eq!(error, "1 pixels differ from the golden image");
+    }
+
+    #[test]
+    fn tests_run_with_quirks_and_tick_rate() {
+        // SHR V0, V1 with V0 = 2 and V1 = 8, then draws the digit in V0
+        let rom = [0x60, 0x02, 0x61, 0x08, 0x80, 0x16, 0xF0, 0x29, 0xD2, 0x25, 0x12, 0x0A];
+        let mut test = RomTest {
+            frames: 1,
+            tick_rate: Some(4),
+            ..RomTest::default()
+        };
+        assert_eq!(test.run(&rom).unwrap().diff(&Framebuffer::new()), 0);
+
+        test.tick_rate = None;
+        let shifted_vx = test.run(&rom).unwrap();
+        test.quirks = Quirks::VIP;
+        let shifted_vy = test.run(&rom).unwrap();
+        let mut one = Framebuffer::new();
+        one.draw_sprite(0, 0, &[0x20, 0x60, 0x20, 0x20, 0x70]);
+        let mut four = Framebuffer::new();
+        four.draw_sprite(0, 0, &[0x90, 0x90, 0xF0, 0x10, 0x10]);
+        assert_eq!(shifted_vx.diff(&one), 0);
+        assert_eq!(shifted_vy.diff(&four), 0);
+    }
+}
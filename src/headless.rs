@@ -0,0 +1,165 @@
+use cpu::{Cpu, Registers};
+use debugger::WatchedMemory;
+use display::Framebuffer;
+use error::Error;
+use font::Font;
+use keyboard::Keypad;
+use memory::{BlockMemory, MemoryMap, PROGRAM_START};
+use quirks::Quirks;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use trace::Tracer;
+
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+/// A CHIP-8 machine without window, sound or keyboard attached. It runs as
+/// fast as the host allows and is driven one frame at a time.
+pub struct Machine {
+    cpu: Cpu<WatchedMemory<BlockMemory>, Framebuffer, Keypad>,
+    // The memory as loaded, for a reset
+    initial_memory: BlockMemory,
+    instructions_per_frame: u32,
+    // Instructions executed in the current frame
+    cycles: u32,
+}
+
+impl Machine {
+    pub fn new<R: Read>(rom: &mut R) -> Result<Machine, String> {
+        let mut bytes = Vec::new();
+        rom.read_to_end(&mut bytes).map_err(|e| format!("Error reading ROM: {}", e))?;
+        Machine::from_bytes(&bytes, PROGRAM_START)
+    }
+
+    /// Creates a machine with the ROM loaded at `load_address`, where
+    /// execution starts.
+    pub fn from_bytes(rom: &[u8], load_address: u16) -> Result<Machine, String> {
+        let initial_memory = BlockMemory::with_rom(rom, load_address)?;
+        let memory = WatchedMemory::new(initial_memory.clone());
+        let sound_active = Arc::new(AtomicBool::new(false));
+        let mut cpu = Cpu::new(memory, Framebuffer::new(), Keypad::new(), sound_active);
+        cpu.set_entry_point(load_address);
+        Ok(Machine {
+            cpu,
+            initial_memory,
+            instructions_per_frame: INSTRUCTIONS_PER_FRAME,
+            cycles: 0,
+        })
+    }
+
+    pub fn seed_rng(&mut self, seed: u32) {
+        self.cpu.seed_rng(seed);
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+    }
+
+    /// Sets how many return addresses fit on the stack, 16 by default.
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.cpu.set_stack_depth(depth);
+    }
+
+    /// Keeps the stack in memory at `address`, see `VIP_STACK_ADDRESS`.
+    pub fn set_stack_address(&mut self, address: Option<u16>) {
+        self.cpu.set_stack_address(address);
+    }
+
+    /// Sets the layout of the memory. With `MemoryMap::Vip`, the stack
+    /// should be kept in memory as well, see `set_stack_address`.
+    pub fn set_memory_map(&mut self, map: MemoryMap) -> Result<(), String> {
+        self.initial_memory.set_memory_map(map)?;
+        self.cpu.memory_mut().inner_mut().set_memory_map(map)
+    }
+
+    /// Replaces the font, which is the one of Octo at 0x000 by default.
+    pub fn set_font(&mut self, font: &Font, address: u16) -> Result<(), String> {
+        self.initial_memory.set_font(font, address)?;
+        self.cpu.memory_mut().inner_mut().set_font(font, address)?;
+        self.cpu.set_font_address(address);
+        Ok(())
+    }
+
+    /// Sets the number of instructions executed per frame, 10 by default.
+    pub fn set_tick_rate(&mut self, instructions_per_frame: u32) {
+        self.instructions_per_frame = instructions_per_frame;
+    }
+
+    pub fn set_trace_output(&mut self, output: Box<dyn Write>) {
+        self.cpu.set_trace_output(output);
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.cpu.set_tracer(tracer);
+    }
+
+    pub fn set_key(&mut self, key: u8, down: bool) {
+        self.cpu.keyboard().set_key(key, down);
+    }
+
+    /// Executes the instructions of one frame and counts down the timers.
+    /// Returns whether the sound timer was running during the frame.
+    pub fn run_frame(&mut self) -> Result<bool, Error> {
+        while self.cycles < self.instructions_per_frame {
+            self.cpu.cycle()?;
+            self.cycles += 1;
+        }
+        self.cycles = 0;
+        let sound_active = self.cpu.sound_active();
+        self.cpu.decrement_timers();
+        Ok(sound_active)
+    }
+
+    /// Restarts the program with the ROM loaded into fresh memory. The
+    /// watchpoints are kept. The next frame starts right away.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let mut memory = WatchedMemory::new(self.initial_memory.clone());
+        for watchpoint in self.cpu.memory().watchpoints() {
+            memory.add_watchpoint(*watchpoint);
+        }
+        self.cycles = 0;
+        self.cpu.reset(memory)
+    }
+
+    /// Restarts the program, keeping the memory.
+    pub fn soft_reset(&mut self) -> Result<(), Error> {
+        self.cycles = 0;
+        self.cpu.soft_reset()
+    }
+
+    /// Executes a single instruction. The timers are counted down after
+    /// the last instruction of a frame.
+    pub fn step(&mut self) -> Result<(), Error> {
+        self.cpu.cycle()?;
+        self.cycles += 1;
+        if self.cycles >= self.instructions_per_frame {
+            self.cycles = 0;
+            self.cpu.decrement_timers();
+        }
+        Ok(())
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn registers(&self) -> &Registers {
+        self.cpu.registers()
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        self.cpu.registers_mut()
+    }
+
+    pub fn memory(&self) -> &WatchedMemory<BlockMemory> {
+        self.cpu.memory()
+    }
+
+    pub fn memory_mut(&mut self) -> &mut WatchedMemory<BlockMemory> {
+        self.cpu.memory_mut()
+    }
+
+    pub fn framebuffer(&self) -> &Framebuffer {
+        self.cpu.display()
+    }
+}
@@ -1,45 +1,210 @@
 use sdl2::EventPump;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use std::str::FromStr;
 
-const KEY_0: Keycode = Keycode::Comma;
-const KEY_1: Keycode = Keycode::Num7;
-const KEY_2: Keycode = Keycode::Num8;
-const KEY_3: Keycode = Keycode::Num9;
-const KEY_4: Keycode = Keycode::H;
-const KEY_5: Keycode = Keycode::G;
-const KEY_6: Keycode = Keycode::F;
-const KEY_7: Keycode = Keycode::N;
-const KEY_8: Keycode = Keycode::R;
-const KEY_9: Keycode = Keycode::T;
-const KEY_A: Keycode = Keycode::M;
-const KEY_B: Keycode = Keycode::Period;
-const KEY_C: Keycode = Keycode::Num0;
-const KEY_D: Keycode = Keycode::Q;
-const KEY_E: Keycode = Keycode::D;
-const KEY_F: Keycode = Keycode::J;
-
-pub struct Keyboard<'a> {
+const DEFAULT_KEYS: [Keycode; 16] = [
+    Keycode::Comma,
+    Keycode::Num7,
+    Keycode::Num8,
+    Keycode::Num9,
+    Keycode::H,
+    Keycode::G,
+    Keycode::F,
+    Keycode::N,
+    Keycode::R,
+    Keycode::T,
+    Keycode::M,
+    Keycode::Period,
+    Keycode::Num0,
+    Keycode::Q,
+    Keycode::D,
+    Keycode::J,
+];
+
+const HOTKEY_MENU: Keycode = Keycode::F1;
+const HOTKEY_RESET: Keycode = Keycode::F2;
+const HOTKEY_SOFT_RESET: Keycode = Keycode::F3;
+const HOTKEY_PAUSE: Keycode = Keycode::F5;
+const HOTKEY_STEP: Keycode = Keycode::F6;
+const HOTKEY_FRAME: Keycode = Keycode::F7;
+const HOTKEY_MUTE: Keycode = Keycode::F8;
+const HOTKEY_OVERLAY: Keycode = Keycode::F9;
+const HOTKEY_FAST_FORWARD: Keycode = Keycode::F10;
+const HOTKEY_SLOW_MOTION: Keycode = Keycode::F11;
+
+// How the keys for the instructions per frame change them, unless they are
+// mapped to CHIP-8 keys. On many layouts + shares a key with =.
+fn tick_rate_delta(key: Keycode) -> Option<i32> {
+    match key {
+        Keycode::KpPlus | Keycode::Plus | Keycode::Equals => Some(1),
+        Keycode::KpMinus | Keycode::Minus => Some(-1),
+        _ => None,
+    }
+}
+
+/// Emulator functions triggered from the keyboard which are handled outside
+/// of the CHIP-8 machine.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Hotkey {
+    /// The window was closed or the quit key pressed
+    Quit,
+    /// Back to the ROM menu when browsing
+    Menu,
+    /// Restart the program with the ROM loaded again
+    Reset,
+    /// Restart the program, keeping the memory
+    SoftReset,
+    ToggleMute,
+    ToggleOverlay,
+    TogglePause,
+    /// Execute a single instruction while paused
+    Step,
+    /// Run a single frame while paused
+    Frame,
+    ToggleFastForward,
+    ToggleSlowMotion,
+    /// Change the instructions per frame by the given amount
+    AdjustTickRate(i32),
+}
+
+/// Host keys of the CHIP-8 keys 0 to F. It is written as the 16 characters
+/// of the keys in that order, e.g. `x123qweasdzc4rfv`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keymap([Keycode; 16]);
+
+impl Keymap {
+    fn key(&self, keycode: Keycode) -> Option<u8> {
+        self.0.iter().position(|&k| k == keycode).map(|key| key as u8)
+    }
+
+    pub fn contains(&self, keycode: Keycode) -> bool {
+        self.key(keycode).is_some()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap(DEFAULT_KEYS)
+    }
+}
+
+impl FromStr for Keymap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Keymap, String> {
+        let invalid = || format!("Invalid keymap: {} (expected 16 keys for 0 to F)", s);
+        let chars: Vec<char> = s.to_lowercase().chars().collect();
+        if chars.len() != 16 {
+            return Err(invalid());
+        }
+        // SDL uses the ASCII codes of printable keys as key codes
+        let mut keys = DEFAULT_KEYS;
+        for (key, &c) in keys.iter_mut().zip(chars.iter()) {
+            *key = Keycode::from_i32(c as i32).ok_or_else(invalid)?;
+        }
+        Ok(Keymap(keys))
+    }
+}
+
+// Keys without a character that can quit the emulator. The function keys
+// are taken by the hotkeys.
+const NAMED_KEYS: [(&str, Keycode); 8] = [
+    ("Escape", Keycode::Escape),
+    ("Backspace", Keycode::Backspace),
+    ("Tab", Keycode::Tab),
+    ("Insert", Keycode::Insert),
+    ("Delete", Keycode::Delete),
+    ("Home", Keycode::Home),
+    ("End", Keycode::End),
+    ("Pause", Keycode::Pause),
+];
+
+/// The key that quits the emulator, Escape by default. It is written as a
+/// single character or as one of the names in `NAMED_KEYS`, e.g. `Backspace`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuitKey(Keycode);
+
+impl QuitKey {
+    pub fn keycode(&self) -> Keycode {
+        self.0
+    }
+
+    /// The name of the key as shown in the ROM menu.
+    pub fn name(&self) -> String {
+        match NAMED_KEYS.iter().find(|&&(_, keycode)| keycode == self.0) {
+            Some(&(name, _)) => name.to_string(),
+            None => ((self.0 as u8) as char).to_uppercase().collect(),
+        }
+    }
+}
+
+impl Default for QuitKey {
+    fn default() -> QuitKey {
+        QuitKey(Keycode::Escape)
+    }
+}
+
+impl FromStr for QuitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<QuitKey, String> {
+        let invalid = || format!("Invalid key: {}", s);
+        let named = NAMED_KEYS.iter().find(|&&(name, _)| name.eq_ignore_ascii_case(s));
+        if let Some(&(_, keycode)) = named {
+            return Ok(QuitKey(keycode));
+        }
+        let lowercase = s.to_lowercase();
+        let mut chars = lowercase.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) if c.is_ascii_graphic() => {
+                Keycode::from_i32(c as i32).map(QuitKey).ok_or_else(invalid)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+pub trait Keyboard {
+    fn is_pressed(&mut self, key: u8) -> bool;
+    fn any_key_pressed(&mut self) -> Option<u8>;
+    /// Forgets all keys held down, e.g. when the machine is reset.
+    fn release_all(&mut self);
+}
+
+/// State of the 16 keys of the CHIP-8 keypad without any input source
+/// attached. Keys are pressed and released with `set_key`.
+pub struct Keypad {
     key_statuses: [bool; 16],
     key_press_pending: bool,
-    event_pump: &'a mut EventPump,
 }
 
-impl<'a> Keyboard<'a> {
-    pub fn new(event_pump: &'a mut EventPump) -> Keyboard<'a> {
-        Keyboard {
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad {
             key_statuses: [false; 16],
             key_press_pending: false,
-            event_pump,
         }
     }
 
-    pub fn is_pressed(&mut self, key: u8) -> bool {
+    pub fn set_key(&mut self, key: u8, down: bool) {
+        self.key_statuses[key as usize] = down;
+        self.key_press_pending = true;
+    }
+
+    /// Returns which keys are held down without consuming a key press.
+    pub fn key_states(&self) -> [bool; 16] {
+        self.key_statuses
+    }
+}
+
+impl Keyboard for Keypad {
+    fn is_pressed(&mut self, key: u8) -> bool {
         self.key_press_pending = false;
         self.key_statuses[key as usize]
     }
 
-    pub fn any_key_pressed(&mut self) -> Option<u8> {
+    fn any_key_pressed(&mut self) -> Option<u8> {
         let mut ret: Option<u8> = None;
         if self.key_press_pending {
             let first_key = self.key_statuses.iter().position(|&x| x);
@@ -52,39 +217,92 @@ impl<'a> Keyboard<'a> {
         ret
     }
 
-    pub fn check_events(&mut self) {
+    fn release_all(&mut self) {
+        self.key_statuses = [false; 16];
+        self.key_press_pending = false;
+    }
+}
+
+/// Reads the keyboard of the host once per frame and passes the state of
+/// the mapped keys on to the `Keypad` of the machine.
+pub struct SdlInput<'a> {
+    keymap: Keymap,
+    quit_key: QuitKey,
+    event_pump: &'a mut EventPump,
+}
+
+impl<'a> SdlInput<'a> {
+    pub fn new(event_pump: &'a mut EventPump) -> SdlInput<'a> {
+        SdlInput {
+            keymap: Keymap::default(),
+            quit_key: QuitKey::default(),
+            event_pump,
+        }
+    }
+
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        self.keymap = keymap;
+    }
+
+    pub fn set_quit_key(&mut self, quit_key: QuitKey) {
+        self.quit_key = quit_key;
+    }
+
+    /// Handles the events since the last call, updating `keypad`. Returns
+    /// the hotkeys pressed, including `Hotkey::Quit`, which is up to the
+    /// caller to act on.
+    pub fn poll(&mut self, keypad: &mut Keypad) -> Vec<Hotkey> {
+        let mut hotkeys = Vec::new();
         while let Some(event) = self.event_pump.poll_event() {
             match event {
-                Event::Quit { .. } |
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => ::std::process::exit(0),
-                Event::KeyDown { keycode: Some(key), .. } => self.update_key_status(key, true),
-                Event::KeyUp { keycode: Some(key), .. } => self.update_key_status(key, false),
+                Event::Quit { .. } => hotkeys.push(Hotkey::Quit),
+                Event::KeyDown { keycode: Some(key), repeat: false, .. }
+                    if key == self.quit_key.keycode() => hotkeys.push(Hotkey::Quit),
+                Event::KeyDown { keycode: Some(HOTKEY_MENU), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::Menu)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_RESET), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::Reset)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_SOFT_RESET), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::SoftReset)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_MUTE), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::ToggleMute)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_OVERLAY), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::ToggleOverlay)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_PAUSE), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::TogglePause)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_STEP), .. } => {
+                    hotkeys.push(Hotkey::Step)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_FRAME), .. } => {
+                    hotkeys.push(Hotkey::Frame)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_FAST_FORWARD), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::ToggleFastForward)
+                }
+                Event::KeyDown { keycode: Some(HOTKEY_SLOW_MOTION), repeat: false, .. } => {
+                    hotkeys.push(Hotkey::ToggleSlowMotion)
+                }
+                Event::KeyDown { keycode: Some(key), .. } => {
+                    if let Some(key) = self.keymap.key(key) {
+                        keypad.set_key(key, true);
+                    } else if let Some(delta) = tick_rate_delta(key) {
+                        hotkeys.push(Hotkey::AdjustTickRate(delta));
+                    }
+                }
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if let Some(key) = self.keymap.key(key) {
+                        keypad.set_key(key, false);
+                    }
+                }
                 _ => {}
             }
         }
-    }
-
-    fn update_key_status(&mut self, keycode: Keycode, down: bool) {
-        let key = match keycode {
-            KEY_0 => 0x0,
-            KEY_1 => 0x1,
-            KEY_2 => 0x2,
-            KEY_3 => 0x3,
-            KEY_4 => 0x4,
-            KEY_5 => 0x5,
-            KEY_6 => 0x6,
-            KEY_7 => 0x7,
-            KEY_8 => 0x8,
-            KEY_9 => 0x9,
-            KEY_A => 0xA,
-            KEY_B => 0xB,
-            KEY_C => 0xC,
-            KEY_D => 0xD,
-            KEY_E => 0xE,
-            KEY_F => 0xF,
-            _ => return,
-        };
-        self.key_statuses[key] = down;
-        self.key_press_pending = true;
+        hotkeys
     }
 }
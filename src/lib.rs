@@ -1,36 +1,516 @@
+extern crate gif;
+extern crate hound;
 extern crate rand;
 extern crate sdl2;
+extern crate serde_json;
+extern crate sha1_smol;
+extern crate zip;
 
+#[cfg(test)]
+extern crate proptest;
+
+mod analysis;
+mod archive;
+mod asm;
 mod audio;
+mod browser;
+mod cartridge;
+mod config;
 mod cpu;
+mod debugger;
+mod disasm;
 mod display;
+mod error;
+mod font;
+mod gdbstub;
+mod harness;
+mod headless;
 mod memory;
 mod keyboard;
+mod loader;
+mod octo;
+mod overlay;
+mod quirks;
+mod romdb;
+mod speed;
+mod trace;
+
+pub use analysis::{analyze, BasicBlock, ControlFlowGraph, Edge, EdgeKind};
+pub use archive::{read_zip_entry, zip_entries};
+pub use asm::{assemble, AsmError};
+pub use audio::{BeeperConfig, Waveform};
+pub use cartridge::{read_cartridge, Cartridge};
+pub use config::Config;
+pub use cpu::Registers;
+pub use debugger::{
+    parse_number, Breakpoint, Condition, Debugger, Stop, WatchHit, WatchKind, WatchedMemory,
+    Watchpoint,
+};
+pub use disasm::{disassemble, disassemble_rom};
+pub use display::Framebuffer;
+pub use error::Error;
+pub use font::Font;
+pub use gdbstub::serve as serve_gdb;
+pub use memory::{
+    BlockMemory, Memory, MemoryMap, Region, RegionKind, ETI_660_PROGRAM_START, PROGRAM_START,
+    VIP_STACK_ADDRESS,
+};
+pub use loader::{load_rom_data, load_rom_file, ROM_EXTENSIONS};
+pub use harness::{Expectation, InputScript, KeyEvent, RomTest};
+pub use headless::Machine;
+pub use octo::{
+    compile as compile_octo, compile_runnable as compile_runnable_octo, OctoProgram, Target,
+};
+pub use quirks::Quirks;
+pub use romdb::{hash as rom_hash, RomDatabase, RomInfo};
+pub use trace::{first_divergence, parse_trace, OpcodeClass, TraceRecord, Tracer, TracerConfig};
 
+use keyboard::{Hotkey, Keypad, QuitKey};
+use speed::Speed;
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
 use std::fs::File;
+use std::io::{LineWriter, Read, Write};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
 use std::{thread, time};
 
-pub fn run(file: &mut File) {
-    let sdl_context = sdl2::init().unwrap();
+const FRAME_RATE: u32 = 60;
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+
+/// Runs the ROM with the settings from the ROM database, as far as they are
+/// not given in `config`. Fails if the ROM cannot be read or the output
+/// files cannot be created.
+pub fn run<R: Read>(rom: &mut R, config: &Config) -> Result<(), String> {
+    let mut bytes = Vec::new();
+    rom.read_to_end(&mut bytes)
+        .map_err(|e| format!("Error reading ROM: {}", e))?;
+    let (config, title) = rom_settings(&bytes, config, &rom_database(config));
+    if let Some(frames) = config.headless {
+        return run_headless(&bytes, &config, frames);
+    }
+    Frontend::new(&config)?.play(&bytes, &config, title.as_ref().map(|title| &title[..]))?;
+    Ok(())
+}
+
+/// Shows a menu of the ROMs in `dir` in the window. The chosen ROM runs
+/// until F1 is pressed, which brings back the menu, or until the user quits.
+pub fn browse(dir: &Path, config: &Config) -> Result<(), String> {
+    let database = rom_database(config);
+    let mut menu = browser::Menu::new(browser::list_roms(dir, &database)?);
+    let heading = format!("ROMs in {}", dir.display());
+    let mut frontend = Frontend::new(config)?;
+    frontend.browsing = true;
+    while frontend.choose(&mut menu, &heading) {
+        let rom = menu.selected().unwrap().clone();
+        let mut rom_config = config.clone();
+        let entry = rom.entry.as_ref().map(|entry| &entry[..]);
+        let result = load_rom_file(&rom.path, entry, &mut rom_config).and_then(|bytes| {
+            let (rom_config, _) = rom_settings(&bytes, &rom_config, &database);
+            frontend.play(&bytes, &rom_config, Some(&rom.title))
+        });
+        let quit = match result {
+            Ok(quit) => quit,
+            Err(e) => !frontend.show_error(&browser::error_lines(&rom.title, &e)),
+        };
+        if quit {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// The window, the sound and the input, which are kept while ROMs are started
+// from the menu one after the other
+struct Frontend {
+    display_context: display::DisplayContext,
+    event_pump: sdl2::EventPump,
+    _audio_device: audio::AudioDevice,
+    sound_active: Arc<AtomicBool>,
+    muted: Arc<AtomicBool>,
+    wav_writer: Option<audio::WavWriter>,
+    // The sound output for the WAV file, while recording
+    captured: Option<mpsc::Receiver<Vec<f32>>>,
+    quit_key: QuitKey,
+    browsing: bool,
+}
+
+impl Frontend {
+    fn new(config: &Config) -> Result<Frontend, String> {
+        let wav_writer = match config.record_audio {
+            Some(ref path) => Some(audio::create_wav_writer(path, audio::SAMPLE_RATE)?),
+            None => None,
+        };
+        let sdl_context = sdl2::init()?;
+
+        let (capture, captured) = match wav_writer {
+            Some(_) => {
+                let (capture, captured) = mpsc::channel();
+                (Some(capture), Some(captured))
+            }
+            None => (None, None),
+        };
+        let sound_active = Arc::new(AtomicBool::new(false));
+        let muted = Arc::new(AtomicBool::new(false));
+        let audio_device = audio::create_audio_device(
+            &sdl_context,
+            &config.beeper,
+            sound_active.clone(),
+            muted.clone(),
+            capture,
+        )?;
+        audio_device.resume();
+
+        Ok(Frontend {
+            display_context: display::DisplayContext::new(&sdl_context)?,
+            event_pump: sdl_context.event_pump()?,
+            _audio_device: audio_device,
+            sound_active,
+            muted,
+            wav_writer,
+            captured,
+            quit_key: config.quit_key,
+            browsing: false,
+        })
+    }
+
+    // Shows the menu until a ROM is chosen. Returns false if the user quits.
+    fn choose(&mut self, menu: &mut browser::Menu, heading: &str) -> bool {
+        let mut display = display::SdlDisplay::new(&mut self.display_context);
+        display.set_title("chip8");
+        let quit_key = self.quit_key.keycode();
+        loop {
+            for event in self.event_pump.poll_iter() {
+                match event {
+                    Event::Quit { .. } => return false,
+                    Event::KeyDown { keycode: Some(key), .. } if key == quit_key => return false,
+                    Event::KeyDown { keycode: Some(Keycode::Return), .. }
+                        if menu.selected().is_some() => return true,
+                    Event::KeyDown { keycode: Some(Keycode::Up), .. } => menu.move_by(-1),
+                    Event::KeyDown { keycode: Some(Keycode::Down), .. } => menu.move_by(1),
+                    Event::KeyDown { keycode: Some(Keycode::PageUp), .. } => {
+                        menu.move_by(-browser::Menu::page_size())
+                    }
+                    Event::KeyDown { keycode: Some(Keycode::PageDown), .. } => {
+                        menu.move_by(browser::Menu::page_size())
+                    }
+                    _ => {}
+                }
+            }
+            display.show_text(&menu.lines(heading, &self.quit_key.name()));
+            record_captured(&mut self.wav_writer, &mut self.captured);
+            thread::sleep(time::Duration::from_millis(17));
+        }
+    }
+
+    // Shows why a ROM cannot be started until a key is pressed. Returns
+    // false if the user closes the window.
+    fn show_error(&mut self, lines: &[String]) -> bool {
+        let mut display = display::SdlDisplay::new(&mut self.display_context);
+        loop {
+            for event in self.event_pump.poll_iter() {
+                match event {
+                    Event::Quit { .. } => return false,
+                    Event::KeyDown { .. } => return true,
+                    _ => {}
+                }
+            }
+            display.show_text(lines);
+            record_captured(&mut self.wav_writer, &mut self.captured);
+            thread::sleep(time::Duration::from_millis(17));
+        }
+    }
+
+    // Runs the ROM until an error occurs, the user quits or, when browsing,
+    // until the user goes back to the menu. Returns true if the user quits,
+    // and an error if the ROM cannot be started with the settings or stops
+    // with an error.
+    fn play(&mut self, rom: &[u8], config: &Config, title: Option<&str>) -> Result<bool, String> {
+        let load_address = config.load_address.unwrap_or(memory::PROGRAM_START);
+        let mut memory = BlockMemory::with_rom(rom, load_address)?;
+        memory.set_memory_map(config.memory_map)?;
+        memory.set_font(&config.font, config.font_address)?;
+        let (stack_depth, stack_address) = stack(config)?;
+        let trace = match config.trace {
+            Some(ref path) => Some(create_trace(path)?),
+            None => None,
+        };
+        let tracer = if config.tracer.is_enabled() {
+            Some(create_tracer(&config.tracer)?)
+        } else {
+            None
+        };
+        let initial_memory = memory.clone();
+
+        let mut display = display::SdlDisplay::new(&mut self.display_context);
+        display.set_palette(config.palette);
+        let title = match title {
+            Some(title) => format!("chip8 - {}", title),
+            None => "chip8".to_string(),
+        };
+
+        let mut input = keyboard::SdlInput::new(&mut self.event_pump);
+        input.set_keymap(config.keymap);
+        input.set_quit_key(config.quit_key);
+
+        let mut cpu = cpu::Cpu::new(memory, display, Keypad::new(), self.sound_active.clone());
+        cpu.set_entry_point(load_address);
+        cpu.set_font_address(config.font_address);
+        if let Some(depth) = stack_depth {
+            cpu.set_stack_depth(depth);
+        }
+        cpu.set_stack_address(stack_address);
+        cpu.set_quirks(config.quirks);
+        if let Some(seed) = config.seed {
+            cpu.seed_rng(seed);
+        }
+        if let Some(trace) = trace {
+            cpu.set_trace_output(trace);
+        }
+        if let Some(tracer) = tracer {
+            cpu.set_tracer(tracer);
+        }
+        let mut speed = Speed::new(config.tick_rate.unwrap_or(INSTRUCTIONS_PER_FRAME));
+        cpu.display_mut().set_title(&format!("{} ({})", title, speed));
+        let mut show_overlay = false;
+        let mut quit = false;
+        let mut error = None;
+        'frames: loop {
+            let mut frames = speed.frames();
+            // Single instructions executed while paused
+            let mut steps = 0;
+            // All instructions of a frame see the same keys
+            for hotkey in input.poll(cpu.keyboard()) {
+                match hotkey {
+                    Hotkey::Quit => {
+                        quit = true;
+                        break 'frames;
+                    }
+                    Hotkey::Menu if self.browsing => break 'frames,
+                    Hotkey::Menu => {}
+                    Hotkey::Reset => {
+                        if let Err(e) = cpu.reset(initial_memory.clone()) {
+                            error = Some(format!("Error restarting the program: {}", e));
+                            break 'frames;
+                        }
+                    }
+                    Hotkey::SoftReset => {
+                        if let Err(e) = cpu.soft_reset() {
+                            error = Some(format!("Error restarting the program: {}", e));
+                            break 'frames;
+                        }
+                    }
+                    Hotkey::ToggleMute => {
+                        self.muted.fetch_xor(true, Ordering::Relaxed);
+                    }
+                    Hotkey::ToggleOverlay => show_overlay = !show_overlay,
+                    Hotkey::TogglePause => speed.toggle_pause(),
+                    Hotkey::Step if speed.is_paused() => steps += 1,
+                    Hotkey::Frame if speed.is_paused() => frames += 1,
+                    Hotkey::Step | Hotkey::Frame => {}
+                    Hotkey::ToggleFastForward => speed.toggle_fast_forward(),
+                    Hotkey::ToggleSlowMotion => speed.toggle_slow_motion(),
+                    Hotkey::AdjustTickRate(delta) => speed.adjust_tick_rate(delta),
+                }
+                let title = format!("{} ({})", title, speed);
+                cpu.display_mut().set_title(&title);
+            }
+            for _ in 0..steps {
+                if let Err(e) = cpu.cycle() {
+                    error = Some(format!("Error at {:03X}: {}", cpu.pc(), e));
+                    break 'frames;
+                }
+            }
+            for _ in 0..frames {
+                for _ in 0..speed.tick_rate() {
+                    if let Err(e) = cpu.cycle() {
+                        error = Some(format!("Error at {:03X}: {}", cpu.pc(), e));
+                        break 'frames;
+                    }
+                }
+                cpu.decrement_timers();
+            }
+            thread::sleep(time::Duration::from_millis(17));
+            let overlay = if show_overlay {
+                let keys = cpu.keyboard().key_states();
+                let paused = speed.is_paused();
+                Some(overlay::describe(cpu.registers(), cpu.memory(), &keys, paused))
+            } else {
+                None
+            };
+            cpu.display_mut().set_overlay(overlay);
+            cpu.redraw_display();
+            record_captured(&mut self.wav_writer, &mut self.captured);
+        }
+        // The next ROM starts with a silent beeper and a window without panel
+        self.sound_active.store(false, Ordering::Relaxed);
+        cpu.display_mut().set_overlay(None);
+        match error {
+            Some(e) => Err(e),
+            None => Ok(quit),
+        }
+    }
+}
+
+// Writes the sound output since the last call to the WAV file. Recording
+// stops at the first error, e.g. a full disk, while the game goes on. The
+// receiver is dropped as well, which makes the audio callback stop capturing.
+fn record_captured(
+    wav_writer: &mut Option<audio::WavWriter>,
+    captured: &mut Option<mpsc::Receiver<Vec<f32>>>,
+) {
+    let result = match (wav_writer.as_mut(), captured.as_ref()) {
+        (Some(writer), Some(captured)) => captured
+            .try_iter()
+            .try_for_each(|samples| audio::write_samples(writer, &samples)),
+        _ => return,
+    };
+    if let Err(e) = result {
+        println!("Error recording audio, recording stopped: {}", e);
+        *wav_writer = None;
+        *captured = None;
+    }
+}
 
-    let audio_device = audio::create_audio_device(&sdl_context);
+// Runs the ROM as fast as possible without any input. The sound output is
+// rendered from the state of the sound timer in each frame, which makes it
+// independent of the timing of the host.
+fn run_headless(rom: &[u8], config: &Config, frames: u32) -> Result<(), String> {
+    let load_address = config.load_address.unwrap_or(memory::PROGRAM_START);
+    let mut machine = Machine::from_bytes(rom, load_address)?;
+    machine.set_quirks(config.quirks);
+    machine.set_memory_map(config.memory_map)?;
+    machine.set_font(&config.font, config.font_address)?;
+    let (stack_depth, stack_address) = stack(config)?;
+    if let Some(depth) = stack_depth {
+        machine.set_stack_depth(depth);
+    }
+    machine.set_stack_address(stack_address);
+    if let Some(tick_rate) = config.tick_rate {
+        machine.set_tick_rate(tick_rate);
+    }
+    if let Some(seed) = config.seed {
+        machine.seed_rng(seed);
+    }
+    if let Some(ref path) = config.trace {
+        machine.set_trace_output(create_trace(path)?);
+    }
+    if config.tracer.is_enabled() {
+        machine.set_tracer(create_tracer(&config.tracer)?);
+    }
+    // The sound up to an error is still written
+    let mut error = None;
+    let mut timeline = Vec::with_capacity(frames as usize);
+    for _ in 0..frames {
+        match machine.run_frame() {
+            Ok(sound_active) => timeline.push(sound_active),
+            Err(e) => {
+                error = Some(format!("Error at {:03X}: {}", machine.pc(), e));
+                break;
+            }
+        }
+    }
 
-    let mut display_context = display::DisplayContext::new(&sdl_context);
-    let display = display::Display::new(&mut display_context);
+    if let Some(ref path) = config.record_audio {
+        let samples =
+            audio::render_timeline(&config.beeper, audio::SAMPLE_RATE, FRAME_RATE, &timeline);
+        let mut writer = audio::create_wav_writer(path, audio::SAMPLE_RATE)?;
+        audio::write_samples(&mut writer, &samples)
+            .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+    }
+    match error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
 
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    let keyboard = keyboard::Keyboard::new(&mut event_pump);
+// The depth of the stack, if not the default, and where the stack is kept
+// in memory, if at all. The stack of the COSMAC VIP is 12 entries deep by
+// default and cannot grow beyond the V registers that follow it.
+fn stack(config: &Config) -> Result<(Option<usize>, Option<u16>), String> {
+    if !config.vip_stack && config.memory_map != memory::MemoryMap::Vip {
+        return Ok((config.stack_depth, None));
+    }
+    match config.stack_depth.unwrap_or(memory::VIP_STACK_DEPTH) {
+        depth if depth > memory::VIP_STACK_CAPACITY => Err(format!(
+            "Stack depth {} too large for the stack of the COSMAC VIP (at most {})",
+            depth,
+            memory::VIP_STACK_CAPACITY
+        )),
+        depth => Ok((Some(depth), Some(memory::VIP_STACK_ADDRESS))),
+    }
+}
 
-    let mut memory = memory::BlockMemory::new();
-    memory.load_rom(file);
+// The bundled database with the user's entries on top. A database given
+// explicitly must exist.
+fn rom_database(config: &Config) -> RomDatabase {
+    let mut database = RomDatabase::bundled();
+    let path = match config.rom_db {
+        Some(ref path) => Some(path.clone()),
+        None => romdb::default_path().filter(|path| path.exists()),
+    };
+    if let Some(path) = path {
+        if let Err(e) = database.load_file(&path) {
+            println!("{}", e);
+        }
+    }
+    database
+}
 
-    let mut cpu = cpu::Cpu::new(memory, display, keyboard, audio_device);
-    loop {
-        for _ in 0..10 {
-            cpu.cycle();
+// Applies the settings of the ROM from the database and returns its title
+fn rom_settings(rom: &[u8], config: &Config, database: &RomDatabase) -> (Config, Option<String>) {
+    let mut config = config.clone();
+    let info = match database.lookup(rom) {
+        Some(info) => info,
+        None => return (config, None),
+    };
+    info.apply(&mut config);
+    match info.platform {
+        Some(platform) if platform != Target::Chip8 => {
+            println!("Warning: {} ROMs are not fully supported", platform)
         }
-        thread::sleep(time::Duration::from_millis(17));
-        cpu.decrement_timers();
-        cpu.redraw_display();
+        _ => {}
+    }
+    (config, info.title.clone())
+}
+
+// Every record is written immediately, so that the trace is complete even if
+// the emulator is terminated
+fn create_trace(path: &Path) -> Result<Box<dyn Write>, String> {
+    let file =
+        File::create(path).map_err(|e| format!("Error creating {}: {}", path.display(), e))?;
+    Ok(Box::new(LineWriter::new(file)))
+}
+
+// Without a file, the log goes to stderr, which is the natural place for the
+// instructions dumped when an error occurs
+fn create_tracer(config: &TracerConfig) -> Result<Tracer, String> {
+    let output: Box<dyn Write> = match config.output {
+        Some(ref path) if path != Path::new("-") => create_trace(path)?,
+        _ => Box::new(io::stderr()),
+    };
+    Ok(Tracer::new(output, config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vip_stack_limits_the_stack_depth() {
+        let mut config = Config::default();
+        assert_eq!(stack(&config), Ok((None, None)));
+        config.set("vip-stack", "true").unwrap();
+        assert_eq!(stack(&config), Ok((Some(12), Some(memory::VIP_STACK_ADDRESS))));
+        config.set("stack-depth", "48").unwrap();
+        assert!(stack(&config).is_err());
+        config.set("stack-depth", "24").unwrap();
+        assert_eq!(stack(&config), Ok((Some(24), Some(memory::VIP_STACK_ADDRESS))));
+        config.set("vip-stack", "false").unwrap();
+        config.set("stack-depth", "48").unwrap();
+        assert_eq!(stack(&config), Ok((Some(48), None)));
     }
 }
@@ -0,0 +1,86 @@
+use archive::{read_zip_entry, zip_entries};
+use cartridge::read_cartridge;
+use config::Config;
+use octo::{compile_runnable, Target};
+use std::fs;
+use std::path::Path;
+
+/// Extensions of the files that `load_rom_file` understands.
+pub const ROM_EXTENSIONS: [&str; 7] = ["ch8", "c8", "sc8", "xo8", "8o", "gif", "zip"];
+
+/// Reads a ROM from a file. Octo source (`.8o`) and cartridges (`.gif`) are
+/// compiled, from zip archives the file `entry` is loaded, which may be left
+/// out if the archive holds only one file. Settings that come with the file
+/// are applied to `config` unless the user gave them.
+pub fn load_rom_file(
+    path: &Path,
+    entry: Option<&str>,
+    config: &mut Config,
+) -> Result<Vec<u8>, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Error opening {}: {}", path.display(), e))?;
+    load_rom_data(&path.to_string_lossy(), bytes, entry, config)
+}
+
+/// Like `load_rom_file` for the contents of a file with the given name.
+pub fn load_rom_data(
+    name: &str,
+    bytes: Vec<u8>,
+    entry: Option<&str>,
+    config: &mut Config,
+) -> Result<Vec<u8>, String> {
+    let error = |e: String| format!("{}: {}", name, e);
+    match &extension(name)[..] {
+        "zip" => {
+            let entry = match entry {
+                Some(entry) => entry.to_string(),
+                None => {
+                    let mut names = zip_entries(&bytes).map_err(error)?;
+                    if names.len() != 1 {
+                        return Err(error(format!(
+                            "The archive holds {} files, one of them must be chosen",
+                            names.len()
+                        )));
+                    }
+                    names.remove(0)
+                }
+            };
+            let rom = read_zip_entry(&bytes, &entry).map_err(error)?;
+            load_rom_data(&entry, rom, None, config)
+        }
+        "gif" => {
+            let cartridge = read_cartridge(&bytes).map_err(error)?;
+            cartridge.info.apply(config);
+            let target = config.target.or(cartridge.info.platform).unwrap_or(Target::Chip8);
+            compile_source(name, &cartridge.source, target)
+        }
+        "8o" => {
+            let source = String::from_utf8(bytes)
+                .map_err(|_| error("Source is not valid UTF-8".to_string()))?;
+            compile_source(name, &source, config.target.unwrap_or(Target::Chip8))
+        }
+        _ => Ok(bytes),
+    }
+}
+
+/// The extension of a file name in lower case, empty if there is none.
+pub fn extension(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+// Compile errors start with the line number, like `pong.8o:12: ...`. The
+// emulator has neither the instructions nor the 64 KB of memory of XO-CHIP,
+// and of the SCHIP instructions only the big digits.
+fn compile_source(name: &str, source: &str, target: Target) -> Result<Vec<u8>, String> {
+    if target == Target::XoChip {
+        return Err(format!(
+            "{}: XO-CHIP programs cannot be run, only CHIP-8 and SCHIP programs",
+            name
+        ));
+    }
+    compile_runnable(source, target)
+        .map(|program| program.rom)
+        .map_err(|e| format!("{}:{}", name, e))
+}
@@ -1,66 +1,407 @@
-use std::fs::File;
+use error::Error;
+use font::Font;
 use std::io::Read;
+use std::str::FromStr;
+
+const MEMORY_SIZE: usize = 4096;
+
+/// Where programs are loaded on most interpreters.
+pub const PROGRAM_START: u16 = 0x200;
+/// Where programs are loaded on the ETI-660.
+pub const ETI_660_PROGRAM_START: u16 = 0x600;
+/// Where the COSMAC VIP interpreter keeps the stack.
+pub const VIP_STACK_ADDRESS: u16 = 0xEA0;
+/// How many return addresses the COSMAC VIP interpreter allows.
+pub const VIP_STACK_DEPTH: usize = 12;
+/// How many return addresses fit between `VIP_STACK_ADDRESS` and V0 to VF.
+pub const VIP_STACK_CAPACITY: usize = 24;
 
 pub trait Memory {
-    fn read_byte(&self, address: u16) -> u8;
-    fn write_byte(&mut self, address: u16, value: u8);
-    fn read_block(&self, address: u16, size: usize) -> &[u8];
+    fn read_byte(&self, address: u16) -> Result<u8, Error>;
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error>;
+    fn read_block(&self, address: u16, size: usize) -> Result<&[u8], Error>;
+
+    /// The regions of the memory map that mirror the state of the machine.
+    /// Plain memory has none.
+    fn regions(&self) -> &[Region] {
+        &[]
+    }
+
+    /// The kinds of regions the program wrote to since the last call.
+    fn take_written_regions(&mut self) -> Vec<RegionKind> {
+        Vec::new()
+    }
+
+    /// Copies the state of the machine into a region. Unlike `write_byte`,
+    /// this does not count as a write of the program.
+    fn mirror(&mut self, address: u16, bytes: &[u8]) -> Result<(), Error> {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.write_byte(address + offset as u16, byte)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a region back into the state of the machine. Unlike
+    /// `read_block`, this does not count as a read of the program.
+    fn read_mirror(&self, address: u16, size: usize) -> Result<&[u8], Error> {
+        self.read_block(address, size)
+    }
+}
+
+/// What a region of the memory map holds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegionKind {
+    /// The interpreter, which programs cannot overwrite
+    Interpreter,
+    /// The return addresses, see `VIP_STACK_ADDRESS`
+    Stack,
+    /// The registers V0 to VF
+    Variables,
+    /// The pixels of the screen, 8 per byte with the leftmost in the most
+    /// significant bit, row by row
+    DisplayRam,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Region {
+    pub start: u16,
+    pub size: u16,
+    pub kind: RegionKind,
+}
+
+impl Region {
+    pub fn contains(&self, address: u16) -> bool {
+        address >= self.start && address - self.start < self.size
+    }
+}
+
+/// The layout of the memory.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum MemoryMap {
+    /// All memory is free for the program
+    #[default]
+    Plain,
+    /// The COSMAC VIP with 4 KB: the interpreter at 0x000, the stack at
+    /// 0xEA0, V0 to VF at 0xEF0 and the display at 0xF00. Programs can
+    /// read and change the registers and the screen through memory.
+    Vip,
 }
 
+impl MemoryMap {
+    pub fn regions(self) -> Vec<Region> {
+        let region = |start, size, kind| Region { start, size, kind };
+        match self {
+            MemoryMap::Plain => Vec::new(),
+            MemoryMap::Vip => vec![
+                region(0x000, 0x200, RegionKind::Interpreter),
+                region(VIP_STACK_ADDRESS, 2 * VIP_STACK_CAPACITY as u16, RegionKind::Stack),
+                region(0xEF0, 0x10, RegionKind::Variables),
+                region(0xF00, 0x100, RegionKind::DisplayRam),
+            ],
+        }
+    }
+}
+
+impl FromStr for MemoryMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MemoryMap, String> {
+        match &s.to_lowercase()[..] {
+            "plain" => Ok(MemoryMap::Plain),
+            "vip" => Ok(MemoryMap::Vip),
+            _ => Err(format!("Unknown memory map: {} (expected plain or vip)", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct BlockMemory {
-    memory: [u8; 4096],
+    memory: [u8; MEMORY_SIZE],
+    load_address: u16,
+    rom_size: usize,
+    // Address and size of the font
+    font_address: u16,
+    font_size: usize,
+    regions: Vec<Region>,
+    written: Vec<RegionKind>,
 }
 
 impl BlockMemory {
     pub fn new() -> BlockMemory {
-        let mut memory = BlockMemory { memory: [0; 4096] };
-        memory.initialize_sprites();
+        let mut memory = BlockMemory {
+            memory: [0; MEMORY_SIZE],
+            load_address: PROGRAM_START,
+            rom_size: 0,
+            font_address: 0,
+            font_size: 0,
+            regions: Vec::new(),
+            written: Vec::new(),
+        };
+        memory.set_font(&Font::default(), 0).unwrap();
         memory
     }
 
-    pub fn load_rom(&mut self, file: &mut File) {
+    /// Memory with the font and the ROM loaded at `load_address`.
+    pub fn with_rom(rom: &[u8], load_address: u16) -> Result<BlockMemory, String> {
+        let mut memory = BlockMemory::new();
+        memory.set_load_address(load_address);
+        memory.load_rom_bytes(rom)?;
+        Ok(memory)
+    }
+
+    /// Replaces the font, which is at 0x000 by default. The memory of the
+    /// previous font is cleared. Fails if the font would overlap the ROM or
+    /// a region of the memory map other than the interpreter, which is
+    /// where the COSMAC VIP keeps its font.
+    pub fn set_font(&mut self, font: &Font, address: u16) -> Result<(), String> {
+        let bytes = font.bytes();
+        let start = address as usize;
+        let end = start + bytes.len();
+        if end > MEMORY_SIZE {
+            return Err(format!("The font does not fit at 0x{:03X}", address));
+        }
+        let rom_start = self.load_address as usize;
+        if start < rom_start + self.rom_size && rom_start < end {
+            return Err(format!("The font at 0x{:03X} overlaps the ROM", address));
+        }
+        let overlaps = |region: &Region| {
+            region.kind != RegionKind::Interpreter
+                && start < region.start as usize + region.size as usize
+                && (region.start as usize) < end
+        };
+        if self.regions.iter().any(overlaps) {
+            return Err(format!("The font at 0x{:03X} overlaps the memory map", address));
+        }
+        let old = self.font_address as usize;
+        for byte in &mut self.memory[old..old + self.font_size] {
+            *byte = 0;
+        }
+        self.memory[start..start + bytes.len()].copy_from_slice(&bytes);
+        self.font_address = address;
+        self.font_size = bytes.len();
+        Ok(())
+    }
+
+    /// Sets the layout of the memory. Fails if the ROM overlaps with one of
+    /// the regions.
+    pub fn set_memory_map(&mut self, map: MemoryMap) -> Result<(), String> {
+        let previous = ::std::mem::replace(&mut self.regions, map.regions());
+        if let Err(e) = self.check_rom_size(self.rom_size) {
+            self.regions = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Sets where ROMs are loaded, 0x200 by default.
+    pub fn set_load_address(&mut self, address: u16) {
+        self.load_address = address;
+    }
+
+    pub fn load_address(&self) -> u16 {
+        self.load_address
+    }
+
+    /// Copies the ROM to the load address. Fails without changing the
+    /// memory if the ROM does not fit.
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), String> {
+        self.check_rom_size(rom.len())?;
+        let start = self.load_address as usize;
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        self.rom_size = rom.len();
+        // A ROM loaded over the font replaces it, so that moving the font
+        // later does not clear part of the ROM
+        let font_start = self.font_address as usize;
+        if start < font_start + self.font_size && font_start < start + rom.len() {
+            self.font_size = 0;
+        }
+        Ok(())
+    }
+
+    pub fn load_rom_from<R: Read>(&mut self, rom: &mut R) -> Result<(), String> {
         let mut bytes = Vec::new();
-        file.read_to_end(&mut bytes).unwrap();
-        let size = bytes.len();
-        for i in 0..size {
-            self.memory[0x200 + i] = bytes[i];
+        rom.read_to_end(&mut bytes).map_err(|e| format!("Error reading ROM: {}", e))?;
+        self.load_rom_bytes(&bytes)
+    }
+}
+
+impl BlockMemory {
+    // The ROM must start in memory outside of the regions of the memory map
+    // and end before the next region
+    fn check_rom_size(&self, size: usize) -> Result<(), String> {
+        let start = self.load_address as usize;
+        if start >= MEMORY_SIZE {
+            return Err(format!("Load address 0x{:03X} is outside of the memory", start));
+        }
+        if self.regions.iter().any(|region| region.contains(self.load_address)) {
+            return Err(format!("Load address 0x{:03X} is reserved by the memory map", start));
+        }
+        let end = self
+            .regions
+            .iter()
+            .map(|region| region.start as usize)
+            .filter(|&region_start| region_start >= start)
+            .min()
+            .unwrap_or(MEMORY_SIZE);
+        let max_size = end.saturating_sub(start);
+        if size > max_size {
+            return Err(format!(
+                "ROM too large: {} bytes, at most {} bytes fit at 0x{:03X}",
+                size, max_size, start
+            ));
         }
+        Ok(())
     }
+}
 
-    fn initialize_sprites(&mut self) {
-        let numbers: [u8; 0x50] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0,
-            0x20, 0x60, 0x20, 0x20, 0x70,
-            0xF0, 0x10, 0xF0, 0x80, 0xF0,
-            0xF0, 0x10, 0xF0, 0x10, 0xF0,
-            0x90, 0x90, 0xF0, 0x10, 0x10,
-            0xF0, 0x80, 0xF0, 0x10, 0xF0,
-            0xF0, 0x80, 0xF0, 0x90, 0xF0,
-            0xF0, 0x10, 0x20, 0x40, 0x40,
-            0xF0, 0x90, 0xF0, 0x90, 0xF0,
-            0xF0, 0x90, 0xF0, 0x10, 0xF0,
-            0xF0, 0x90, 0xF0, 0x90, 0x90,
-            0xE0, 0x90, 0xE0, 0x90, 0xE0,
-            0xF0, 0x80, 0x80, 0x80, 0xF0,
-            0xE0, 0x90, 0x90, 0x90, 0xE0,
-            0xF0, 0x80, 0xF0, 0x80, 0xF0,
-            0xF0, 0x80, 0xF0, 0x80, 0x80,
-        ];
-        self.memory[..0x50].clone_from_slice(&numbers);
+impl Default for BlockMemory {
+    fn default() -> BlockMemory {
+        BlockMemory::new()
     }
 }
 
 impl Memory for BlockMemory {
-    fn read_byte(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+    fn read_byte(&self, address: u16) -> Result<u8, Error> {
+        self.memory
+            .get(address as usize)
+            .cloned()
+            .ok_or(Error::AddressOutOfBounds(address))
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) -> Result<(), Error> {
+        if let Some(region) = self.regions.iter().find(|region| region.contains(address)) {
+            if region.kind == RegionKind::Interpreter {
+                return Err(Error::WriteProtected(address));
+            }
+            if !self.written.contains(&region.kind) {
+                self.written.push(region.kind);
+            }
+        }
+        match self.memory.get_mut(address as usize) {
+            Some(byte) => {
+                *byte = value;
+                Ok(())
+            }
+            None => Err(Error::AddressOutOfBounds(address)),
+        }
+    }
+
+    fn read_block(&self, address: u16, size: usize) -> Result<&[u8], Error> {
+        let start = address as usize;
+        self.memory
+            .get(start..(start + size))
+            .ok_or(Error::AddressOutOfBounds(address))
+    }
+
+    fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    fn take_written_regions(&mut self) -> Vec<RegionKind> {
+        ::std::mem::take(&mut self.written)
+    }
+
+    fn mirror(&mut self, address: u16, bytes: &[u8]) -> Result<(), Error> {
+        let start = address as usize;
+        match self.memory.get_mut(start..start + bytes.len()) {
+            Some(block) => {
+                block.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(Error::AddressOutOfBounds(address)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_rom_at_load_address() {
+        let mut memory = BlockMemory::new();
+        memory.set_load_address(ETI_660_PROGRAM_START);
+        memory.load_rom_from(&mut &[0x12, 0x34][..]).unwrap();
+        assert_eq!(memory.read_block(0x600, 2), Ok(&[0x12, 0x34][..]));
+        assert_eq!(memory.read_byte(0x200), Ok(0));
+    }
+
+    #[test]
+    fn replaces_the_font() {
+        let mut memory = BlockMemory::new();
+        assert_eq!(memory.read_block(0x000, 5), Ok(&[0xF0, 0x90, 0x90, 0x90, 0xF0][..]));
+        memory.set_font(&Font::vip(), 0x050).unwrap();
+        assert_eq!(memory.read_byte(0x000), Ok(0));
+        assert_eq!(memory.read_block(0x055, 2), Ok(&[0x60, 0x20][..]));
+        assert!(memory.set_font(&Font::octo(), 0xF80).is_err());
+    }
+
+    #[test]
+    fn rejects_fonts_overlapping_the_rom_or_the_memory_map() {
+        let mut memory = BlockMemory::with_rom(&[0x12, 0x00], PROGRAM_START).unwrap();
+        assert_eq!(
+            memory.set_font(&Font::vip(), 0x1B1),
+            Err("The font at 0x1B1 overlaps the ROM".to_string())
+        );
+        memory.set_font(&Font::vip(), 0x1B0).unwrap();
+        memory.set_memory_map(MemoryMap::Vip).unwrap();
+        assert!(memory.set_font(&Font::octo(), 0xE00).is_err());
+        memory.set_font(&Font::octo(), 0x000).unwrap();
+        assert_eq!(memory.read_block(0x200, 2), Ok(&[0x12, 0x00][..]));
+
+        // Moving a font the ROM was loaded over keeps the ROM
+        let mut memory = BlockMemory::with_rom(&[0x12, 0x00], 0x000).unwrap();
+        memory.set_font(&Font::vip(), 0x100).unwrap();
+        assert_eq!(memory.read_block(0x000, 2), Ok(&[0x12, 0x00][..]));
+    }
+
+    #[test]
+    fn vip_memory_map() {
+        let mut memory = BlockMemory::with_rom(&[0; 3233], PROGRAM_START).unwrap();
+        assert_eq!(
+            memory.set_memory_map(MemoryMap::Vip),
+            Err("ROM too large: 3233 bytes, at most 3232 bytes fit at 0x200".to_string())
+        );
+        memory.load_rom_bytes(&[0; 3232]).unwrap();
+        memory.set_memory_map(MemoryMap::Vip).unwrap();
+
+        assert_eq!(memory.write_byte(0x1FF, 1), Err(Error::WriteProtected(0x1FF)));
+        memory.write_byte(0xF00, 0xFF).unwrap();
+        memory.write_byte(0xF01, 0xFF).unwrap();
+        memory.mirror(0xEF0, &[1, 2]).unwrap();
+        assert_eq!(memory.take_written_regions(), vec![RegionKind::DisplayRam]);
+        assert!(memory.take_written_regions().is_empty());
+        assert_eq!(memory.read_block(0xEF0, 2), Ok(&[1, 2][..]));
     }
 
-    fn write_byte(&mut self, address: u16, value: u8) {
-        self.memory[address as usize] = value;
+    #[test]
+    fn rejects_roms_that_do_not_fit() {
+        let mut memory = BlockMemory::new();
+        assert!(memory.load_rom_bytes(&[0xFF; 3584]).is_ok());
+        assert_eq!(
+            memory.load_rom_bytes(&[0; 3585]),
+            Err("ROM too large: 3585 bytes, at most 3584 bytes fit at 0x200".to_string())
+        );
+        assert_eq!(memory.read_byte(0x200), Ok(0xFF));
     }
 
-    fn read_block(&self, address: u16, size: usize) -> &[u8] {
-        let address = address as usize;
-        &self.memory[address..(address + size)]
+    #[test]
+    fn rejects_load_addresses_outside_of_free_memory() {
+        let mut memory = BlockMemory::new();
+        memory.set_load_address(0x1000);
+        assert_eq!(
+            memory.load_rom_bytes(&[]),
+            Err("Load address 0x1000 is outside of the memory".to_string())
+        );
+        memory.set_load_address(0x100);
+        memory.load_rom_bytes(&[0x12, 0x00]).unwrap();
+        assert_eq!(
+            memory.set_memory_map(MemoryMap::Vip),
+            Err("Load address 0x100 is reserved by the memory map".to_string())
+        );
+        memory.set_load_address(PROGRAM_START);
+        memory.set_memory_map(MemoryMap::Vip).unwrap();
+        memory.set_load_address(0xEA0);
+        assert!(memory.load_rom_bytes(&[]).is_err());
     }
 }
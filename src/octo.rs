@@ -0,0 +1,1225 @@
+use asm::AsmError;
+use std::collections::{HashMap, VecDeque};
+use std::f64::consts;
+use std::fmt;
+use std::str::FromStr;
+
+const ORIGIN: usize = 0x200;
+
+// Stops macros that expand themselves endlessly
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// The instruction set an Octo program is compiled for.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Target {
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Target {
+    fn memory_size(self) -> usize {
+        match self {
+            Target::Chip8 | Target::Schip => 0x1000,
+            Target::XoChip => 0x10000,
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Target, String> {
+        match &s.to_lowercase()[..] {
+            "chip8" | "chip-8" => Ok(Target::Chip8),
+            "schip" | "superchip" => Ok(Target::Schip),
+            "xochip" | "xo-chip" => Ok(Target::XoChip),
+            _ => Err(format!("Unknown target: {} (expected chip8, schip or xochip)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Target::Chip8 => "CHIP-8",
+            Target::Schip => "SCHIP",
+            Target::XoChip => "XO-CHIP",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// An Octo program compiled to bytecode.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OctoProgram {
+    /// The bytecode to be loaded at 0x200.
+    pub rom: Vec<u8>,
+    /// The source line, starting at 1, of each instruction and data byte.
+    pub lines: HashMap<u16, usize>,
+    /// The addresses marked with `:breakpoint NAME`.
+    pub breakpoints: Vec<(String, u16)>,
+}
+
+impl OctoProgram {
+    pub fn line(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).cloned()
+    }
+}
+
+/// Compiles Octo source into bytecode for the target.
+///
+/// Besides the statements of Octo like `v0 := 5`, `i += v1`, `sprite v0 v1
+/// 5`, `if v0 == 3 then ...`, `if ... begin ... else ... end` and `loop ...
+/// while ... again`, the directives `: label`, `:const`, `:alias`, `:calc`,
+/// `:macro`, `:byte`, `:org`, `:unpack`, `:call` and `:breakpoint` are
+/// supported. Instructions of SCHIP or XO-CHIP are rejected unless the
+/// target supports them.
+pub fn compile(source: &str, target: Target) -> Result<OctoProgram, AsmError> {
+    compile_program(source, target, false)
+}
+
+/// Compiles Octo source to be run by this emulator. Of the SCHIP
+/// instructions, the emulator only has `i := bighex vx`, so the others are
+/// rejected even if the target has them.
+pub fn compile_runnable(source: &str, target: Target) -> Result<OctoProgram, AsmError> {
+    compile_program(source, target, true)
+}
+
+fn compile_program(source: &str, target: Target, runnable: bool) -> Result<OctoProgram, AsmError> {
+    let mut compiler = Compiler {
+        tokens: tokenize(source),
+        target,
+        runnable,
+        here: ORIGIN,
+        rom: Vec::new(),
+        lines: HashMap::new(),
+        breakpoints: Vec::new(),
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        expansions: 0,
+        patches: Vec::new(),
+        blocks: Vec::new(),
+        loops: Vec::new(),
+        jump_to_main: true,
+    };
+    // Execution starts at `main`, the jump is dropped again if nothing comes
+    // before it
+    let main = Token {
+        text: "main".to_string(),
+        line: 1,
+        column: 1,
+    };
+    compiler.emit(0x1000, &main)?;
+    compiler.fixup(ORIGIN, Fixup::Address, Reference { token: main, address: None })?;
+    while let Some(token) = compiler.tokens.pop_front() {
+        compiler.statement(token)?;
+    }
+    compiler.finish()
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+// Octo tokens are separated by whitespace, comments start with `#`
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = &line[..line.find('#').unwrap_or(line.len())];
+        let mut start = None;
+        for (index, c) in line.char_indices().chain(Some((line.len(), ' '))) {
+            match start {
+                Some(begin) if c.is_whitespace() => {
+                    tokens.push_back(Token {
+                        text: line[begin..index].to_string(),
+                        line: number + 1,
+                        column: line[..begin].chars().count() + 1,
+                    });
+                    start = None;
+                }
+                None if !c.is_whitespace() => start = Some(index),
+                _ => {}
+            }
+        }
+    }
+    tokens
+}
+
+fn is_name(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[derive(Clone, Copy)]
+enum Fixup {
+    // The lower 12 bits of the instruction at the position
+    Address,
+    // The 16 bit word at the position
+    Long,
+    // The byte at the position gets the nibble and the upper 4 bits
+    UnpackHigh(u8),
+    // The byte at the position gets the lower 8 bits
+    UnpackLow,
+}
+
+// A reference to a label that was not defined yet
+struct Patch {
+    position: usize,
+    fixup: Fixup,
+    label: Token,
+}
+
+struct Macro {
+    arguments: Vec<String>,
+    body: Vec<Token>,
+}
+
+// An `if ... begin` block, `jump` is the position of the jump over the
+// block that is filled in by `else` or `end`
+struct Block {
+    token: Token,
+    jump: usize,
+    has_else: bool,
+}
+
+// A `loop`, `exits` are the positions of the jumps of its `while`s
+struct Loop {
+    token: Token,
+    start: usize,
+    exits: Vec<usize>,
+}
+
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+// A condition is compiled to instructions that prepare VF, followed by one
+// of the two skips
+struct Condition {
+    setup: Vec<u16>,
+    skip_if_true: u16,
+    skip_if_false: u16,
+}
+
+// An address given by a number or a label, which is unknown while the label
+// has not been defined yet
+#[derive(Clone)]
+struct Reference {
+    token: Token,
+    address: Option<usize>,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    target: Target,
+    // Only instructions the emulator can execute are allowed
+    runnable: bool,
+    here: usize,
+    rom: Vec<u8>,
+    lines: HashMap<u16, usize>,
+    breakpoints: Vec<(String, u16)>,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    patches: Vec<Patch>,
+    blocks: Vec<Block>,
+    loops: Vec<Loop>,
+    jump_to_main: bool,
+}
+
+impl Compiler {
+    fn statement(&mut self, token: Token) -> Result<(), AsmError> {
+        if let Some(x) = self.register(&token) {
+            return self.register_statement(x, token);
+        }
+        if parse_number(&token.text).is_some() || token.text == "{" {
+            self.tokens.push_front(token.clone());
+            let value = self.byte()?;
+            return self.emit_byte(value, &token);
+        }
+        match &token.text[..] {
+            ":" => {
+                let name = self.name()?;
+                self.define(&name)?;
+                if name.text == "main" && self.jump_to_main {
+                    if self.here == ORIGIN + 2 {
+                        self.remove_jump_to_main();
+                    } else {
+                        self.lines.insert(ORIGIN as u16, name.line);
+                        self.lines.insert(ORIGIN as u16 + 1, name.line);
+                    }
+                }
+                self.labels.insert(name.text, self.here);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = self.value()?;
+                self.define(&name)?;
+                self.constants.insert(name.text, value);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let brace = self.next()?;
+                if brace.text != "{" {
+                    return Err(brace.error("Expected `{` after the name".to_string()));
+                }
+                self.tokens.push_front(brace);
+                let value = self.value()?;
+                self.define(&name)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let register = self.next()?;
+                let x = self.register(&register).ok_or_else(|| {
+                    register.error(format!("Expected a register: {}", register.text))
+                })?;
+                self.define(&name)?;
+                self.aliases.insert(name.text, x);
+            }
+            ":macro" => self.define_macro()?,
+            ":byte" => {
+                let value = self.byte()?;
+                self.emit_byte(value, &token)?;
+            }
+            ":org" => {
+                let value = self.value()?;
+                if value < ORIGIN as f64 || value >= self.target.memory_size() as f64 {
+                    return Err(token.error(format!("Address out of range: {}", value)));
+                }
+                self.here = value as usize;
+            }
+            ":unpack" => {
+                let nibble = self.integer(0, 0xF)? as u8;
+                let address = self.address()?;
+                self.emit(0x6000, &token)?;
+                self.fixup(self.here - 1, Fixup::UnpackHigh(nibble), address.clone())?;
+                self.emit(0x6100, &token)?;
+                self.fixup(self.here - 1, Fixup::UnpackLow, address)?;
+            }
+            ":breakpoint" => {
+                let name = self.next()?;
+                self.breakpoints.push((name.text, self.here as u16));
+            }
+            ":call" => {
+                let address = self.address()?;
+                self.emit(0x2000, &token)?;
+                self.fixup(self.here - 2, Fixup::Address, address)?;
+            }
+            ";" | "return" => self.emit(0x00EE, &token)?,
+            "clear" => self.emit(0x00E0, &token)?,
+            "hires" | "lores" | "exit" | "scroll-left" | "scroll-right" => {
+                self.require(Target::Schip, &token)?;
+                let opcode = match &token.text[..] {
+                    "hires" => 0x00FF,
+                    "lores" => 0x00FE,
+                    "exit" => 0x00FD,
+                    "scroll-left" => 0x00FC,
+                    _ => 0x00FB,
+                };
+                self.emit(opcode, &token)?;
+            }
+            "scroll-down" => {
+                self.require(Target::Schip, &token)?;
+                let n = self.integer(0, 0xF)?;
+                self.emit(0x00C0 | n, &token)?;
+            }
+            "scroll-up" => {
+                self.require(Target::XoChip, &token)?;
+                let n = self.integer(0, 0xF)?;
+                self.emit(0x00D0 | n, &token)?;
+            }
+            "plane" => {
+                self.require(Target::XoChip, &token)?;
+                let n = self.integer(0, 3)?;
+                self.emit(0xF001 | n << 8, &token)?;
+            }
+            "audio" => {
+                self.require(Target::XoChip, &token)?;
+                self.emit(0xF002, &token)?;
+            }
+            "jump" | "jump0" => {
+                let address = self.address()?;
+                let opcode = if token.text == "jump" { 0x1000 } else { 0xB000 };
+                self.emit(opcode, &token)?;
+                self.fixup(self.here - 2, Fixup::Address, address)?;
+            }
+            "i" => self.i_statement()?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.expect_register()? as u16;
+                let opcode = match &token.text[..] {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => {
+                        self.require(Target::XoChip, &token)?;
+                        0xF03A
+                    }
+                };
+                self.emit(opcode | x << 8, &token)?;
+            }
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let n = self.integer(0, 0xF)?;
+                if n == 0 {
+                    self.require(Target::Schip, &token)?;
+                }
+                self.emit(0xD000 | x << 8 | y << 4 | n, &token)?;
+            }
+            "bcd" => {
+                let x = self.expect_register()? as u16;
+                self.emit(0xF033 | x << 8, &token)?;
+            }
+            "save" | "load" => {
+                let x = self.expect_register()? as u16;
+                let range = self.tokens.front().is_some_and(|next| next.text == "-");
+                if range {
+                    self.require(Target::XoChip, &token)?;
+                    self.next()?;
+                    let y = self.expect_register()? as u16;
+                    let opcode = if token.text == "save" { 0x5002 } else { 0x5003 };
+                    self.emit(opcode | x << 8 | y << 4, &token)?;
+                } else {
+                    let opcode = if token.text == "save" { 0xF055 } else { 0xF065 };
+                    self.emit(opcode | x << 8, &token)?;
+                }
+            }
+            "saveflags" | "loadflags" => {
+                self.require(Target::Schip, &token)?;
+                let x = self.expect_register()? as u16;
+                let opcode = if token.text == "saveflags" { 0xF075 } else { 0xF085 };
+                self.emit(opcode | x << 8, &token)?;
+            }
+            "if" => self.if_statement(token)?,
+            "else" => {
+                let block = match self.blocks.pop() {
+                    Some(ref block) if block.has_else => {
+                        return Err(token.error("Duplicate `else`".to_string()))
+                    }
+                    Some(block) => block,
+                    None => return Err(token.error("`else` without `if ... begin`".to_string())),
+                };
+                self.emit(0x1000, &token)?;
+                let here = self.here;
+                self.patch_address(block.jump, here, &token)?;
+                self.blocks.push(Block {
+                    token,
+                    jump: here - 2,
+                    has_else: true,
+                });
+            }
+            "end" => {
+                let block = self
+                    .blocks
+                    .pop()
+                    .ok_or_else(|| token.error("`end` without `if ... begin`".to_string()))?;
+                let here = self.here;
+                self.patch_address(block.jump, here, &token)?;
+            }
+            "loop" => self.loops.push(Loop {
+                token,
+                start: self.here,
+                exits: Vec::new(),
+            }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return Err(token.error("`while` outside of a loop".to_string()));
+                }
+                let condition = self.condition()?;
+                for &opcode in &condition.setup {
+                    self.emit(opcode, &token)?;
+                }
+                self.emit(condition.skip_if_true, &token)?;
+                self.emit(0x1000, &token)?;
+                let exit = self.here - 2;
+                self.loops.last_mut().unwrap().exits.push(exit);
+            }
+            "again" => {
+                let lp = self
+                    .loops
+                    .pop()
+                    .ok_or_else(|| token.error("`again` without `loop`".to_string()))?;
+                self.emit(0x1000, &token)?;
+                let here = self.here;
+                self.patch_address(here - 2, lp.start, &token)?;
+                for exit in lp.exits {
+                    self.patch_address(exit, here, &token)?;
+                }
+            }
+            _ if token.text.starts_with(':') => {
+                return Err(token.error(format!("Unknown directive: {}", token.text)))
+            }
+            _ if self.macros.contains_key(&token.text) => self.expand(token)?,
+            _ if is_name(&token.text) => {
+                // A name on its own calls the subroutine at that label
+                let address = self.address_of(&token)?;
+                self.emit(0x2000, &token)?;
+                self.fixup(self.here - 2, Fixup::Address, address)?;
+            }
+            _ => return Err(token.error(format!("Unexpected token: {}", token.text))),
+        }
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u8, token: Token) -> Result<(), AsmError> {
+        let x = x as u16;
+        let operator = self.next()?;
+        let operand = self.next()?;
+        let y = self.register(&operand).map(|y| y as u16);
+        let opcode = match (&operator.text[..], y) {
+            (":=", Some(y)) => 0x8000 | x << 8 | y << 4,
+            (":=", None) if operand.text == "random" => 0xC000 | x << 8 | self.byte()? as u16,
+            (":=", None) if operand.text == "delay" => 0xF007 | x << 8,
+            (":=", None) if operand.text == "key" => 0xF00A | x << 8,
+            (":=", None) => {
+                self.tokens.push_front(operand);
+                0x6000 | x << 8 | self.byte()? as u16
+            }
+            ("+=", Some(y)) => 0x8004 | x << 8 | y << 4,
+            ("+=", None) => {
+                self.tokens.push_front(operand);
+                0x7000 | x << 8 | self.byte()? as u16
+            }
+            ("-=", Some(y)) => 0x8005 | x << 8 | y << 4,
+            ("-=", None) => {
+                self.tokens.push_front(operand);
+                0x7000 | x << 8 | (self.byte()? as u16).wrapping_neg() & 0xFF
+            }
+            ("=-", Some(y)) => 0x8007 | x << 8 | y << 4,
+            ("|=", Some(y)) => 0x8001 | x << 8 | y << 4,
+            ("&=", Some(y)) => 0x8002 | x << 8 | y << 4,
+            ("^=", Some(y)) => 0x8003 | x << 8 | y << 4,
+            (">>=", Some(y)) => 0x8006 | x << 8 | y << 4,
+            ("<<=", Some(y)) => 0x800E | x << 8 | y << 4,
+            ("=-" | "|=" | "&=" | "^=" | ">>=" | "<<=", None) => {
+                return Err(operand.error(format!("Expected a register: {}", operand.text)))
+            }
+            _ => return Err(operator.error(format!("Unknown operator: {}", operator.text))),
+        };
+        self.emit(opcode, &token)
+    }
+
+    fn i_statement(&mut self) -> Result<(), AsmError> {
+        let operator = self.next()?;
+        let token = operator.clone();
+        match &operator.text[..] {
+            ":=" => {
+                let operand = self.next()?;
+                match &operand.text[..] {
+                    "hex" => {
+                        let x = self.expect_register()? as u16;
+                        self.emit(0xF029 | x << 8, &token)
+                    }
+                    "bighex" => {
+                        self.require(Target::Schip, &operand)?;
+                        let x = self.expect_register()? as u16;
+                        self.emit(0xF030 | x << 8, &token)
+                    }
+                    "long" => {
+                        self.require(Target::XoChip, &operand)?;
+                        let address = self.address()?;
+                        self.emit(0xF000, &token)?;
+                        self.emit(0x0000, &token)?;
+                        self.fixup(self.here - 2, Fixup::Long, address)
+                    }
+                    _ => {
+                        self.tokens.push_front(operand);
+                        let address = self.address()?;
+                        self.emit(0xA000, &token)?;
+                        self.fixup(self.here - 2, Fixup::Address, address)
+                    }
+                }
+            }
+            "+=" => {
+                let x = self.expect_register()? as u16;
+                self.emit(0xF01E | x << 8, &token)
+            }
+            _ => Err(operator.error(format!("Unknown operator: {}", operator.text))),
+        }
+    }
+
+    fn if_statement(&mut self, token: Token) -> Result<(), AsmError> {
+        let condition = self.condition()?;
+        for &opcode in &condition.setup {
+            self.emit(opcode, &token)?;
+        }
+        let keyword = self.next()?;
+        match &keyword.text[..] {
+            "then" => {
+                self.emit(condition.skip_if_false, &token)?;
+                // The skip only jumps over a single instruction
+                let start = self.here;
+                let mut statement = self.next()?;
+                // Macros are expanded first. Like in Octo, only the first
+                // instruction of the expansion is conditional.
+                while self.macros.contains_key(&statement.text) {
+                    self.expand(statement)?;
+                    statement = self.next()?;
+                }
+                self.statement(statement.clone())?;
+                if self.here != start + 2 {
+                    return Err(statement.error(
+                        "`then` must be followed by a single instruction".to_string(),
+                    ));
+                }
+            }
+            "begin" => {
+                self.emit(condition.skip_if_true, &token)?;
+                self.emit(0x1000, &token)?;
+                self.blocks.push(Block {
+                    token,
+                    jump: self.here - 2,
+                    has_else: false,
+                });
+            }
+            _ => return Err(keyword.error("Expected `then` or `begin`".to_string())),
+        }
+        Ok(())
+    }
+
+    fn condition(&mut self) -> Result<Condition, AsmError> {
+        let x = self.expect_register()? as u16;
+        let operator = self.next()?;
+        match &operator.text[..] {
+            "key" | "-key" => {
+                let (pressed, released) = (0xE09E | x << 8, 0xE0A1 | x << 8);
+                let key = operator.text == "key";
+                return Ok(Condition {
+                    setup: Vec::new(),
+                    skip_if_true: if key { pressed } else { released },
+                    skip_if_false: if key { released } else { pressed },
+                });
+            }
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {}
+            _ => return Err(operator.error(format!("Unknown comparison: {}", operator.text))),
+        }
+        let operand = match self.tokens.front().and_then(|next| self.register(next)) {
+            Some(y) => {
+                self.next()?;
+                Operand::Register(y)
+            }
+            None => Operand::Byte(self.byte()?),
+        };
+        let (equal, not_equal) = match operand {
+            Operand::Register(y) => {
+                let xy = x << 8 | (y as u16) << 4;
+                (0x5000 | xy, 0x9000 | xy)
+            }
+            Operand::Byte(nn) => (0x3000 | x << 8 | nn as u16, 0x4000 | x << 8 | nn as u16),
+        };
+        // For the other comparisons VF is set to the flag of a subtraction,
+        // which is 0 if it borrowed
+        let borrowed = (0x3F00, 0x4F00);
+        let (setup, (skip_if_true, skip_if_false)) = match (&operator.text[..], operand) {
+            ("==", _) => (Vec::new(), (equal, not_equal)),
+            ("!=", _) => (Vec::new(), (not_equal, equal)),
+            (operator, Operand::Register(y)) => {
+                let y = y as u16;
+                let setup = match operator {
+                    // VF := VX - VY borrows if VX < VY
+                    "<" | ">=" => vec![0x8F00 | x << 4, 0x8F05 | y << 4],
+                    // VF := VY - VX borrows if VX > VY
+                    _ => vec![0x8F00 | y << 4, 0x8F05 | x << 4],
+                };
+                (setup, borrowed)
+            }
+            (operator, Operand::Byte(nn)) => {
+                let nn = nn as u16;
+                let setup = match operator {
+                    // VF := VX - NN
+                    "<" | ">=" => vec![0x6F00 | nn, 0x8F07 | x << 4],
+                    // VF := NN - VX
+                    _ => vec![0x6F00 | nn, 0x8F05 | x << 4],
+                };
+                (setup, borrowed)
+            }
+        };
+        let (skip_if_true, skip_if_false) = match &operator.text[..] {
+            ">=" | "<=" => (skip_if_false, skip_if_true),
+            _ => (skip_if_true, skip_if_false),
+        };
+        Ok(Condition {
+            setup,
+            skip_if_true,
+            skip_if_false,
+        })
+    }
+
+    fn define_macro(&mut self) -> Result<(), AsmError> {
+        let name = self.name()?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            arguments.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self
+                .tokens
+                .pop_front()
+                .ok_or_else(|| name.error("Missing `}` at the end of the macro".to_string()))?;
+            match &token.text[..] {
+                "{" => depth += 1,
+                "}" if depth == 0 => break,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(token);
+        }
+        self.define(&name)?;
+        self.macros.insert(name.text, Macro { arguments, body });
+        Ok(())
+    }
+
+    fn expand(&mut self, token: Token) -> Result<(), AsmError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(token.error("Too many macro expansions".to_string()));
+        }
+        let count = self.macros[&token.text].arguments.len();
+        let mut values = HashMap::new();
+        for index in 0..count {
+            let value = self.next()?;
+            values.insert(self.macros[&token.text].arguments[index].clone(), value.text);
+        }
+        for body_token in self.macros[&token.text].body.iter().rev() {
+            let mut body_token = body_token.clone();
+            if let Some(value) = values.get(&body_token.text) {
+                body_token.text = value.clone();
+            }
+            self.tokens.push_front(body_token);
+        }
+        Ok(())
+    }
+
+    fn remove_jump_to_main(&mut self) {
+        self.jump_to_main = false;
+        self.here = ORIGIN;
+        self.rom.clear();
+        self.lines.clear();
+        self.patches.retain(|patch| patch.position != ORIGIN);
+        for address in self.labels.values_mut() {
+            if *address == ORIGIN + 2 {
+                *address = ORIGIN;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<OctoProgram, AsmError> {
+        if !self.labels.contains_key("main") {
+            return Err(AsmError {
+                line: 1,
+                column: 1,
+                message: "Missing `: main`, where the program starts".to_string(),
+            });
+        }
+        if let Some(block) = self.blocks.pop() {
+            return Err(block.token.error("`begin` without `end`".to_string()));
+        }
+        if let Some(lp) = self.loops.pop() {
+            return Err(lp.token.error("`loop` without `again`".to_string()));
+        }
+        for patch in std::mem::take(&mut self.patches) {
+            let address = *self.labels.get(&patch.label.text).ok_or_else(|| {
+                patch.label.error(format!("Undefined name: {}", patch.label.text))
+            })?;
+            self.apply(patch.position, patch.fixup, address, &patch.label)?;
+        }
+        Ok(OctoProgram {
+            rom: self.rom,
+            lines: self.lines,
+            breakpoints: self.breakpoints,
+        })
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(token),
+            None => Err(AsmError {
+                line: self.lines.values().max().cloned().unwrap_or(1),
+                column: 1,
+                message: "Unexpected end of source".to_string(),
+            }),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if token.text == text {
+            Ok(token)
+        } else {
+            Err(token.error(format!("Expected `{}` instead of {}", text, token.text)))
+        }
+    }
+
+    fn name(&mut self) -> Result<Token, AsmError> {
+        let token = self.next()?;
+        if is_name(&token.text) && self.register(&token).is_none() {
+            Ok(token)
+        } else {
+            Err(token.error(format!("Invalid name: {}", token.text)))
+        }
+    }
+
+    fn define(&self, name: &Token) -> Result<(), AsmError> {
+        let text = &name.text;
+        if self.labels.contains_key(text)
+            || self.constants.contains_key(text)
+            || self.aliases.contains_key(text)
+            || self.macros.contains_key(text)
+        {
+            return Err(name.error(format!("Duplicate name: {}", text)));
+        }
+        Ok(())
+    }
+
+    fn register(&self, token: &Token) -> Option<u8> {
+        if let Some(&x) = self.aliases.get(&token.text) {
+            return Some(x);
+        }
+        match token.text.strip_prefix(['v', 'V']) {
+            Some(digit) if digit.len() == 1 => u8::from_str_radix(digit, 16).ok(),
+            _ => None,
+        }
+    }
+
+    fn expect_register(&mut self) -> Result<u8, AsmError> {
+        let token = self.next()?;
+        self.register(&token)
+            .ok_or_else(|| token.error(format!("Expected a register: {}", token.text)))
+    }
+
+    fn require(&self, target: Target, token: &Token) -> Result<(), AsmError> {
+        if self.target < target {
+            Err(token.error(format!("`{}` requires {}", token.text, target)))
+        } else if self.runnable && target > Target::Chip8 && token.text != "bighex" {
+            Err(token.error(format!("`{}` requires {}, which cannot be run", token.text, target)))
+        } else {
+            Ok(())
+        }
+    }
+
+    // A number, a constant, a defined label or a `{ ... }` expression
+    fn value(&mut self) -> Result<f64, AsmError> {
+        let token = self.next()?;
+        if token.text == "{" {
+            let mut expression = Vec::new();
+            loop {
+                let token = self
+                    .tokens
+                    .pop_front()
+                    .ok_or_else(|| token.error("Missing `}` after the expression".to_string()))?;
+                if token.text == "}" {
+                    break;
+                }
+                expression.push(token);
+            }
+            let mut position = 0;
+            let value = self.expression(&token, &expression, &mut position)?;
+            return match expression.get(position) {
+                Some(extra) => Err(extra.error(format!("Unexpected token: {}", extra.text))),
+                None => Ok(value),
+            };
+        }
+        self.symbol(&token)
+    }
+
+    fn symbol(&self, token: &Token) -> Result<f64, AsmError> {
+        if let Some(value) = parse_number(&token.text) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.constants.get(&token.text) {
+            return Ok(value);
+        }
+        if let Some(&address) = self.labels.get(&token.text) {
+            return Ok(address as f64);
+        }
+        match &token.text[..] {
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(consts::PI),
+            "E" => Ok(consts::E),
+            name if is_name(name) => Err(token.error(format!("Undefined name: {}", name))),
+            _ => Err(token.error(format!("Expected a number: {}", token.text))),
+        }
+    }
+
+    // Like in Octo, expressions are evaluated from right to left without
+    // operator precedence, parentheses group explicitly
+    fn expression(
+        &self,
+        start: &Token,
+        tokens: &[Token],
+        position: &mut usize,
+    ) -> Result<f64, AsmError> {
+        let left = self.term(start, tokens, position)?;
+        let operator = match tokens.get(*position) {
+            Some(token) if token.text != ")" => token,
+            _ => return Ok(left),
+        };
+        *position += 1;
+        let right = self.expression(operator, tokens, position)?;
+        let value = match &operator.text[..] {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => (left as i64 & right as i64) as f64,
+            "|" => (left as i64 | right as i64) as f64,
+            "^" => (left as i64 ^ right as i64) as f64,
+            "<<" => ((left as i64) << (right as i64 & 63)) as f64,
+            ">>" => ((left as i64) >> (right as i64 & 63)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            _ => return Err(operator.error(format!("Unknown operator: {}", operator.text))),
+        };
+        Ok(value)
+    }
+
+    fn term(&self, start: &Token, tokens: &[Token], position: &mut usize) -> Result<f64, AsmError> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| start.error("Incomplete expression".to_string()))?;
+        *position += 1;
+        let unary: Option<fn(f64) -> f64> = match &token.text[..] {
+            "-" => Some(|v| -v),
+            "~" => Some(|v| !(v as i64) as f64),
+            "!" => Some(|v| (v == 0.0) as u8 as f64),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(function) = unary {
+            return Ok(function(self.term(token, tokens, position)?));
+        }
+        if token.text == "(" {
+            let value = self.expression(token, tokens, position)?;
+            return match tokens.get(*position) {
+                Some(close) if close.text == ")" => {
+                    *position += 1;
+                    Ok(value)
+                }
+                _ => Err(token.error("Missing `)`".to_string())),
+            };
+        }
+        self.symbol(token)
+    }
+
+    fn integer(&mut self, min: i64, max: i64) -> Result<u16, AsmError> {
+        let token = self.tokens.front().cloned();
+        let value = self.value()?.floor() as i64;
+        if value < min || value > max {
+            let token = token.unwrap();
+            return Err(token.error(format!("Value out of range: {}", token.text)));
+        }
+        Ok(value as u16)
+    }
+
+    // Bytes may be given as signed numbers
+    fn byte(&mut self) -> Result<u8, AsmError> {
+        Ok(self.integer(-128, 255)? as u8)
+    }
+
+    // An address that may refer to a label defined later on
+    fn address(&mut self) -> Result<Reference, AsmError> {
+        let token = self.next()?;
+        self.address_of(&token)
+    }
+
+    fn address_of(&mut self, token: &Token) -> Result<Reference, AsmError> {
+        if is_name(&token.text) && !self.constants.contains_key(&token.text) {
+            return Ok(Reference {
+                token: token.clone(),
+                address: self.labels.get(&token.text).cloned(),
+            });
+        }
+        self.tokens.push_front(token.clone());
+        let value = self.value()?.floor();
+        if value < 0.0 || value >= self.target.memory_size() as f64 {
+            return Err(token.error(format!("Address out of range: {}", token.text)));
+        }
+        Ok(Reference {
+            token: token.clone(),
+            address: Some(value as usize),
+        })
+    }
+
+    fn fixup(
+        &mut self,
+        position: usize,
+        fixup: Fixup,
+        reference: Reference,
+    ) -> Result<(), AsmError> {
+        match reference.address {
+            Some(address) => self.apply(position, fixup, address, &reference.token),
+            None => {
+                self.patches.push(Patch {
+                    position,
+                    fixup,
+                    label: reference.token,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        position: usize,
+        fixup: Fixup,
+        address: usize,
+        token: &Token,
+    ) -> Result<(), AsmError> {
+        let index = position - ORIGIN;
+        match fixup {
+            Fixup::Address => {
+                if address > 0xFFF {
+                    return Err(token.error(format!(
+                        "Address {:04X} out of range, use `i := long`",
+                        address
+                    )));
+                }
+                self.rom[index] = self.rom[index] & 0xF0 | (address >> 8) as u8;
+                self.rom[index + 1] = address as u8;
+            }
+            Fixup::Long => {
+                self.rom[index] = (address >> 8) as u8;
+                self.rom[index + 1] = address as u8;
+            }
+            Fixup::UnpackHigh(nibble) => {
+                self.rom[index] = nibble << 4 | (address >> 8) as u8 & 0xF;
+            }
+            Fixup::UnpackLow => self.rom[index] = address as u8,
+        }
+        Ok(())
+    }
+
+    fn patch_address(
+        &mut self,
+        position: usize,
+        address: usize,
+        token: &Token,
+    ) -> Result<(), AsmError> {
+        self.apply(position, Fixup::Address, address, token)
+    }
+
+    fn emit(&mut self, opcode: u16, token: &Token) -> Result<(), AsmError> {
+        self.emit_byte((opcode >> 8) as u8, token)?;
+        self.emit_byte(opcode as u8, token)
+    }
+
+    fn emit_byte(&mut self, value: u8, token: &Token) -> Result<(), AsmError> {
+        if self.here >= self.target.memory_size() {
+            return Err(token.error("Program does not fit into memory".to_string()));
+        }
+        let index = self.here - ORIGIN;
+        if self.rom.len() <= index {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = value;
+        self.lines.insert(self.here as u16, token.line);
+        self.here += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use headless::Machine;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source, Target::Chip8)
+            .unwrap_or_else(|e| panic!("{}", e))
+            .rom
+    }
+
+    #[test]
+    fn statements_compile_to_opcodes() {
+        let source = "
+            : main
+                clear
+                v0 := 5
+                v1 := v0
+                v1 += 3
+                v1 -= 1
+                v2 -= v1
+                v2 =- v1
+                va |= vb
+                v3 := random 0x0F
+                v4 := key
+                delay := v4
+                i := ball       # defined below
+                i += v1
+                sprite v0 v1 4
+                bcd v3
+                save v3
+                load v3
+                draw
+                jump main
+            : draw
+                return
+            : ball
+                0xFF 0x81 -1
+        ";
+        assert_eq!(
+            rom(source),
+            vec![
+                0x00, 0xE0, 0x60, 0x05, 0x81, 0x00, 0x71, 0x03, 0x71, 0xFF, 0x82, 0x15, 0x82,
+                0x17, 0x8A, 0xB1, 0xC3, 0x0F, 0xF4, 0x0A, 0xF4, 0x15, 0xA2, 0x28, 0xF1, 0x1E,
+                0xD0, 0x14, 0xF3, 0x33, 0xF3, 0x55, 0xF3, 0x65, 0x22, 0x26, 0x12, 0x00, 0x00,
+                0xEE, 0xFF, 0x81, 0xFF,
+            ]
+        );
+    }
+
+    #[test]
+    fn control_flow_runs_as_written() {
+        let source = "
+            : main
+            v0 := 3
+            v1 := 7
+            if v0 == 3 then va += 1
+            if v0 < v1 then va += 2
+            if v1 < v0 then va += 4
+            if v0 > 2 then va += 8
+            if v0 >= 3 then va += 16
+            if v0 <= 2 then va += 32
+            if v1 != v0 then va += 64
+            loop
+                vb += 1
+                while vb != 10
+            again
+            if vb == 10 begin
+                vc := 1
+            else
+                vc := 2
+            end
+            : halt
+            jump halt
+        ";
+        let rom = rom(source);
+        assert_eq!(&rom[4..8], &[0x40, 0x03, 0x7A, 0x01]);
+        let mut machine = Machine::new(&mut &rom[..]).unwrap();
+        for _ in 0..20 {
+            machine.run_frame().unwrap();
+        }
+        let v = machine.registers().v;
+        assert_eq!((v[0xA], v[0xB], v[0xC]), (1 + 2 + 8 + 16 + 64, 10, 1));
+    }
+
+    #[test]
+    fn macros_constants_and_aliases() {
+        let source = "
+            :alias counter v3
+            :const step 2
+            :calc double { step * 2 + 1 }   # right to left: 2 * 3
+            :macro add-twice reg amount { reg += amount  reg += amount }
+            : main
+            counter := double
+            add-twice counter step
+            :unpack 0xA data
+            : data
+            :byte { HERE - 0x200 }
+        ";
+        assert_eq!(
+            rom(source),
+            vec![0x63, 0x06, 0x73, 0x02, 0x73, 0x02, 0x60, 0xA2, 0x61, 0x0A, 0x0A]
+        );
+        assert_eq!(rom(":org 0x204 : main 0x42"), vec![0x12, 0x04, 0, 0, 0x42]);
+
+        let source = ":macro bump reg { reg += 1 } : main if v0 == 1 then bump v2";
+        assert_eq!(rom(source), vec![0x40, 0x01, 0x72, 0x01]);
+        let source = ":macro bump2 { v1 += 1 v1 += 1 } : main if v0 == 1 then bump2";
+        assert_eq!(rom(source), vec![0x40, 0x01, 0x71, 0x01, 0x71, 0x01]);
+    }
+
+    #[test]
+    fn errors_and_lines_refer_to_source() {
+        let source = ": sub\n  return\n: main\n  v0 := 1\n:breakpoint b\n  sub";
+        let program = compile(source, Target::Chip8).unwrap();
+        assert_eq!(program.rom, vec![0x12, 0x04, 0x00, 0xEE, 0x60, 0x01, 0x22, 0x02]);
+        assert_eq!(program.line(0x200), Some(3));
+        assert_eq!(program.line(0x204), Some(4));
+        assert_eq!(program.line(0x206), Some(6));
+        assert_eq!(program.breakpoints, vec![("b".to_string(), 0x206)]);
+
+        let compile = |source: &str, target| compile(&format!(": main {}", source), target);
+        let error = compile("v0 := 1\n  jump nowhere", Target::Chip8).unwrap_err();
+        assert_eq!(error.to_string(), "2:8: Undefined name: nowhere");
+        let error = compile("loop\n  v0 += 1", Target::Chip8).unwrap_err();
+        assert_eq!(error.to_string(), "1:8: `loop` without `again`");
+        let error = compile("if v0 == 1 then i := long 0x1234", Target::XoChip).unwrap_err();
+        assert_eq!((error.line, error.column), (1, 24));
+        let error = ::octo::compile("clear", Target::Chip8).unwrap_err();
+        assert_eq!(error.to_string(), "1:1: Missing `: main`, where the program starts");
+
+        let error = compile("sprite v0 v1 0", Target::Chip8).unwrap_err();
+        assert_eq!(error.message, "`sprite` requires SCHIP");
+        assert_eq!(compile("sprite v0 v1 0", Target::Schip).unwrap().rom, vec![0xD0, 0x10]);
+        assert!(compile("hires", Target::Chip8).is_err());
+        assert!(compile("save v1 - v3", Target::Schip).is_err());
+        let error = ::octo::compile_runnable(": main\n  hires", Target::Schip).unwrap_err();
+        assert_eq!(error.to_string(), "2:3: `hires` requires SCHIP, which cannot be run");
+        let program = ::octo::compile_runnable(": main\n  i := bighex v0", Target::Schip);
+        assert_eq!(program.unwrap().rom, vec![0xF0, 0x30]);
+        assert_eq!(compile("save v1 - v3", Target::XoChip).unwrap().rom, vec![0x51, 0x32]);
+    }
+}
@@ -0,0 +1,219 @@
+use cpu::Registers;
+use disasm::disassemble;
+use memory::Memory;
+
+/// Size of the debug panel in characters.
+pub const PANEL_COLS: usize = 34;
+pub const PANEL_ROWS: usize = 20;
+
+/// Size of a character cell in pixels, the glyphs are 5x7 pixels.
+pub const CHAR_WIDTH: usize = 6;
+pub const CHAR_HEIGHT: usize = 8;
+
+const BACKGROUND: u8 = 0x20;
+const FOREGROUND: u8 = 0xE0;
+
+/// Describes the state of the machine as lines of text for the debug panel.
+pub fn describe(
+    registers: &Registers,
+    memory: &dyn Memory,
+    keys: &[bool; 16],
+    paused: bool,
+) -> Vec<String> {
+    let r = registers;
+    let mut lines = vec![format!(
+        "PC {:03X} I {:03X} SP {:X} DT {:02X} ST {:02X}",
+        r.pc, r.i, r.sp, r.delay_timer, r.sound_timer
+    )];
+    for (row, values) in r.v.chunks(4).enumerate() {
+        let registers: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(x, v)| format!("V{:X} {:02X}", 4 * row + x, v))
+            .collect();
+        lines.push(registers.join("  "));
+    }
+    // The most recent return addresses are the most interesting
+    let depth = (r.sp as usize).min(r.stack.len());
+    let stack: Vec<String> = r.stack[depth.saturating_sub(7)..depth]
+        .iter()
+        .map(|address| format!("{:03X}", address))
+        .collect();
+    lines.push(format!("STACK {}", stack.join(" ")));
+    lines.push(String::new());
+
+    let start = r.pc.saturating_sub(4);
+    for address in (start..start.saturating_add(14)).step_by(2) {
+        let marker = if address == r.pc { '>' } else { ' ' };
+        let opcode = match (memory.read_byte(address), memory.read_byte(address.wrapping_add(1))) {
+            (Ok(high), Ok(low)) => (high as u16) << 8 | low as u16,
+            _ => break,
+        };
+        lines.push(format!("{}{:03X} {:04X} {}", marker, address, opcode, disassemble(opcode)));
+    }
+    lines.push(String::new());
+
+    for row in 0..2 {
+        let address = r.i.wrapping_add(8 * row);
+        let mut line = format!("{:03X}", address);
+        for offset in 0..8 {
+            match memory.read_byte(address.wrapping_add(offset)) {
+                Ok(byte) => line += &format!(" {:02X}", byte),
+                Err(_) => line += " --",
+            }
+        }
+        lines.push(line);
+    }
+    lines.push(String::new());
+
+    let keys: String = keys
+        .iter()
+        .enumerate()
+        .map(|(key, &down)| if down { format!("{:X}", key) } else { ".".to_string() })
+        .collect();
+    lines.push(format!("KEYS {}", keys));
+    lines.push(if paused { "PAUSED  F5 RUN  F6 STEP" } else { "RUNNING  F5 PAUSE" }.to_string());
+    lines
+}
+
+/// Renders the lines into an RGB24 buffer of the size of the panel.
+pub fn render(lines: &[String], buffer: &mut [u8], pitch: usize) {
+    for row in buffer.chunks_mut(pitch) {
+        for byte in row.iter_mut() {
+            *byte = BACKGROUND;
+        }
+    }
+    for (row, line) in lines.iter().take(PANEL_ROWS).enumerate() {
+        for (col, c) in line.chars().take(PANEL_COLS).enumerate() {
+            for (y, bits) in glyph(c).iter().enumerate() {
+                for x in 0..5 {
+                    if bits & (0x10 >> x) == 0 {
+                        continue;
+                    }
+                    let px = col * CHAR_WIDTH + x;
+                    let py = row * CHAR_HEIGHT + y;
+                    let offset = py * pitch + px * 3;
+                    buffer[offset..offset + 3].copy_from_slice(&[FOREGROUND; 3]);
+                }
+            }
+        }
+    }
+}
+
+// 5x7 glyphs, one byte per row with the leftmost pixel in bit 4. Lower case
+// letters are shown in upper case, except for the x of hex numbers.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        _ if c == 'x' => [0x00, 0x00, 0x11, 0x0A, 0x04, 0x0A, 0x11],
+        ' ' => [0x00; 7],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+        '[' => [0x0E, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0E],
+        ']' => [0x0E, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0E],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        '>' => [0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08],
+        '<' => [0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '\'' => [0x04, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::BlockMemory;
+
+    #[test]
+    fn describe_shows_registers_code_and_stack() {
+        let mut stack = vec![0; 16];
+        for (depth, address) in stack.iter_mut().enumerate() {
+            *address = 0x300 + 2 * depth as u16;
+        }
+        let mut v = [0; 16];
+        v[0xA] = 0x5C;
+        let registers = Registers {
+            v,
+            stack,
+            i: 0x2F0,
+            pc: 0x204,
+            sp: 9,
+            delay_timer: 0x3C,
+            sound_timer: 1,
+        };
+        let memory = BlockMemory::with_rom(&[0x00, 0xE0, 0x6A, 0x5C, 0x12, 0x00], 0x200).unwrap();
+        let mut keys = [false; 16];
+        keys[0xB] = true;
+        let lines = describe(&registers, &memory, &keys, true);
+
+        assert_eq!(lines[0], "PC 204 I 2F0 SP 9 DT 3C ST 01");
+        assert_eq!(lines[3], "V8 00  V9 00  VA 5C  VB 00");
+        // The last seven of the nine return addresses
+        assert_eq!(lines[5], "STACK 304 306 308 30A 30C 30E 310");
+        assert_eq!(lines[7], " 200 00E0 CLS");
+        assert_eq!(lines[9], ">204 1200 JP 0x200");
+        assert!(lines.contains(&"KEYS ...........B....".to_string()));
+        assert_eq!(lines.last().unwrap(), "PAUSED  F5 RUN  F6 STEP");
+        assert!(lines.len() <= PANEL_ROWS);
+        assert!(lines.iter().all(|line| line.len() <= PANEL_COLS));
+    }
+
+    #[test]
+    fn render_fills_the_panel() {
+        let pitch = PANEL_COLS * CHAR_WIDTH * 3;
+        let mut buffer = vec![0; pitch * PANEL_ROWS * CHAR_HEIGHT];
+        // Longer and more lines than fit are cut off
+        let lines = vec!["#".repeat(PANEL_COLS + 1); PANEL_ROWS + 1];
+        render(&lines, &mut buffer, pitch);
+        assert!(buffer.iter().all(|&byte| byte == BACKGROUND || byte == FOREGROUND));
+        // The bottom right pixel of the last glyph
+        let (x, y) = (PANEL_COLS * CHAR_WIDTH - 3, PANEL_ROWS * CHAR_HEIGHT - 2);
+        let offset = y * pitch + x * 3;
+        assert_eq!(buffer[offset], FOREGROUND);
+        assert_eq!(buffer[buffer.len() - 1], BACKGROUND);
+    }
+}
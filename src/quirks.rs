@@ -0,0 +1,70 @@
+use std::str::FromStr;
+
+/// Behaviors in which CHIP-8 interpreters differ. ROMs are usually written
+/// for one of them and misbehave on the others.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    /// `SHR` and `SHL` shift Vx in place instead of shifting Vy into Vx.
+    pub shift_vx: bool,
+    /// `LD [I], Vx` and `LD Vx, [I]` leave I pointing after the last
+    /// register.
+    pub increment_i: bool,
+    /// `JP V0, nnn` jumps to nnn + Vx, where x is the highest digit of nnn.
+    pub jump_vx: bool,
+    /// `OR`, `AND` and `XOR` reset VF to 0.
+    pub reset_vf: bool,
+}
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub const VIP: Quirks = Quirks {
+        shift_vx: false,
+        increment_i: true,
+        jump_vx: false,
+        reset_vf: true,
+    };
+
+    /// SUPER-CHIP 1.1 on the HP 48.
+    pub const SCHIP: Quirks = Quirks {
+        shift_vx: true,
+        increment_i: false,
+        jump_vx: true,
+        reset_vf: false,
+    };
+
+    /// XO-CHIP as implemented by Octo.
+    pub const XOCHIP: Quirks = Quirks {
+        shift_vx: false,
+        increment_i: true,
+        jump_vx: false,
+        reset_vf: false,
+    };
+
+    /// The behavior most emulators share, which is the default.
+    pub const MODERN: Quirks = Quirks {
+        shift_vx: true,
+        increment_i: false,
+        jump_vx: false,
+        reset_vf: false,
+    };
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::MODERN
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Quirks, String> {
+        match &s.to_lowercase()[..] {
+            "vip" | "chip8" | "chip-8" => Ok(Quirks::VIP),
+            "schip" | "superchip" => Ok(Quirks::SCHIP),
+            "xochip" | "xo-chip" => Ok(Quirks::XOCHIP),
+            "modern" => Ok(Quirks::MODERN),
+            _ => Err(format!("Unknown quirks: {} (expected vip, schip, xochip or modern)", s)),
+        }
+    }
+}
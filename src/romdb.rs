@@ -0,0 +1,225 @@
+use config::Config;
+use octo::Target;
+use sha1_smol::Sha1;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Options a ROM can bring along. Everything else is up to the user.
+const ROM_OPTIONS: [&str; 15] = [
+    "quirks",
+    "quirk-shift",
+    "quirk-increment-i",
+    "quirk-jump",
+    "quirk-vf-reset",
+    "tick-rate",
+    "keymap",
+    "foreground",
+    "background",
+    "font",
+    "font-address",
+    "stack-depth",
+    "vip-stack",
+    "memory-map",
+    "load-address",
+];
+
+/// What is known about a ROM.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomInfo {
+    pub title: Option<String>,
+    pub platform: Option<Target>,
+    /// Settings as `(option, value)`, in the format of `Config::set`
+    pub options: Vec<(String, String)>,
+}
+
+impl RomInfo {
+    /// Applies the settings of the ROM to all options the user has not set.
+    /// Without explicit quirks, those of the platform are used.
+    pub fn apply(&self, config: &mut Config) {
+        let has_quirks = self.options.iter().any(|(option, _)| option == "quirks");
+        if let (Some(platform), false) = (self.platform, has_quirks) {
+            let quirks = match platform {
+                Target::Chip8 => "vip",
+                Target::Schip => "schip",
+                Target::XoChip => "xochip",
+            };
+            config.set_default("quirks", quirks).unwrap();
+        }
+        for (option, value) in &self.options {
+            // The values have been checked when the database was read
+            config.set_default(option, value).unwrap();
+        }
+    }
+}
+
+/// ROMs identified by the SHA-1 hash of their contents. The file format
+/// consists of sections like
+///
+/// ```text
+/// [0123456789abcdef0123456789abcdef01234567]
+/// title = Some Game
+/// platform = schip
+/// tick-rate = 30
+/// ```
+///
+/// where `platform` is chip8, schip or xochip and the other settings are
+/// the options `quirks`, `quirk-*`, `tick-rate`, `keymap`, `foreground`,
+/// `background`, `font`, `font-address`, `stack-depth`, `vip-stack`,
+/// `memory-map` and `load-address`. Lines starting with `#` are comments.
+#[derive(Clone, Debug, Default)]
+pub struct RomDatabase {
+    entries: HashMap<String, RomInfo>,
+}
+
+impl RomDatabase {
+    pub fn new() -> RomDatabase {
+        RomDatabase::default()
+    }
+
+    /// The database shipped with the emulator.
+    pub fn bundled() -> RomDatabase {
+        let mut database = RomDatabase::new();
+        database.parse(include_str!("../roms.db")).expect("Invalid bundled ROM database");
+        database
+    }
+
+    /// Adds the entries of a database file. Entries for a ROM that is
+    /// already known replace the previous ones.
+    pub fn load_file(&mut self, path: &Path) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        self.parse(&text).map_err(|e| format!("{}:{}", path.display(), e))
+    }
+
+    /// Adds the entries of a database in text form. Errors are prefixed
+    /// with the line number.
+    pub fn parse(&mut self, text: &str) -> Result<(), String> {
+        let mut current: Option<(String, RomInfo)> = None;
+        for (number, line) in text.lines().enumerate() {
+            let error = |message: String| format!("{}: {}", number + 1, message);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let hash = line[1..line.len() - 1].trim().to_lowercase();
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(error(format!("Invalid SHA-1 hash: {}", hash)));
+                }
+                if let Some((hash, info)) = current.take() {
+                    self.entries.insert(hash, info);
+                }
+                current = Some((hash, RomInfo::default()));
+                continue;
+            }
+            let info = match current {
+                Some((_, ref mut info)) => info,
+                None => return Err(error("expected `[sha1]` before the first setting".to_string())),
+            };
+            let mut parts = line.splitn(2, '=');
+            let option = parts.next().unwrap().trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(error("expected `option = value`".to_string())),
+            };
+            match option {
+                "title" => info.title = Some(value.to_string()),
+                "platform" => info.platform = Some(value.parse().map_err(&error)?),
+                _ if ROM_OPTIONS.contains(&option) => {
+                    Config::default().set(option, value).map_err(&error)?;
+                    info.options.push((option.to_string(), value.to_string()));
+                }
+                _ => return Err(error(format!("Unknown ROM setting: {}", option))),
+            }
+        }
+        if let Some((hash, info)) = current {
+            self.entries.insert(hash, info);
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<&RomInfo> {
+        self.entries.get(&hash(rom))
+    }
+}
+
+/// The SHA-1 hash of the ROM as lowercase hex digits.
+pub fn hash(rom: &[u8]) -> String {
+    Sha1::from(rom).digest().to_string()
+}
+
+/// Where the user's own database is looked for if `rom-db` is not set:
+/// `chip8/roms.db` in the XDG config directory.
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("chip8").join("roms.db"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quirks::Quirks;
+
+    const ROM: [u8; 2] = [0x12, 0x00];
+
+    #[test]
+    fn applies_settings_the_user_has_not_set() {
+        let mut database = RomDatabase::new();
+        let text = format!(
+            "# Loops forever\n[{}]\ntitle = Loop\nplatform = schip\n\
+             tick-rate = 30\nforeground = FF8000\n",
+            hash(&ROM)
+        );
+        database.parse(&text).unwrap();
+        let info = database.lookup(&ROM).unwrap();
+        assert_eq!(info.title, Some("Loop".to_string()));
+        assert_eq!(info.platform, Some(Target::Schip));
+
+        let mut config = Config::default();
+        config.set("tick-rate", "12").unwrap();
+        info.apply(&mut config);
+        assert_eq!(config.tick_rate, Some(12));
+        assert_eq!(config.quirks, Quirks::SCHIP);
+        assert_eq!(config.palette.foreground, [0xFF, 0x80, 0x00]);
+        assert!(database.lookup(&[0x00, 0xE0]).is_none());
+    }
+
+    #[test]
+    fn single_quirks_of_the_user_override_the_platform() {
+        let info = RomInfo {
+            platform: Some(Target::Chip8),
+            ..RomInfo::default()
+        };
+        let mut config = Config::default();
+        config.set("quirk-shift", "true").unwrap();
+        info.apply(&mut config);
+        assert_eq!(config.quirks, Quirks { shift_vx: true, ..Quirks::VIP });
+
+        let info = RomInfo {
+            options: vec![("quirks".to_string(), "vip".to_string())],
+            ..RomInfo::default()
+        };
+        let mut config = Config::default();
+        config.set("quirk-vf-reset", "false").unwrap();
+        info.apply(&mut config);
+        assert_eq!(config.quirks, Quirks { reset_vf: false, ..Quirks::VIP });
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let mut database = RomDatabase::new();
+        assert_eq!(database.parse("title = Loop"),
+                   Err("1: expected `[sha1]` before the first setting".to_string()));
+        let hash = hash(&ROM);
+        assert_eq!(database.parse(&format!("[{}]\n\nseed = 1", hash)),
+                   Err("3: Unknown ROM setting: seed".to_string()));
+        assert!(database.parse(&format!("[{}]\ntick-rate = fast", hash)).is_err());
+        assert!(database.parse("[0123]").is_err());
+        assert!(RomDatabase::bundled().lookup(&ROM).is_none());
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+
+// How many frames run per frame of the host when fast-forwarding, and how
+// many host frames one frame takes in slow motion
+const FAST_FORWARD_FACTOR: u32 = 4;
+const SLOW_MOTION_FACTOR: u32 = 4;
+
+const MAX_TICK_RATE: u32 = 1000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mode {
+    Normal,
+    FastForward,
+    SlowMotion,
+}
+
+/// How fast the frontend runs the program: the instructions per frame and
+/// whether it is paused, fast-forwarded or in slow motion.
+#[derive(Clone, Debug)]
+pub struct Speed {
+    tick_rate: u32,
+    mode: Mode,
+    paused: bool,
+    // Host frames since the last emulated one in slow motion
+    waited: u32,
+}
+
+impl Speed {
+    pub fn new(tick_rate: u32) -> Speed {
+        Speed {
+            tick_rate,
+            mode: Mode::Normal,
+            paused: false,
+            waited: 0,
+        }
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    pub fn toggle_fast_forward(&mut self) {
+        self.toggle_mode(Mode::FastForward);
+    }
+
+    pub fn toggle_slow_motion(&mut self) {
+        self.toggle_mode(Mode::SlowMotion);
+    }
+
+    /// Changes the instructions per frame by `delta`, keeping it between 1
+    /// and 1000.
+    pub fn adjust_tick_rate(&mut self, delta: i32) {
+        let tick_rate = self.tick_rate as i64 + delta as i64;
+        self.tick_rate = tick_rate.clamp(1, MAX_TICK_RATE as i64) as u32;
+    }
+
+    /// The number of frames to emulate in the next frame of the host.
+    pub fn frames(&mut self) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        match self.mode {
+            Mode::Normal => 1,
+            Mode::FastForward => FAST_FORWARD_FACTOR,
+            Mode::SlowMotion => {
+                self.waited += 1;
+                if self.waited < SLOW_MOTION_FACTOR {
+                    return 0;
+                }
+                self.waited = 0;
+                1
+            }
+        }
+    }
+
+    fn toggle_mode(&mut self, mode: Mode) {
+        self.mode = if self.mode == mode { Mode::Normal } else { mode };
+        self.waited = 0;
+    }
+}
+
+/// Like `15 ipf, 4x, paused`, for the window title.
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ipf", self.tick_rate)?;
+        match self.mode {
+            Mode::Normal => {}
+            Mode::FastForward => write!(f, ", {}x", FAST_FORWARD_FACTOR)?,
+            Mode::SlowMotion => write!(f, ", 1/{}x", SLOW_MOTION_FACTOR)?,
+        }
+        if self.paused {
+            write!(f, ", paused")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_change_the_frames_per_host_frame() {
+        let mut speed = Speed::new(10);
+        assert_eq!(speed.frames(), 1);
+        speed.toggle_fast_forward();
+        assert_eq!(speed.frames(), 4);
+        assert_eq!(speed.to_string(), "10 ipf, 4x");
+
+        speed.toggle_slow_motion();
+        let frames: Vec<u32> = (0..8).map(|_| speed.frames()).collect();
+        assert_eq!(frames, vec![0, 0, 0, 1, 0, 0, 0, 1]);
+        speed.toggle_slow_motion();
+        assert_eq!(speed.frames(), 1);
+
+        speed.toggle_pause();
+        assert_eq!(speed.frames(), 0);
+        assert_eq!(speed.to_string(), "10 ipf, paused");
+    }
+
+    #[test]
+    fn tick_rate_stays_in_range() {
+        let mut speed = Speed::new(10);
+        speed.adjust_tick_rate(-20);
+        assert_eq!(speed.tick_rate(), 1);
+        speed.adjust_tick_rate(2000);
+        assert_eq!(speed.tick_rate(), 1000);
+    }
+}
@@ -0,0 +1,389 @@
+use disasm::disassemble;
+use error::Error;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// State of the machine right before an instruction is executed.
+///
+/// The text form is one line per instruction with space separated
+/// `NAME=HEX` fields:
+///
+/// ```text
+/// PC=0200 OP=6A02 V=00000000000000000000000000000000 I=0000 SP=00 DT=00 ST=00
+/// ```
+///
+/// `V` holds the registers V0 to VF as two hex digits each. When reading a
+/// trace, the fields may appear in any order, the registers may also be given
+/// individually as `V0=00 V1=00 ...`, and unknown fields are ignored, so
+/// that traces of other emulators can be converted easily.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u16,
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+}
+
+impl TraceRecord {
+    /// Lists the fields in which the records differ as `NAME: self != other`.
+    pub fn differences(&self, other: &TraceRecord) -> Vec<String> {
+        let mut differences = Vec::new();
+        let mut compare = |name: &str, a: u16, b: u16, width: usize| {
+            if a != b {
+                differences.push(format!("{}: {:0w$X} != {:0w$X}", name, a, b, w = width));
+            }
+        };
+        compare("PC", self.pc, other.pc, 4);
+        compare("OP", self.opcode, other.opcode, 4);
+        for (j, (&a, &b)) in self.v.iter().zip(other.v.iter()).enumerate() {
+            compare(&format!("V{:X}", j), a as u16, b as u16, 2);
+        }
+        compare("I", self.i, other.i, 4);
+        compare("SP", self.sp as u16, other.sp as u16, 2);
+        compare("DT", self.delay_timer as u16, other.delay_timer as u16, 2);
+        compare("ST", self.sound_timer as u16, other.sound_timer as u16, 2);
+        differences
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PC={:04X} OP={:04X} V=", self.pc, self.opcode)?;
+        for v in &self.v {
+            write!(f, "{:02X}", v)?;
+        }
+        write!(
+            f,
+            " I={:04X} SP={:02X} DT={:02X} ST={:02X}",
+            self.i, self.sp, self.delay_timer, self.sound_timer
+        )
+    }
+}
+
+impl FromStr for TraceRecord {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TraceRecord, String> {
+        let mut record = TraceRecord::default();
+        let mut seen_pc = false;
+        for field in s.split_whitespace() {
+            let mut parts = field.splitn(2, '=');
+            let name = parts.next().unwrap().to_uppercase();
+            let value = match parts.next() {
+                Some(value) => value.trim_start_matches("0x"),
+                None => return Err(format!("Expected NAME=VALUE: {}", field)),
+            };
+            let invalid = || format!("Invalid value for {}: {}", name, value);
+            let parse_u16 = || u16::from_str_radix(value, 16).map_err(|_| invalid());
+            let parse_u8 = || u8::from_str_radix(value, 16).map_err(|_| invalid());
+            match &name[..] {
+                "PC" => {
+                    record.pc = parse_u16()?;
+                    seen_pc = true;
+                }
+                "OP" => record.opcode = parse_u16()?,
+                "I" => record.i = parse_u16()?,
+                "SP" => record.sp = parse_u8()?,
+                "DT" => record.delay_timer = parse_u8()?,
+                "ST" => record.sound_timer = parse_u8()?,
+                "V" => {
+                    if value.len() != 32 || !value.is_ascii() {
+                        return Err(invalid());
+                    }
+                    for (j, v) in record.v.iter_mut().enumerate() {
+                        *v = u8::from_str_radix(&value[2 * j..2 * j + 2], 16)
+                            .map_err(|_| invalid())?;
+                    }
+                }
+                _ if name.len() == 2 && name.starts_with('V') => {
+                    match u8::from_str_radix(&name[1..], 16) {
+                        Ok(j) => record.v[j as usize] = parse_u8()?,
+                        Err(_) => continue,
+                    }
+                }
+                _ => continue,
+            }
+        }
+        if !seen_pc {
+            return Err("Missing PC".to_string());
+        }
+        Ok(record)
+    }
+}
+
+/// Parses a trace, ignoring empty lines and lines starting with `#`.
+pub fn parse_trace(text: &str) -> Result<Vec<TraceRecord>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|&(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| line.parse().map_err(|e| format!("line {}: {}", number + 1, e)))
+        .collect()
+}
+
+/// Returns the index of the first record in which the traces differ. If one
+/// trace is a prefix of the other, the length of the shorter one is returned.
+pub fn first_divergence(a: &[TraceRecord], b: &[TraceRecord]) -> Option<usize> {
+    match a.iter().zip(b.iter()).position(|(a, b)| a != b) {
+        Some(index) => Some(index),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+/// Groups of instructions by which the `Tracer` can filter.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OpcodeClass {
+    /// JP, CALL and RET
+    Flow,
+    /// SE and SNE
+    Skip,
+    /// LD, ADD, SUB, logic and shift operations on V registers, and RND
+    Arithmetic,
+    /// LD I, ADD I, LD F, LD HF, LD B and the register loads and stores
+    Memory,
+    /// CLS and DRW
+    Display,
+    /// SKP, SKNP and LD Vx, K
+    Input,
+    /// Loads from and to DT and ST
+    Timer,
+}
+
+impl OpcodeClass {
+    /// Returns the class of the opcode or `None` if it is no instruction.
+    pub fn of(opcode: u16) -> Option<OpcodeClass> {
+        let class = match opcode {
+            0x00E0 => OpcodeClass::Display,
+            0x00EE | 0x1000..=0x2FFF | 0xB000..=0xBFFF => OpcodeClass::Flow,
+            0x3000..=0x5FFF | 0x9000..=0x9FFF => OpcodeClass::Skip,
+            0x6000..=0x8FFF | 0xC000..=0xCFFF => OpcodeClass::Arithmetic,
+            0xA000..=0xAFFF => OpcodeClass::Memory,
+            0xD000..=0xDFFF => OpcodeClass::Display,
+            0xE000..=0xEFFF => OpcodeClass::Input,
+            0xF000..=0xFFFF => match opcode & 0xFF {
+                0x07 | 0x15 | 0x18 => OpcodeClass::Timer,
+                0x0A => OpcodeClass::Input,
+                _ => OpcodeClass::Memory,
+            },
+            _ => return None,
+        };
+        Some(class)
+    }
+}
+
+impl FromStr for OpcodeClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OpcodeClass, String> {
+        match s {
+            "flow" => Ok(OpcodeClass::Flow),
+            "skip" => Ok(OpcodeClass::Skip),
+            "arithmetic" => Ok(OpcodeClass::Arithmetic),
+            "memory" => Ok(OpcodeClass::Memory),
+            "display" => Ok(OpcodeClass::Display),
+            "input" => Ok(OpcodeClass::Input),
+            "timer" => Ok(OpcodeClass::Timer),
+            _ => Err(format!("Unknown opcode class: {}", s)),
+        }
+    }
+}
+
+/// Settings of the `Tracer`.
+#[derive(Clone, Debug, Default)]
+pub struct TracerConfig {
+    /// File to write to, `-` for stderr
+    pub output: Option<PathBuf>,
+    /// Only instructions at addresses in this inclusive range are logged
+    pub pc_range: Option<(u16, u16)>,
+    /// Only instructions of these classes are logged, all if empty
+    pub classes: Vec<OpcodeClass>,
+    /// Keep only the last N instructions and write them when an error occurs
+    pub last: Option<usize>,
+}
+
+impl TracerConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.output.is_some() || self.last.is_some()
+    }
+}
+
+/// Human readable log of the executed instructions, showing the disassembly
+/// of every instruction together with the registers it changed:
+///
+/// ```text
+/// 0206  C1FF  RND V1, 0xFF          V1=92
+/// ```
+pub struct Tracer {
+    output: Box<dyn Write>,
+    pc_range: Option<(u16, u16)>,
+    classes: Vec<OpcodeClass>,
+    history: Option<(VecDeque<String>, usize)>,
+}
+
+impl Tracer {
+    pub fn new(output: Box<dyn Write>, config: &TracerConfig) -> Tracer {
+        Tracer {
+            output,
+            pc_range: config.pc_range,
+            classes: config.classes.clone(),
+            history: config.last.map(|n| (VecDeque::with_capacity(n), n)),
+        }
+    }
+
+    /// Logs an instruction given the state of the machine before and after
+    /// its execution.
+    pub fn log(&mut self, before: &TraceRecord, after: &TraceRecord) -> io::Result<()> {
+        if !self.matches(before) {
+            return Ok(());
+        }
+        let line = format_line(before, after);
+        match self.history {
+            Some((ref mut lines, capacity)) => {
+                if lines.len() == capacity {
+                    lines.pop_front();
+                }
+                if capacity > 0 {
+                    lines.push_back(line);
+                }
+                Ok(())
+            }
+            None => writeln!(self.output, "{}", line),
+        }
+    }
+
+    /// Writes the failed instruction together with the buffered instructions
+    /// before it. `opcode` is `None` if the instruction could not be fetched.
+    pub fn log_error(&mut self, pc: u16, opcode: Option<u16>, error: &Error) -> io::Result<()> {
+        if let Some((ref mut lines, _)) = self.history {
+            writeln!(self.output, "Last {} instructions:", lines.len())?;
+            for line in lines.drain(..) {
+                writeln!(self.output, "{}", line)?;
+            }
+        }
+        match opcode {
+            Some(opcode) => writeln!(
+                self.output,
+                "{:04X}  {:04X}  {:20}  error: {}",
+                pc,
+                opcode,
+                disassemble(opcode),
+                error
+            )?,
+            None => writeln!(self.output, "{:04X}  error: {}", pc, error)?,
+        }
+        self.output.flush()
+    }
+
+    fn matches(&self, record: &TraceRecord) -> bool {
+        if let Some((start, end)) = self.pc_range {
+            if record.pc < start || record.pc > end {
+                return false;
+            }
+        }
+        self.classes.is_empty()
+            || OpcodeClass::of(record.opcode).is_some_and(|class| self.classes.contains(&class))
+    }
+}
+
+fn format_line(before: &TraceRecord, after: &TraceRecord) -> String {
+    let mut line = format!(
+        "{:04X}  {:04X}  {:20}",
+        before.pc,
+        before.opcode,
+        disassemble(before.opcode)
+    );
+    for (j, (&a, &b)) in before.v.iter().zip(after.v.iter()).enumerate() {
+        if a != b {
+            line += &format!("  V{:X}={:02X}", j, b);
+        }
+    }
+    if before.i != after.i {
+        line += &format!("  I={:04X}", after.i);
+    }
+    if before.sp != after.sp {
+        line += &format!("  SP={:02X}", after.sp);
+    }
+    if before.delay_timer != after.delay_timer {
+        line += &format!("  DT={:02X}", after.delay_timer);
+    }
+    if before.sound_timer != after.sound_timer {
+        line += &format!("  ST={:02X}", after.sound_timer);
+    }
+    // Only jumps, calls and skips are worth mentioning
+    if after.pc != before.pc.wrapping_add(2) {
+        line += &format!("  PC={:04X}", after.pc);
+    }
+    line.truncate(line.trim_end().len());
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pc: u16) -> TraceRecord {
+        TraceRecord {
+            pc,
+            opcode: 0x6A02,
+            v: [0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xFF],
+            i: 0x0ABC,
+            sp: 1,
+            delay_timer: 0x3C,
+            sound_timer: 2,
+        }
+    }
+
+    #[test]
+    fn records_survive_a_round_trip() {
+        let record = record(0x0200);
+        let line = record.to_string();
+        assert_eq!(
+            line,
+            "PC=0200 OP=6A02 V=100000000000000000000000000000FF I=0ABC SP=01 DT=3C ST=02"
+        );
+        assert_eq!(line.parse(), Ok(record));
+    }
+
+    #[test]
+    fn records_of_other_emulators_are_read() {
+        let line = "cycle=5 v0=0x10 I=0ABC vf=FF st=2 OP=6A02 dt=3c SP=1 PC=0x200";
+        assert_eq!(line.parse(), Ok(record(0x0200)));
+    }
+
+    #[test]
+    fn invalid_records_are_rejected() {
+        for line in &[
+            "OP=6A02",
+            "PC",
+            "PC=XYZ",
+            "PC=0200 SP=100",
+            "PC=0200 V=00",
+            "PC=0200 V=ä00000000000000000000000000000",
+        ] {
+            assert!(line.parse::<TraceRecord>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn traces_are_compared_record_by_record() {
+        let text = "# header\nPC=0200\n\nPC=0202 V3=01\nPC=0204\n";
+        let a = parse_trace(text).unwrap();
+        assert_eq!(a.len(), 3);
+        assert_eq!(a[1].v[3], 1);
+        let error = parse_trace("PC=0200\nPC=0202 I=Z").unwrap_err();
+        assert_eq!(error, "line 2: Invalid value for I: Z");
+
+        let mut b = a.clone();
+        assert_eq!(first_divergence(&a, &b), None);
+        assert_eq!(first_divergence(&a, &b[..2]), Some(2));
+        b[1].v[3] = 2;
+        assert_eq!(first_divergence(&a, &b), Some(1));
+        assert_eq!(a[1].differences(&b[1]), vec!["V3: 01 != 02"]);
+    }
+}